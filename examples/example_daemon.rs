@@ -1,10 +1,10 @@
 /// run with `cargo run --example example_daemon`
-use fork::{daemon, Fork};
+use fork::{daemon_with, DaemonOptions, Fork};
 use std::process::Command;
 
 fn main() {
     // Keep file descriptors open to print the pid of the daemon
-    match daemon(false, true) {
+    match daemon_with(DaemonOptions::CHDIR_ROOT) {
         Ok(Fork::Child) => {
             Command::new("sleep")
                 .arg("300")
@@ -1,10 +1,10 @@
 /// run with `cargo run --example example_touch_pid`
-use fork::{daemon, Fork};
+use fork::{daemon_with, DaemonOptions, Fork};
 use std::fs::OpenOptions;
 use std::process::Command;
 
 fn main() {
-    match daemon(false, false) {
+    match daemon_with(DaemonOptions::CHDIR_ROOT | DaemonOptions::REDIRECT_STDIO) {
         Ok(Fork::Child) => {
             Command::new("sleep")
                 .arg("300")
@@ -17,6 +17,7 @@ fn main() {
             OpenOptions::new()
                 .write(true)
                 .create(true)
+                .truncate(true)
                 .open(file_name)
                 .expect("failed to open file");
         }
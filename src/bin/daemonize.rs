@@ -0,0 +1,75 @@
+//! Run a command as a daemon, with stdio connected to `/dev/null` unless
+//! redirected to files
+//!
+//! build with `cargo build --features daemonize`
+use fork::{exitcode, Daemon};
+use std::env;
+use std::ffi::OsString;
+use std::process::{exit, Command};
+
+const USAGE: &str = "usage: daemonize [--pidfile file] [--user user] [--group group] \
+[--uid uid] [--gid gid] [--chdir dir] [--stdout file] [--stderr file] [--umask mode] [--] command [args...]";
+
+fn usage(error: &str) -> ! {
+    eprintln!("daemonize: {error}\n{USAGE}");
+    exit(exitcode::USAGE)
+}
+
+fn main() {
+    let mut args = env::args_os().skip(1);
+    // a foreign command must not find its first open file on stdout
+    let mut daemon = Daemon::new().redirect_stdio(true);
+    let mut command: Vec<OsString> = Vec::new();
+
+    while let Some(arg) = args.next() {
+        let flag = arg.to_string_lossy().into_owned();
+        if !flag.starts_with("--") {
+            command.push(arg);
+            break;
+        }
+        if flag == "--" {
+            break;
+        }
+        if flag == "--help" {
+            println!("{USAGE}");
+            return;
+        }
+        let Some(value) = args.next() else {
+            usage(&format!("missing value for {flag}"));
+        };
+        daemon = match flag.as_str() {
+            "--pidfile" => daemon.pidfile(value),
+            "--user" => daemon.user(value.to_string_lossy()),
+            "--group" => daemon.group(value.to_string_lossy()),
+            "--uid" => match value.to_string_lossy().parse() {
+                Ok(uid) => daemon.user_id(uid),
+                Err(_) => usage(&format!("invalid uid {}", value.to_string_lossy())),
+            },
+            "--gid" => match value.to_string_lossy().parse() {
+                Ok(gid) => daemon.group_id(gid),
+                Err(_) => usage(&format!("invalid gid {}", value.to_string_lossy())),
+            },
+            "--chdir" => daemon.working_dir(value),
+            "--stdout" => daemon.stdout(value),
+            "--stderr" => daemon.stderr(value),
+            "--umask" => match libc::mode_t::from_str_radix(&value.to_string_lossy(), 8) {
+                Ok(mode) => daemon.umask(mode),
+                Err(_) => usage(&format!("invalid umask {}", value.to_string_lossy())),
+            },
+            _ => usage(&format!("unknown option {flag}")),
+        };
+    }
+    command.extend(args);
+
+    let Some((program, args)) = command.split_first() else {
+        usage("missing command");
+    };
+
+    match daemon.exec(Command::new(program).args(args)) {
+        Ok(_) => exit(exitcode::OK),
+        Err(_) => {
+            eprintln!("daemonize: failed to run {}", program.to_string_lossy());
+            exit(exitcode::UNAVAILABLE)
+        }
+    }
+}
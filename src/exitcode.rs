@@ -0,0 +1,70 @@
+//! Conventional exit statuses from BSD `<sysexits.h>`, also used by LSB init
+//! scripts and service managers to tell failures apart.
+//!
+//! The launcher of a [`Daemon`](crate::Daemon) exits with these when startup
+//! fails, so scripts interpreting its status get the usual meanings.
+//!
+//! Example:
+//!
+//!```no_run
+//!use fork::exitcode;
+//!use std::process::exit;
+//!
+//!let Some(config) = std::env::args().nth(1) else {
+//!    eprintln!("usage: myapp config");
+//!    exit(exitcode::USAGE);
+//!};
+//!```
+
+/// Successful termination
+pub const OK: i32 = 0;
+
+/// The command was used incorrectly, e.g. with the wrong number of arguments
+pub const USAGE: i32 = 64;
+
+/// The input data was incorrect in some way
+pub const DATAERR: i32 = 65;
+
+/// An input file did not exist or was not readable
+pub const NOINPUT: i32 = 66;
+
+/// The user specified did not exist
+pub const NOUSER: i32 = 67;
+
+/// The host specified did not exist
+pub const NOHOST: i32 = 68;
+
+/// A service is unavailable, e.g. a daemon that failed to start
+pub const UNAVAILABLE: i32 = 69;
+
+/// An internal software error has been detected
+pub const SOFTWARE: i32 = 70;
+
+/// An operating system error has been detected, e.g. `fork` failed
+pub const OSERR: i32 = 71;
+
+/// A system file, e.g. `/etc/passwd`, does not exist or can't be opened
+pub const OSFILE: i32 = 72;
+
+/// A user-specified output file can't be created
+pub const CANTCREAT: i32 = 73;
+
+/// An error occurred while doing I/O on some file
+pub const IOERR: i32 = 74;
+
+/// A temporary failure, the operation may be retried later
+pub const TEMPFAIL: i32 = 75;
+
+/// The remote system returned something invalid during a protocol exchange
+pub const PROTOCOL: i32 = 76;
+
+/// Insufficient permission to perform the operation
+pub const NOPERM: i32 = 77;
+
+/// Something was found in an unconfigured or misconfigured state
+pub const CONFIG: i32 = 78;
+
+/// A launcher whose daemon didn't signal readiness within
+/// [`Daemon::startup_timeout`](crate::Daemon::startup_timeout), the status
+/// `timeout(1)` uses
+pub const STARTUP_TIMED_OUT: i32 = 124;
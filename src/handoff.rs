@@ -0,0 +1,406 @@
+//! Hand listening sockets and state from a running daemon to a new instance of
+//! its executable, for upgrades without dropping connections.
+//!
+//! The old daemon calls [`upgrade`], which starts its executable again with a
+//! Unix socket inherited as [`HANDOFF_ENV`]. Over that socket it sends a small
+//! versioned message: the descriptors, passed with `SCM_RIGHTS`, and opaque
+//! state bytes the application serializes as it likes. The new instance picks
+//! them up with [`receive`] and confirms with [`Received::ready`] once it is
+//! serving. If it doesn't within the timeout, or exits, the old daemon kills
+//! it and keeps running: the upgrade is rolled back.
+//!
+//! Example:
+//!
+//!```no_run
+//!use fork::handoff::{receive, upgrade, Upgrade};
+//!use std::net::TcpListener;
+//!use std::os::unix::io::{AsRawFd, FromRawFd};
+//!use std::time::Duration;
+//!
+//!let listener = match receive().expect("broken handoff") {
+//!    Some(received) => {
+//!        let listener = unsafe { TcpListener::from_raw_fd(received.fds[0]) };
+//!        // restore received.state, then tell the old instance to go away
+//!        received.ready().expect("old instance is gone");
+//!        listener
+//!    }
+//!    None => TcpListener::bind("0.0.0.0:8080").expect("failed to bind"),
+//!};
+//!
+//!// on SIGUSR2
+//!match upgrade(&[listener.as_raw_fd()], b"state", Duration::from_secs(10)) {
+//!    Ok(Upgrade::Ready(_)) => std::process::exit(0),
+//!    _ => eprintln!("upgrade failed, still serving"),
+//!}
+//!```
+
+use crate::{self_command, waitpid_status, WaitStatus};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Environment variable holding the descriptor of the handoff socket in the new
+/// instance
+pub const HANDOFF_ENV: &str = "FORK_HANDOFF_FD";
+
+/// Set once [`receive`] took the socket named by [`HANDOFF_ENV`]
+static RECEIVED: AtomicBool = AtomicBool::new(false);
+
+/// Version of the handoff message, a new instance refuses other versions
+pub const VERSION: u8 = 1;
+
+/// Start of every handoff message
+const MAGIC: [u8; 4] = *b"FKHO";
+
+/// Magic, version, descriptor count and state length
+const HEADER_LEN: usize = 10;
+
+/// Most descriptors a single `SCM_RIGHTS` message can carry on Linux
+pub const MAX_FDS: usize = 253;
+
+/// Sent by the new instance once it is ready
+const READY: u8 = b'R';
+
+/// Outcome of [`upgrade`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Upgrade {
+    /// The new instance with this pid is serving, the caller should exit
+    Ready(libc::pid_t),
+    /// The new instance failed or timed out and was killed, with this status
+    RolledBack(WaitStatus),
+}
+
+/// Descriptors and state received from the old instance by [`receive`]
+#[derive(Debug)]
+pub struct Received {
+    /// The descriptors in the order they were passed, close-on-exec
+    pub fds: Vec<RawFd>,
+    /// The opaque state
+    pub state: Vec<u8>,
+    channel: File,
+}
+
+impl Received {
+    /// Tell the old instance that the new one is serving, it exits afterwards
+    ///
+    /// # Errors
+    /// returns `-1` if the old instance is gone, e.g. because the timeout passed
+    pub fn ready(mut self) -> Result<(), i32> {
+        self.channel.write_all(&[READY]).map_err(|_| -1)
+    }
+}
+
+/// Start a new instance of the executable and hand it `fds` and `state`
+///
+/// The new instance runs with the same arguments and [`REEXEC_ENV`] set, it is
+/// a child of the caller until the caller exits. It has `timeout` to call
+/// [`Received::ready`], otherwise it is killed with `SIGKILL` and reaped.
+///
+/// [`REEXEC_ENV`]: crate::REEXEC_ENV
+///
+/// # Errors
+/// returns `-1` if there are more than [`MAX_FDS`] descriptors, or the socket,
+/// spawning the new instance or sending fails
+pub fn upgrade(fds: &[RawFd], state: &[u8], timeout: Duration) -> Result<Upgrade, i32> {
+    if fds.len() > MAX_FDS {
+        return Err(-1);
+    }
+    let [local, remote] = socket_pair()?;
+    let remote_fd = remote.as_raw_fd();
+    let mut cmd = self_command()?;
+    cmd.env(HANDOFF_ENV, remote_fd.to_string());
+    unsafe {
+        cmd.pre_exec(move || match libc::fcntl(remote_fd, libc::F_SETFD, 0) {
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        })
+    };
+    let child = cmd.spawn().map_err(|_| -1)?;
+    drop(remote);
+    let pid = libc::pid_t::try_from(child.id()).map_err(|_| -1)?;
+
+    if send(&local, fds, state).is_ok() && await_ready(&local, timeout) {
+        return Ok(Upgrade::Ready(pid));
+    }
+    unsafe { libc::kill(pid, libc::SIGKILL) };
+    waitpid_status(pid).map(Upgrade::RolledBack)
+}
+
+/// Descriptors and state from the old instance if this process was started by
+/// [`upgrade`], `None` otherwise
+///
+/// Only the first call takes the socket, later calls return `None`. The
+/// environment is left alone, [`HANDOFF_ENV`] stays set for the commands the
+/// process starts: [`reexec_self`] and [`upgrade`] drop or replace it, other
+/// commands should drop it with [`Command::env_remove`].
+///
+/// [`reexec_self`]: crate::reexec_self
+/// [`Command::env_remove`]: std::process::Command::env_remove
+///
+/// # Errors
+/// returns `-1` if the message is malformed or has another [`VERSION`]
+pub fn receive() -> Result<Option<Received>, i32> {
+    let Some(fd) = std::env::var_os(HANDOFF_ENV) else {
+        return Ok(None);
+    };
+    if RECEIVED.swap(true, Ordering::Relaxed) {
+        return Ok(None);
+    }
+    let fd: RawFd = fd.to_str().and_then(|fd| fd.parse().ok()).ok_or(-1)?;
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(-1);
+    }
+    recv(unsafe { File::from_raw_fd(fd) }).map(Some)
+}
+
+/// A connected pair of close-on-exec Unix stream sockets
+fn socket_pair() -> Result<[File; 2], i32> {
+    let mut fds = [0; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } == -1 {
+        return Err(-1);
+    }
+    let pair = unsafe { [File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])] };
+    for socket in &pair {
+        if unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(-1);
+        }
+    }
+    Ok(pair)
+}
+
+/// Send the header with `fds` attached, then `state`
+fn send(channel: &File, fds: &[RawFd], state: &[u8]) -> Result<(), i32> {
+    send_header(channel, fds, u32::try_from(state.len()).map_err(|_| -1)?)?;
+    let mut channel = channel;
+    channel.write_all(state).map_err(|_| -1)
+}
+
+/// Send the header announcing `len` bytes of state, with `fds` attached
+fn send_header(channel: &File, fds: &[RawFd], len: u32) -> Result<(), i32> {
+    let mut header = [0u8; HEADER_LEN];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4] = VERSION;
+    header[5] = u8::try_from(fds.len()).map_err(|_| -1)?;
+    header[6..].copy_from_slice(&len.to_le_bytes());
+
+    let fds_len = std::mem::size_of_val(fds);
+    let space = unsafe { libc::CMSG_SPACE(u32::try_from(fds_len).map_err(|_| -1)?) } as usize;
+    let mut control = vec![0u8; space];
+    let mut iov = libc::iovec {
+        iov_base: header.as_mut_ptr().cast(),
+        iov_len: HEADER_LEN,
+    };
+    let mut msg = unsafe { std::mem::zeroed::<libc::msghdr>() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = space as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len as u32) as _;
+            std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg).cast(), fds.len());
+        }
+    }
+    // a stream socket accepts at least the header in one go
+    if unsafe { libc::sendmsg(channel.as_raw_fd(), &msg, 0) }.unsigned_abs() != HEADER_LEN {
+        return Err(-1);
+    }
+    Ok(())
+}
+
+/// Read a message written by [`send`]
+fn recv(mut channel: File) -> Result<Received, i32> {
+    let mut header = [0u8; HEADER_LEN];
+    let space = unsafe { libc::CMSG_SPACE((MAX_FDS * std::mem::size_of::<RawFd>()) as u32) };
+    let mut control = vec![0u8; space as usize];
+    let mut iov = libc::iovec {
+        iov_base: header.as_mut_ptr().cast(),
+        iov_len: HEADER_LEN,
+    };
+    let mut msg = unsafe { std::mem::zeroed::<libc::msghdr>() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = control.len() as _;
+    // close-on-exec from the start, no other thread can exec with them
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    let flags = 0;
+    let read = unsafe { libc::recvmsg(channel.as_raw_fd(), &mut msg, flags) };
+    if read <= 0 {
+        return Err(-1);
+    }
+
+    let mut fds = Vec::new();
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let (level, kind, len) =
+            unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type, (*cmsg).cmsg_len) };
+        if level == libc::SOL_SOCKET && kind == libc::SCM_RIGHTS {
+            // `cmsg_len` is a `socklen_t` on some platforms
+            #[allow(clippy::unnecessary_cast)]
+            let data_len = len as usize - unsafe { libc::CMSG_LEN(0) } as usize;
+            let data = unsafe { libc::CMSG_DATA(cmsg) }.cast::<RawFd>();
+            for i in 0..data_len / std::mem::size_of::<RawFd>() {
+                let fd = unsafe { data.add(i).read_unaligned() };
+                #[cfg(not(any(
+                    target_os = "linux",
+                    target_os = "android",
+                    target_os = "freebsd"
+                )))]
+                unsafe {
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC)
+                };
+                fds.push(fd);
+            }
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+
+    let truncated = msg.msg_flags & libc::MSG_CTRUNC != 0;
+    let mut rest = || {
+        // descriptors that didn't fit were closed by the kernel
+        if truncated {
+            return Err(-1);
+        }
+        channel
+            .read_exact(&mut header[read.unsigned_abs()..])
+            .map_err(|_| -1)?;
+        if header[..4] != MAGIC || header[4] != VERSION || usize::from(header[5]) != fds.len() {
+            return Err(-1);
+        }
+        let len = u32::from_le_bytes([header[6], header[7], header[8], header[9]]);
+        let mut state = vec![0u8; len as usize];
+        channel.read_exact(&mut state).map_err(|_| -1)?;
+        Ok(state)
+    };
+    match rest() {
+        Ok(state) => Ok(Received {
+            fds,
+            state,
+            channel,
+        }),
+        Err(e) => {
+            for fd in fds {
+                unsafe { libc::close(fd) };
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Whether the ready byte arrives on `channel` within `timeout`
+fn await_ready(channel: &File, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let mut poll = libc::pollfd {
+            fd: channel.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let millis = libc::c_int::try_from(left.as_millis()).unwrap_or(libc::c_int::MAX);
+        match unsafe { libc::poll(&mut poll, 1, millis) } {
+            -1 if std::io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) => {}
+            1 => {
+                let mut byte = [0u8];
+                let mut channel = channel;
+                return matches!(channel.read(&mut byte), Ok(1)) && byte[0] == READY;
+            }
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{await_ready, recv, send, send_header, socket_pair, Received};
+    use std::io::{Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::time::Duration;
+
+    #[test]
+    fn test_handoff_message() {
+        let [old, new] = socket_pair().expect("failed to create sockets");
+        let [read, write] = crate::cloexec_pipe().expect("failed to create pipe");
+
+        send(&old, &[write], b"state").expect("failed to send");
+        unsafe { libc::close(write) };
+        let received = recv(new).expect("failed to receive");
+        assert_eq!(received.state, b"state");
+        assert_eq!(received.fds.len(), 1);
+
+        // the passed descriptor is the write end of the pipe
+        let mut passed = unsafe { std::fs::File::from_raw_fd(received.fds[0]) };
+        passed.write_all(b"through").expect("failed to write");
+        drop(passed);
+        let mut output = String::new();
+        let mut read = unsafe { std::fs::File::from_raw_fd(read) };
+        read.read_to_string(&mut output).expect("failed to read");
+        assert_eq!(output, "through");
+
+        let Received { channel, .. } = received;
+        let ready = Received {
+            fds: Vec::new(),
+            state: Vec::new(),
+            channel,
+        };
+        assert!(ready.ready().is_ok());
+        assert!(await_ready(&old, Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_handoff_closes_fds_on_error() {
+        let [old, new] = socket_pair().expect("failed to create sockets");
+        let [read, write] = crate::cloexec_pipe().expect("failed to create pipe");
+        // the old instance goes away before sending the state
+        send_header(&old, &[write], 4).expect("failed to send");
+        unsafe { libc::close(write) };
+        drop(old);
+        assert!(recv(new).is_err());
+        // the received write end was closed, the pipe is at end of file
+        let mut read = unsafe { std::fs::File::from_raw_fd(read) };
+        let mut output = Vec::new();
+        assert_eq!(read.read_to_end(&mut output).ok(), Some(0));
+    }
+
+    #[test]
+    fn test_receive_once() {
+        crate::tests::in_child(|| {
+            let Ok([old, new]) = socket_pair() else {
+                return false;
+            };
+            send(&old, &[], b"state").is_ok() && {
+                std::env::set_var(super::HANDOFF_ENV, new.as_raw_fd().to_string());
+                std::mem::forget(new);
+                let received = super::receive();
+                let restart = crate::self_command().is_ok_and(|cmd| {
+                    cmd.get_envs()
+                        .any(|(key, value)| key == super::HANDOFF_ENV && value.is_none())
+                });
+                received.is_ok_and(|received| received.is_some_and(|r| r.state == b"state"))
+                    && matches!(super::receive(), Ok(None))
+                    && restart
+            }
+        });
+    }
+
+    #[test]
+    fn test_handoff_timeout_and_version() {
+        let [old, new] = socket_pair().expect("failed to create sockets");
+        assert!(!await_ready(&old, Duration::from_millis(50)));
+
+        let mut bogus = &old;
+        bogus
+            .write_all(b"FKHO\x02\x00\x00\x00\x00\x00")
+            .expect("failed to write");
+        assert!(recv(new).is_err());
+    }
+}
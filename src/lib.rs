@@ -2,26 +2,142 @@
 //!
 //! Example:
 //! ```
-//!use fork::{daemon, Fork};
+//!use fork::{daemon_with, DaemonOptions, Fork};
 //!use std::process::Command;
 //!
-//!if let Ok(Fork::Child) = daemon(false, false) {
+//!if let Ok(Fork::Child) = daemon_with(DaemonOptions::CHDIR_ROOT | DaemonOptions::REDIRECT_STDIO) {
 //!    Command::new("sleep")
 //!        .arg("3")
 //!        .output()
 //!        .expect("failed to execute process");
 //!}
 //!```
+//!
+//! # Fork safety
+//!
+//! After `fork` in a multithreaded process only async-signal-safe functions may
+//! be called until `exec`, another thread may have held the allocator or a
+//! lock at the time of the fork. The following only make system calls in the
+//! forked processes, with files, users and paths resolved beforehand:
+//!
+//! * [`Daemon::start`] and [`PreparedDaemon::start`] until they return to the
+//!   daemon, and [`Daemon::exec`] up to `exec`
+//! * [`spawn_detached`] up to `exec`
+//! * [`chdir`], [`close_fd`], [`setsid`] and the `redirect_*_null` functions
+//!   once [`preopen_dev_null`] ran
+//!
+//! Code running in the child of a plain [`fork()`] is the caller's
+//! responsibility.
 
-use std::ffi::CString;
-use std::process::exit;
+use std::collections::{hash_map, HashMap};
+use std::env;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::hash::BuildHasher;
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpListener};
+use std::ops::{BitOr, BitOrAssign};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub mod exitcode;
+pub mod handoff;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod namespace;
+pub mod typed;
+
+#[doc(no_inline)]
+pub use exitcode::STARTUP_TIMED_OUT;
 
 /// Fork result
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Fork {
     Parent(libc::pid_t),
     Child,
 }
 
+impl Fork {
+    /// Whether this is the child process
+    #[must_use]
+    pub const fn is_child(&self) -> bool {
+        matches!(self, Self::Child)
+    }
+
+    /// Whether this is the parent process
+    #[must_use]
+    pub const fn is_parent(&self) -> bool {
+        matches!(self, Self::Parent(_))
+    }
+
+    /// The pid held by `Fork::Parent`, which is the pid of the new child,
+    /// `None` in the child
+    #[must_use]
+    pub const fn parent_pid(&self) -> Option<libc::pid_t> {
+        match self {
+            Self::Parent(pid) => Some(*pid),
+            Self::Child => None,
+        }
+    }
+
+    /// Run `f` in the child, `None` in the parent
+    pub fn child_then<T, F: FnOnce() -> T>(self, f: F) -> Option<T> {
+        match self {
+            Self::Parent(_) => None,
+            Self::Child => Some(f()),
+        }
+    }
+
+    /// Run `f` with the pid of the new child in the parent, `None` in the child
+    pub fn parent_then<T, F: FnOnce(libc::pid_t) -> T>(self, f: F) -> Option<T> {
+        match self {
+            Self::Parent(pid) => Some(f(pid)),
+            Self::Child => None,
+        }
+    }
+}
+
+/// Fork and run `parent` with the pid of the new child in the parent process
+/// and `child` in the child process, returning what each of them returned
+///
+/// Example:
+///
+///```
+///use fork::{run_split, waitpid};
+///use std::process::exit;
+///
+///let child = run_split(
+///    |child| child,
+///    || {
+///        println!("I'm a new child process");
+///        exit(0)
+///    },
+///)
+///.expect("fork failed");
+///
+///waitpid(child).expect("waitpid failed");
+///```
+///
+/// # Errors
+/// returns `-1` if `fork` fails
+pub fn run_split<T, P, C>(parent: P, child: C) -> Result<T, i32>
+where
+    P: FnOnce(libc::pid_t) -> T,
+    C: FnOnce() -> T,
+{
+    match fork()? {
+        Fork::Parent(pid) => Ok(parent(pid)),
+        Fork::Child => Ok(child()),
+    }
+}
+
 /// Change dir to `/` [see chdir(2)](https://www.freebsd.org/cgi/man.cgi?query=chdir&sektion=2)
 ///
 /// Upon successful completion, 0 shall be returned. Otherwise, -1 shall be
@@ -45,201 +161,9023 @@ pub enum Fork {
 ///
 /// # Errors
 /// returns `-1` if error
-/// # Panics
-/// Panics if `CString::new` fails
 pub fn chdir() -> Result<libc::c_int, i32> {
-    let dir = CString::new("/").expect("CString::new failed");
-    let res = unsafe { libc::chdir(dir.as_ptr()) };
+    let res = unsafe { libc::chdir(c"/".as_ptr()) };
     match res {
         -1 => Err(-1),
         res => Ok(res),
     }
 }
 
-/// Close file descriptors stdin,stdout,stderr
+/// Change the working directory for a scope, the previous one is restored when
+/// the guard is dropped
 ///
-/// # Errors
-/// returns `-1` if error
-pub fn close_fd() -> Result<(), i32> {
-    match unsafe { libc::close(0) } {
-        -1 => Err(-1),
-        _ => match unsafe { libc::close(1) } {
-            -1 => Err(-1),
-            _ => match unsafe { libc::close(2) } {
-                -1 => Err(-1),
-                _ => Ok(()),
-            },
-        },
+/// The guard is fork-aware: it only restores the directory in the process that
+/// created it, a forked child dropping its copy keeps whatever directory it is in.
+///
+/// Example:
+///
+///```
+///use fork::ScopedChdir;
+///use std::env;
+///
+///let previous = env::current_dir().expect("failed current_dir");
+///{
+///    let _guard = ScopedChdir::new("/tmp").expect("failed to chdir");
+///    // fork/exec helpers started here run from /tmp
+///}
+///assert_eq!(env::current_dir().expect("failed current_dir"), previous);
+///```
+pub struct ScopedChdir {
+    previous: PathBuf,
+    pid: libc::pid_t,
+}
+
+impl ScopedChdir {
+    /// Change to `dir`, remembering the current working directory
+    ///
+    /// # Errors
+    /// returns `-1` if the current directory can't be read or `dir` can't be entered
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self, i32> {
+        let previous = env::current_dir().map_err(|_| -1)?;
+        env::set_current_dir(dir).map_err(|_| -1)?;
+        Ok(Self {
+            previous,
+            pid: getpid(),
+        })
     }
 }
 
-/// Create a new child process [see fork(2)](https://www.freebsd.org/cgi/man.cgi?fork)
+impl Drop for ScopedChdir {
+    fn drop(&mut self) {
+        if getpid() == self.pid {
+            let _ = env::set_current_dir(&self.previous);
+        }
+    }
+}
+
+/// Set the file mode creation mask for a scope, the previous mask is restored
+/// when the guard is dropped [see umask(2)](https://man.freebsd.org/cgi/man.cgi?umask)
 ///
-/// Upon successful completion, `fork()` returns a value of 0 to the child process
-/// and returns the process ID of the child process to the parent process.
-/// Otherwise, a value of -1 is returned to the parent process, no child process
-/// is created.
+/// Useful to create pid and log files with strict permissions without leaking
+/// the changed mask into the rest of the application. Like [`ScopedChdir`] it
+/// only restores the mask in the process that created it.
 ///
 /// Example:
 ///
-/// ```
-///use fork::{fork, Fork};
+///```
+///use fork::ScopedUmask;
+///use std::fs::File;
+///use std::os::unix::fs::PermissionsExt;
+///
+///let path = std::env::temp_dir().join("fork-umask-example.pid");
+///# std::fs::remove_file(&path).ok();
+///{
+///    let _guard = ScopedUmask::new(0o077);
+///    File::create(&path).expect("failed to create file");
+///}
+///let mode = path.metadata().expect("failed metadata").permissions().mode();
+///assert_eq!(mode & 0o077, 0);
+///# std::fs::remove_file(&path).ok();
+///```
+pub struct ScopedUmask {
+    previous: libc::mode_t,
+    pid: libc::pid_t,
+}
+
+impl ScopedUmask {
+    /// Set the umask to `mode`, remembering the current one
+    #[must_use]
+    pub fn new(mode: libc::mode_t) -> Self {
+        Self {
+            previous: unsafe { libc::umask(mode) },
+            pid: getpid(),
+        }
+    }
+}
+
+impl Drop for ScopedUmask {
+    fn drop(&mut self) {
+        if getpid() == self.pid {
+            unsafe { libc::umask(self.previous) };
+        }
+    }
+}
+
+/// Deliver `SIGALRM` to the process after `after`, replacing a pending alarm
+/// [see setitimer(2)](https://man.freebsd.org/cgi/man.cgi?setitimer)
+///
+/// With the default disposition the signal terminates the process, so a forked
+/// job dies once its time is up even if nobody is left to kill it. Alarms are
+/// not inherited across `fork`, set it in the child.
+///
+/// Example:
+///
+///```
+///use fork::{fork, set_alarm, waitpid_with, Fork, Interrupt, Signal, WaitStatus};
+///use std::time::Duration;
 ///
 ///match fork() {
+///    Ok(Fork::Child) => {
+///        set_alarm(Duration::from_millis(100)).expect("failed to set alarm");
+///        std::thread::sleep(Duration::from_secs(10)); // a stuck job
+///    }
 ///    Ok(Fork::Parent(child)) => {
-///        println!("Continuing execution in parent process, new child has pid: {}", child);
+///        let status = waitpid_with(child, Interrupt::Retry);
+///        assert_eq!(status, Ok(WaitStatus::Signaled(Signal::SIGALRM)));
 ///    }
-///    Ok(Fork::Child) => println!("I'm a new child process"),
-///    Err(_) => println!("Fork failed"),
+///    Err(_) => panic!("fork failed"),
 ///}
 ///```
-/// This will print something like the following (order indeterministic).
 ///
-/// ```text
-/// Continuing execution in parent process, new child has pid: 1234
-/// I'm a new child process
-/// ```
+/// # Errors
+/// returns `-1` if the timer can't be set
+pub fn set_alarm(after: Duration) -> Result<(), i32> {
+    // a zero timer would disarm it instead
+    let after = after.max(Duration::from_micros(1));
+    let timer = libc::itimerval {
+        it_interval: libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        },
+        it_value: libc::timeval {
+            tv_sec: libc::time_t::try_from(after.as_secs()).map_err(|_| -1)?,
+            tv_usec: after.subsec_micros() as libc::suseconds_t,
+        },
+    };
+    match unsafe { libc::setitimer(libc::ITIMER_REAL, &timer, std::ptr::null_mut()) } {
+        -1 => Err(-1),
+        _ => Ok(()),
+    }
+}
+
+/// Cancel the alarm set by [`set_alarm`]
 ///
-/// The thing to note is that you end up with two processes continuing execution
-/// immediately after the fork call but with different match arms.
+/// # Errors
+/// returns `-1` if the timer can't be cleared
+pub fn clear_alarm() -> Result<(), i32> {
+    let timer = unsafe { std::mem::zeroed::<libc::itimerval>() };
+    match unsafe { libc::setitimer(libc::ITIMER_REAL, &timer, std::ptr::null_mut()) } {
+        -1 => Err(-1),
+        _ => Ok(()),
+    }
+}
+
+/// An alarm for a scope, cleared when the guard is dropped, see [`set_alarm`]
 ///
-/// # [`nix::unistd::fork`](https://docs.rs/nix/0.15.0/nix/unistd/fn.fork.html)
+/// Like [`ScopedChdir`] it only clears the alarm in the process that created it.
 ///
-/// The example has been taken from the [`nix::unistd::fork`](https://docs.rs/nix/0.15.0/nix/unistd/fn.fork.html),
-/// please check the [Safety](https://docs.rs/nix/0.15.0/nix/unistd/fn.fork.html#safety) section
+/// Example:
+///
+///```
+///use fork::ScopedAlarm;
+///use std::time::Duration;
+///
+///{
+///    let _deadline = ScopedAlarm::new(Duration::from_secs(30)).expect("failed to set alarm");
+///    // the process dies if this takes longer than 30 seconds
+///}
+///```
+pub struct ScopedAlarm {
+    pid: libc::pid_t,
+}
+
+impl ScopedAlarm {
+    /// Set the alarm to go off after `after`
+    ///
+    /// # Errors
+    /// returns `-1` if the timer can't be set
+    pub fn new(after: Duration) -> Result<Self, i32> {
+        set_alarm(after)?;
+        Ok(Self { pid: getpid() })
+    }
+}
+
+impl Drop for ScopedAlarm {
+    fn drop(&mut self) {
+        if getpid() == self.pid {
+            let _ = clear_alarm();
+        }
+    }
+}
+
+/// Close file descriptors stdin,stdout,stderr
 ///
 /// # Errors
 /// returns `-1` if error
-pub fn fork() -> Result<Fork, i32> {
-    let res = unsafe { libc::fork() };
-    match res {
+pub fn close_fd() -> Result<(), i32> {
+    match unsafe { libc::close(0) } {
         -1 => Err(-1),
-        0 => Ok(Fork::Child),
-        res => Ok(Fork::Parent(res)),
+        _ => match unsafe { libc::close(1) } {
+            -1 => Err(-1),
+            _ => match unsafe { libc::close(2) } {
+                -1 => Err(-1),
+                _ => Ok(()),
+            },
+        },
     }
 }
 
-/// Wait for process to change status [see wait(2)](https://man.freebsd.org/cgi/man.cgi?waitpid)
+/// Connect stdin, stdout and stderr to `/dev/null` [see dup2(2)](https://man.freebsd.org/cgi/man.cgi?dup2)
+///
+/// Unlike [`close_fd`] the descriptors stay valid, so files opened later can't
+/// end up as stdout and receive stray prints.
 ///
 /// # Errors
-/// returns `-1` if error
+/// returns `-1` if `/dev/null` can't be opened or a `dup2` fails
+pub fn redirect_stdio() -> Result<(), i32> {
+    let null = dev_null()?;
+    for fd in 0..3 {
+        dup_onto(null, fd)?;
+    }
+    Ok(())
+}
+
+/// Where [`redirect_stdio_with`] sends the standard streams
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NullPolicy {
+    /// `/dev/null`, failing if it can't be opened
+    #[default]
+    DevNull,
+    /// `/dev/null`, or discarding stand-ins when it's missing as in bare
+    /// containers and chroots
+    DevNullOrDiscard,
+    /// Discarding stand-ins, never touching `/dev/null`
+    Discard,
+}
+
+/// Connect stdin, stdout and stderr to `/dev/null` or a discarding replacement
+/// chosen by `policy`
+///
+/// The replacement is a pipe with its write end closed for stdin, so reads see
+/// end of file, and an anonymous file nobody reads for stdout and stderr,
+/// from `memfd_create` or an unlinked file in `/tmp` where that's missing.
+/// Writes always succeed, also from children after the calling process exited,
+/// but stay in memory until the last descriptor of the file is closed, so the
+/// replacement suits processes that write little.
 ///
 /// Example:
 ///
-/// ```
-///use fork::{waitpid, Fork};
-///use std::process::Command;
+///```
+///use fork::{redirect_stdio_with, NullPolicy};
 ///
-///fn main() {
-///  match fork::fork() {
-///     Ok(Fork::Parent(pid)) => {
+///redirect_stdio_with(NullPolicy::DevNullOrDiscard).expect("failed to redirect stdio");
+///println!("discarded");
+///```
 ///
-///         println!("Child pid: {pid}");
+/// # Errors
+/// returns `-1` if neither `/dev/null` nor the replacement allowed by `policy`
+/// can be opened, or a `dup2` fails
+pub fn redirect_stdio_with(policy: NullPolicy) -> Result<(), i32> {
+    let null = match policy {
+        NullPolicy::Discard => None,
+        NullPolicy::DevNull => Some(dev_null()?),
+        NullPolicy::DevNullOrDiscard => dev_null().ok(),
+    };
+    if let Some(null) = null {
+        for fd in 0..3 {
+            dup_onto(null, fd)?;
+        }
+        return Ok(());
+    }
+    let input = unsafe { OwnedFd::from_raw_fd(eof_pipe()?) };
+    let output = unsafe { OwnedFd::from_raw_fd(discard_sink()?) };
+    dup_onto(input.as_raw_fd(), 0)?;
+    dup_onto(output.as_raw_fd(), 1)?;
+    dup_onto(output.as_raw_fd(), 2)
+}
+
+/// Stand-in for `/dev/null` to read from: a pipe at end of file
+fn eof_pipe() -> Result<libc::c_int, i32> {
+    let [input, closed] = cloexec_pipe()?;
+    unsafe { libc::close(closed) };
+    Ok(input)
+}
+
+/// Stand-in for `/dev/null` to write to: an anonymous file nobody reads
 ///
-///         match waitpid(pid) {
-///             Ok(_) => println!("Child existed"),
-///             Err(_) => eprintln!("Failted to wait on child"),
-///         }
-///     }
-///     Ok(Fork::Child) => {
-///         Command::new("sleep")
-///             .arg("1")
-///             .output()
-///             .expect("failed to execute process");
-///     }
-///     Err(_) => eprintln!("Failed to fork"),
-///  }
-///}
-///```
-pub fn waitpid(pid: i32) -> Result<(), i32> {
-    let mut status: i32 = 0;
-    let res = unsafe { libc::waitpid(pid, &mut status, 0) };
-    match res {
+/// Only system calls are made, so it can run between fork and exec.
+fn discard_sink() -> Result<libc::c_int, i32> {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    let fd = unsafe { libc::memfd_create(c"fork-discard".as_ptr(), libc::MFD_CLOEXEC) };
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    let fd = unsafe {
+        let mut template = *b"/tmp/fork-discard.XXXXXX\0";
+        let fd = libc::mkstemp(template.as_mut_ptr().cast());
+        if fd != -1 {
+            libc::unlink(template.as_ptr().cast());
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        fd
+    };
+    match fd {
         -1 => Err(-1),
-        _ => Ok(()),
+        fd => Ok(fd),
     }
 }
 
-/// Create session and set process group ID [see setsid(2)](https://www.freebsd.org/cgi/man.cgi?setsid)
+/// Descriptor of the cached `/dev/null`, -1 until opened
+static DEV_NULL: AtomicI32 = AtomicI32::new(-1);
+/// Device number of the cached `/dev/null`, to notice when its descriptor was
+/// closed behind our back and reused
+static DEV_NULL_RDEV: AtomicU64 = AtomicU64::new(0);
+
+/// Open the `/dev/null` descriptor shared by the redirect functions now
 ///
-/// Upon successful completion, the `setsid()` system call returns the value of the
-/// process group ID of the new process group, which is the same as the process ID
-/// of the calling process. If an error occurs, `setsid()` returns -1
+/// The redirect functions open `/dev/null` once, close-on-exec, and `dup2` from
+/// it afterwards. Calling this early, before lowering `RLIMIT_NOFILE` or while
+/// descriptors are still plentiful, makes later redirects independent of the
+/// file system and of free descriptor slots. If the descriptor gets closed, for
+/// example by [`close_fd`]-like loops over all descriptors, it is reopened on
+/// next use.
+///
+/// Example:
+///
+///```
+///use fork::{preopen_dev_null, redirect_stdio};
+///
+///preopen_dev_null().expect("failed to open /dev/null");
+///// no open(2) happens here
+///redirect_stdio().expect("failed to redirect stdio");
+///```
 ///
 /// # Errors
-/// returns `-1` if error
-pub fn setsid() -> Result<libc::pid_t, i32> {
-    let res = unsafe { libc::setsid() };
-    match res {
+/// returns `-1` if `/dev/null` can't be opened
+pub fn preopen_dev_null() -> Result<(), i32> {
+    dev_null().map(|_| ())
+}
+
+/// The cached `/dev/null` descriptor if it is still open
+fn cached_dev_null() -> Option<libc::c_int> {
+    let cached = DEV_NULL.load(Ordering::Acquire);
+    (cached != -1 && null_rdev(cached) == Some(DEV_NULL_RDEV.load(Ordering::Acquire)))
+        .then_some(cached)
+}
+
+/// The cached `/dev/null` descriptor, opened on first use
+fn dev_null() -> Result<libc::c_int, i32> {
+    if let Some(cached) = cached_dev_null() {
+        return Ok(cached);
+    }
+    let cached = DEV_NULL.load(Ordering::Acquire);
+    let fd = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_CLOEXEC)
+        .open("/dev/null")
+        .map(IntoRawFd::into_raw_fd)
+        .map_err(|_| -1)?;
+    let rdev = null_rdev(fd).ok_or(-1)?;
+    DEV_NULL_RDEV.store(rdev, Ordering::Release);
+    match DEV_NULL.compare_exchange(cached, fd, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => Ok(fd),
+        Err(other) => {
+            // another thread reopened it first
+            unsafe { libc::close(fd) };
+            Ok(other)
+        }
+    }
+}
+
+/// Duplicate of the cached `/dev/null` descriptor as a `File`
+fn null_file() -> Result<File, i32> {
+    match unsafe { libc::fcntl(dev_null()?, libc::F_DUPFD_CLOEXEC, 0) } {
         -1 => Err(-1),
-        res => Ok(res),
+        fd => Ok(unsafe { File::from_raw_fd(fd) }),
     }
 }
 
-/// The process group of the current process [see getgrp(2)](https://www.freebsd.org/cgi/man.cgi?query=getpgrp)
-///
-/// # Errors
-/// returns `-1` if error
-pub fn getpgrp() -> Result<libc::pid_t, i32> {
-    let res = unsafe { libc::getpgrp() };
-    match res {
+/// Device number of `fd` if it is a character device
+fn null_rdev(fd: libc::c_int) -> Option<u64> {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } == -1 || stat.st_mode & libc::S_IFMT != libc::S_IFCHR {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.st_rdev as u64)
+}
+
+/// `dup2(src, dst)`
+fn dup_onto(src: libc::c_int, dst: libc::c_int) -> Result<(), i32> {
+    match unsafe { libc::dup2(src, dst) } {
         -1 => Err(-1),
-        res => Ok(res),
+        _ => Ok(()),
     }
 }
 
-/// The daemon function is for programs wishing to detach themselves from the
-/// controlling terminal and run in the background as system daemons.
-///
-/// * `nochdir = false`, changes the current working directory to the root (`/`).
-/// * `noclose = false`, will close standard input, standard output, and standard error
+/// Redirect `dst` to the file at `path`, appending when writing
+fn redirect_file(path: &Path, dst: libc::c_int) -> Result<(), i32> {
+    let mut options = OpenOptions::new();
+    options.custom_flags(libc::O_CLOEXEC);
+    if dst == 0 {
+        options.read(true);
+    } else {
+        options.append(true).create(true);
+    }
+    let file = options.open(path).map_err(|_| -1)?;
+    dup_onto(file.as_raw_fd(), dst)
+}
+
+/// Connect stdin to `/dev/null`, leaving stdout and stderr alone
 ///
 /// # Errors
-/// If an error occurs, returns -1
+/// returns `-1` if `/dev/null` can't be opened or `dup2` fails
+pub fn redirect_stdin_null() -> Result<(), i32> {
+    dup_onto(dev_null()?, 0)
+}
+
+/// Connect stdout to `/dev/null`, leaving stdin and stderr alone
 ///
 /// Example:
 ///
 ///```
-///// The parent forks the child
-///// The parent exits
-///// The child calls setsid() to start a new session with no controlling terminals
-///// The child forks a grandchild
-///// The child exits
-///// The grandchild is now the daemon
-///use fork::{daemon, Fork};
-///use std::process::Command;
+///use fork::{redirect_stdin_null, redirect_stdout_null};
 ///
-///if let Ok(Fork::Child) = daemon(false, false) {
-///    Command::new("sleep")
-///        .arg("3")
-///        .output()
-///        .expect("failed to execute process");
-///}
+///// keep stderr so errors still reach the terminal or the service manager
+///redirect_stdin_null().expect("failed to redirect stdin");
+///redirect_stdout_null().expect("failed to redirect stdout");
+///eprintln!("still visible");
 ///```
-pub fn daemon(nochdir: bool, noclose: bool) -> Result<Fork, i32> {
-    match fork() {
-        Ok(Fork::Parent(_)) => exit(0),
-        Ok(Fork::Child) => setsid().and_then(|_| {
-            if !nochdir {
-                chdir()?;
-            }
-            if !noclose {
-                close_fd()?;
-            }
-            fork()
-        }),
-        Err(n) => Err(n),
-    }
+///
+/// # Errors
+/// returns `-1` if `/dev/null` can't be opened or `dup2` fails
+pub fn redirect_stdout_null() -> Result<(), i32> {
+    dup_onto(dev_null()?, 1)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::{fork, Fork};
-
-    #[test]
+/// Connect stderr to `/dev/null`, leaving stdin and stdout alone
+///
+/// # Errors
+/// returns `-1` if `/dev/null` can't be opened or `dup2` fails
+pub fn redirect_stderr_null() -> Result<(), i32> {
+    dup_onto(dev_null()?, 2)
+}
+
+/// Read stdin from the file at `path`
+///
+/// # Errors
+/// returns `-1` if the file can't be opened or `dup2` fails
+pub fn redirect_stdin_file<P: AsRef<Path>>(path: P) -> Result<(), i32> {
+    redirect_file(path.as_ref(), 0)
+}
+
+/// Append stdout to the file at `path`, created if missing
+///
+/// # Errors
+/// returns `-1` if the file can't be opened or `dup2` fails
+pub fn redirect_stdout_file<P: AsRef<Path>>(path: P) -> Result<(), i32> {
+    redirect_file(path.as_ref(), 1)
+}
+
+/// Append stderr to the file at `path`, created if missing
+///
+/// # Errors
+/// returns `-1` if the file can't be opened or `dup2` fails
+pub fn redirect_stderr_file<P: AsRef<Path>>(path: P) -> Result<(), i32> {
+    redirect_file(path.as_ref(), 2)
+}
+
+/// Make stdin a duplicate of `fd`
+///
+/// # Errors
+/// returns `-1` if `dup2` fails
+pub fn redirect_stdin_fd(fd: libc::c_int) -> Result<(), i32> {
+    dup_onto(fd, 0)
+}
+
+/// Make stdout a duplicate of `fd`
+///
+/// # Errors
+/// returns `-1` if `dup2` fails
+pub fn redirect_stdout_fd(fd: libc::c_int) -> Result<(), i32> {
+    dup_onto(fd, 1)
+}
+
+/// Make stderr a duplicate of `fd`
+///
+/// # Errors
+/// returns `-1` if `dup2` fails
+pub fn redirect_stderr_fd(fd: libc::c_int) -> Result<(), i32> {
+    dup_onto(fd, 2)
+}
+
+/// Like [`redirect_stdio`], returning duplicates of what stdin, stdout and
+/// stderr pointed to before
+///
+/// The duplicates are close-on-exec. They can be used to keep writing startup
+/// messages to the real terminal until detachment completes, or to put the
+/// descriptors back with [`OriginalStdio::restore`].
+///
+/// Example:
+///
+///```
+///use fork::redirect_stdio_saved;
+///use std::io::Write;
+///
+///let original = redirect_stdio_saved().expect("failed to redirect stdio");
+///println!("this goes to /dev/null");
+///if let Some(mut stderr) = original.stderr() {
+///    writeln!(stderr, "still reaching the terminal").expect("failed to write");
+///}
+///original.restore().expect("failed to restore stdio");
+///```
+///
+/// # Errors
+/// returns `-1` if a descriptor can't be duplicated or redirected
+pub fn redirect_stdio_saved() -> Result<OriginalStdio, i32> {
+    let save = |fd| match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 3) } {
+        -1 => match io::Error::last_os_error().raw_os_error() {
+            Some(libc::EBADF) => Ok(None),
+            _ => Err(-1),
+        },
+        dup => Ok(Some(unsafe { File::from_raw_fd(dup) })),
+    };
+    let original = OriginalStdio {
+        stdin: save(0)?,
+        stdout: save(1)?,
+        stderr: save(2)?,
+    };
+    redirect_stdio()?;
+    Ok(original)
+}
+
+/// Duplicates of stdin, stdout and stderr saved by [`redirect_stdio_saved`],
+/// `None` for the ones that were closed
+#[derive(Debug)]
+pub struct OriginalStdio {
+    stdin: Option<File>,
+    stdout: Option<File>,
+    stderr: Option<File>,
+}
+
+impl OriginalStdio {
+    /// What stdin pointed to
+    #[must_use]
+    pub const fn stdin(&self) -> Option<&File> {
+        self.stdin.as_ref()
+    }
+
+    /// What stdout pointed to
+    #[must_use]
+    pub const fn stdout(&self) -> Option<&File> {
+        self.stdout.as_ref()
+    }
+
+    /// What stderr pointed to
+    #[must_use]
+    pub const fn stderr(&self) -> Option<&File> {
+        self.stderr.as_ref()
+    }
+
+    /// Point stdin, stdout and stderr back to where they were, closing the ones
+    /// that were closed
+    ///
+    /// # Errors
+    /// returns `-1` if a `dup2` fails
+    pub fn restore(self) -> Result<(), i32> {
+        for (fd, original) in (0..).zip([&self.stdin, &self.stdout, &self.stderr]) {
+            let res = original.as_ref().map_or_else(
+                || unsafe { libc::close(fd) },
+                |file| unsafe { libc::dup2(file.as_raw_fd(), fd) },
+            );
+            if res == -1 {
+                return Err(-1);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a descriptor of an [`FdPlan`] ends up as
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FdSource {
+    Fd(libc::c_int),
+    Null,
+    Close,
+}
+
+/// Where a step of an [`FdPlan`] takes its descriptor from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FdSlot {
+    Fd(libc::c_int),
+    /// a duplicate saved to break a cycle
+    Saved(usize),
+    Null,
+}
+
+/// A step of an [`FdPlan`], ordered so no source is overwritten before use
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FdStep {
+    Dup(FdSlot, libc::c_int),
+    Save(libc::c_int, usize),
+    Inherit(libc::c_int),
+    Close(libc::c_int),
+}
+
+/// Layout of descriptors to set up in a child, generalizing [`redirect_stdio`]
+/// to any set of descriptors
+///
+/// The mappings are applied as if all at once: `map(3, 4).map(4, 3)` swaps the
+/// two descriptors, with a temporary duplicate to break the cycle. The target
+/// descriptors are inheritable afterwards, descriptors passed to
+/// [`FdPlan::close`] are closed last. Setting a target again replaces the
+/// earlier mapping.
+///
+/// Example:
+///
+///```
+///use fork::FdPlan;
+///use std::os::unix::io::AsRawFd;
+///use std::process::Command;
+///
+///let path = std::env::temp_dir().join(format!("fork-plan-{}.log", std::process::id()));
+///let log = std::fs::File::create(&path).expect("failed to create log");
+///let status = FdPlan::new()
+///    .null(0)
+///    .map(log.as_raw_fd(), 1)
+///    .map(1, 2)
+///    .apply(Command::new("sh").args(["-c", "echo out; echo err >&2"]))
+///    .status()
+///    .expect("failed to run sh");
+///assert!(status.success());
+///// stderr got the original stdout, not the log
+///assert_eq!(std::fs::read_to_string(&path).ok(), Some("out\n".into()));
+///# std::fs::remove_file(&path).ok();
+///```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FdPlan {
+    targets: Vec<(libc::c_int, FdSource)>,
+}
+
+impl FdPlan {
+    /// A plan that leaves every descriptor alone
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make `dst` a duplicate of what `src` is before the plan is applied
+    #[must_use]
+    pub fn map(self, src: libc::c_int, dst: libc::c_int) -> Self {
+        self.target(dst, FdSource::Fd(src))
+    }
+
+    /// Connect `dst` to `/dev/null`
+    #[must_use]
+    pub fn null(self, dst: libc::c_int) -> Self {
+        self.target(dst, FdSource::Null)
+    }
+
+    /// Close `fd` once the mappings are done
+    #[must_use]
+    pub fn close(self, fd: libc::c_int) -> Self {
+        self.target(fd, FdSource::Close)
+    }
+
+    fn target(mut self, dst: libc::c_int, source: FdSource) -> Self {
+        self.targets.retain(|&(fd, _)| fd != dst);
+        self.targets.push((dst, source));
+        self
+    }
+
+    /// Apply the plan to the calling process, usually a child after [`fork`]
+    ///
+    /// # Errors
+    /// returns `-1` if a source isn't open, or if `/dev/null` can't be opened
+    pub fn perform(&self) -> Result<(), i32> {
+        let (steps, saved, base) = self.schedule();
+        let mut saved = vec![-1; saved];
+        unsafe { run_fd_steps(&steps, &mut saved, base) }
+    }
+
+    /// Apply the plan right before `cmd` executes, after the standard streams
+    /// configured on `cmd` are set up
+    ///
+    /// The order of the steps is computed here, the hook only makes system
+    /// calls. Spawning fails if a source isn't open.
+    pub fn apply<'a>(&self, cmd: &'a mut Command) -> &'a mut Command {
+        let (steps, saved, base) = self.schedule();
+        let mut saved = vec![-1; saved];
+        unsafe {
+            cmd.pre_exec(move || {
+                run_fd_steps(&steps, &mut saved, base).map_err(|_| io::Error::last_os_error())
+            })
+        }
+    }
+
+    /// The steps, the number of saved duplicates and the lowest descriptor the
+    /// duplicates and `/dev/null` may use without clashing with the plan
+    fn schedule(&self) -> (Vec<FdStep>, usize, libc::c_int) {
+        let base = self
+            .targets
+            .iter()
+            .flat_map(|&(dst, source)| match source {
+                FdSource::Fd(src) => [dst, src],
+                _ => [dst, dst],
+            })
+            .fold(2, libc::c_int::max)
+            + 1;
+        let mut moves = Vec::new();
+        let mut last = Vec::new();
+        for &(dst, source) in &self.targets {
+            match source {
+                FdSource::Fd(src) if src == dst => last.push(FdStep::Inherit(dst)),
+                FdSource::Fd(src) => moves.push((FdSlot::Fd(src), dst)),
+                FdSource::Null => moves.push((FdSlot::Null, dst)),
+                FdSource::Close => last.push(FdStep::Close(dst)),
+            }
+        }
+
+        let mut steps = Vec::new();
+        let mut saved = 0;
+        while !moves.is_empty() {
+            let free = moves
+                .iter()
+                .position(|&(_, dst)| !moves.iter().any(|&(src, _)| src == FdSlot::Fd(dst)));
+            if let Some(i) = free {
+                let (src, dst) = moves.remove(i);
+                steps.push(FdStep::Dup(src, dst));
+                continue;
+            }
+            // every pending target is still needed as a source, save one
+            let Some(fd) = moves.iter().find_map(|&(_, dst)| {
+                moves
+                    .iter()
+                    .any(|&(src, _)| src == FdSlot::Fd(dst))
+                    .then_some(dst)
+            }) else {
+                break;
+            };
+            steps.push(FdStep::Save(fd, saved));
+            for (src, _) in &mut moves {
+                if *src == FdSlot::Fd(fd) {
+                    *src = FdSlot::Saved(saved);
+                }
+            }
+            saved += 1;
+        }
+        steps.extend(last);
+        (steps, saved, base)
+    }
+}
+
+/// Run the steps of an [`FdPlan`], only making system calls
+unsafe fn run_fd_steps(
+    steps: &[FdStep],
+    saved: &mut [libc::c_int],
+    base: libc::c_int,
+) -> Result<(), i32> {
+    let mut null = -1;
+    let mut result = Ok(());
+    for &step in steps {
+        let res = match step {
+            FdStep::Dup(src, dst) => {
+                let src = match src {
+                    FdSlot::Fd(fd) => fd,
+                    FdSlot::Saved(i) => saved[i],
+                    FdSlot::Null => {
+                        if null == -1 {
+                            null =
+                                libc::open(c"/dev/null".as_ptr(), libc::O_RDWR | libc::O_CLOEXEC);
+                            if null != -1 {
+                                let moved = libc::fcntl(null, libc::F_DUPFD_CLOEXEC, base);
+                                libc::close(null);
+                                null = moved;
+                            }
+                        }
+                        null
+                    }
+                };
+                if src == -1 {
+                    -1
+                } else {
+                    libc::dup2(src, dst)
+                }
+            }
+            FdStep::Save(fd, i) => {
+                saved[i] = libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, base);
+                saved[i]
+            }
+            FdStep::Inherit(fd) => match libc::fcntl(fd, libc::F_GETFD) {
+                -1 => -1,
+                flags => libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC),
+            },
+            FdStep::Close(fd) => {
+                libc::close(fd);
+                0
+            }
+        };
+        if res == -1 {
+            result = Err(-1);
+            break;
+        }
+    }
+    for fd in saved.iter_mut().chain([&mut null]) {
+        if *fd != -1 {
+            libc::close(*fd);
+            *fd = -1;
+        }
+    }
+    result
+}
+
+/// Which descriptors [`audit_fds`] accepts besides stdio
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FdPolicy {
+    keep: Vec<libc::c_int>,
+    deny_unexpected: bool,
+}
+
+impl FdPolicy {
+    /// Accept only stdio and report everything else as unexpected
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept `fd`, for sockets or lock files the daemon keeps on purpose
+    #[must_use]
+    pub fn keep(mut self, fd: libc::c_int) -> Self {
+        self.keep.push(fd);
+        self
+    }
+
+    /// Make [`audit_fds`] fail when unexpected descriptors are open
+    #[must_use]
+    pub const fn deny_unexpected(mut self, deny: bool) -> Self {
+        self.deny_unexpected = deny;
+        self
+    }
+
+    /// Let `cmd` inherit only stdio and the kept descriptors
+    ///
+    /// Right before `exec` every other descriptor is marked close-on-exec, with
+    /// a single `close_range(CLOSE_RANGE_CLOEXEC)` on Linux 5.11 and later, and
+    /// the kept ones are checked to be open and made inheritable. Spawning
+    /// fails if a kept descriptor isn't open. Use this for setuid helpers,
+    /// which must not see descriptors leaked by libraries of the daemon.
+    ///
+    /// Example:
+    ///
+    ///```
+    ///use fork::FdPolicy;
+    ///use std::net::TcpListener;
+    ///use std::os::unix::io::AsRawFd;
+    ///use std::process::Command;
+    ///
+    ///let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+    ///let status = FdPolicy::new()
+    ///    .keep(listener.as_raw_fd())
+    ///    .apply(&mut Command::new("true"))
+    ///    .status()
+    ///    .expect("failed to run true");
+    ///assert!(status.success());
+    ///```
+    pub fn apply<'a>(&self, cmd: &'a mut Command) -> &'a mut Command {
+        let mut keep: Vec<libc::c_int> = self.keep.iter().copied().filter(|&fd| fd > 2).collect();
+        keep.sort_unstable();
+        keep.dedup();
+        unsafe {
+            cmd.pre_exec(move || {
+                let mut first = 3;
+                for &fd in &keep {
+                    cloexec_range(first, fd - 1);
+                    first = fd + 1;
+                }
+                cloexec_range(first, libc::c_int::MAX);
+                for &fd in &keep {
+                    let flags = libc::fcntl(fd, libc::F_GETFD);
+                    if flags == -1
+                        || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) == -1
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            })
+        }
+    }
+}
+
+/// Most descriptors probed one by one when they can't be listed, so that an
+/// unlimited `RLIMIT_NOFILE` doesn't mean 2^31 system calls
+const FD_PROBE_MAX: libc::c_int = 65536;
+
+/// Number of descriptors to probe, the soft `RLIMIT_NOFILE` up to
+/// [`FD_PROBE_MAX`], only makes system calls
+fn fd_probe_limit() -> libc::c_int {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == -1 {
+        return 1024;
+    }
+    libc::c_int::try_from(limit.rlim_cur).map_or(FD_PROBE_MAX, |max| max.min(FD_PROBE_MAX))
+}
+
+/// Mark the open descriptors from `first` to `last` close-on-exec
+unsafe fn cloexec_range(first: libc::c_int, last: libc::c_int) {
+    if first > last {
+        return;
+    }
+    #[cfg(target_os = "linux")]
+    if libc::syscall(
+        libc::SYS_close_range,
+        first as libc::c_uint,
+        last as libc::c_uint,
+        libc::CLOSE_RANGE_CLOEXEC,
+    ) == 0
+    {
+        return;
+    }
+    for fd in first..=last.min(fd_probe_limit() - 1) {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags != -1 {
+            libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC);
+        }
+    }
+}
+
+/// Open descriptors found by [`audit_fds`], sorted
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FdReport {
+    /// Open descriptors among 0, 1 and 2
+    pub stdio: Vec<libc::c_int>,
+    /// Open descriptors accepted by [`FdPolicy::keep`]
+    pub kept: Vec<libc::c_int>,
+    /// Everything else, usually leaked by the launching process
+    pub unexpected: Vec<libc::c_int>,
+}
+
+impl FdReport {
+    /// No unexpected descriptors are open
+    #[must_use]
+    pub const fn is_clean(&self) -> bool {
+        self.unexpected.is_empty()
+    }
+}
+
+/// List the open descriptors of the process and classify them against `policy`
+///
+/// Meant to run right after daemonizing to catch descriptors inherited from the
+/// launching shell. Descriptors are read from `/proc/self/fd` or `/dev/fd`, and
+/// probed up to the `RLIMIT_NOFILE` soft limit if neither is available. The
+/// `/dev/null` descriptor kept by the redirect functions belongs to this crate
+/// and isn't reported.
+///
+/// Example:
+///
+///```
+///use fork::{audit_fds, FdPolicy};
+///
+///let report = audit_fds(&FdPolicy::new()).expect("failed to list descriptors");
+///for fd in &report.unexpected {
+///    eprintln!("descriptor {fd} leaked into the daemon");
+///}
+///```
+///
+/// # Errors
+/// returns `-1` if the descriptors can't be listed, or if unexpected descriptors
+/// are open and the policy denies them
+pub fn audit_fds(policy: &FdPolicy) -> Result<FdReport, i32> {
+    let mut report = FdReport::default();
+    let null = cached_dev_null();
+    for fd in open_fds()? {
+        if null == Some(fd) {
+            continue;
+        }
+        if (0..=2).contains(&fd) {
+            report.stdio.push(fd);
+        } else if policy.keep.contains(&fd) {
+            report.kept.push(fd);
+        } else {
+            report.unexpected.push(fd);
+        }
+    }
+    if policy.deny_unexpected && !report.is_clean() {
+        return Err(-1);
+    }
+    Ok(report)
+}
+
+/// Sorted list of the open descriptors, probed up to [`fd_probe_limit`] if
+/// they can't be listed
+fn open_fds() -> Result<Vec<libc::c_int>, i32> {
+    let is_open = |fd: libc::c_int| unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1;
+    for dir in ["/proc/self/fd", "/dev/fd"] {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            continue;
+        };
+        let mut fds: Vec<libc::c_int> = entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect();
+        // the descriptor used to read the directory is closed by now
+        fds.retain(|&fd| is_open(fd));
+        fds.sort_unstable();
+        return Ok(fds);
+    }
+    Ok((0..fd_probe_limit()).filter(|&fd| is_open(fd)).collect())
+}
+
+/// Create a new child process [see fork(2)](https://www.freebsd.org/cgi/man.cgi?fork)
+///
+/// Upon successful completion, `fork()` returns a value of 0 to the child process
+/// and returns the process ID of the child process to the parent process.
+/// Otherwise, a value of -1 is returned to the parent process, no child process
+/// is created.
+///
+/// Example:
+///
+/// ```
+///use fork::{fork, Fork};
+///
+///match fork() {
+///    Ok(Fork::Parent(child)) => {
+///        println!("Continuing execution in parent process, new child has pid: {}", child);
+///    }
+///    Ok(Fork::Child) => println!("I'm a new child process"),
+///    Err(_) => println!("Fork failed"),
+///}
+///```
+/// This will print something like the following (order indeterministic).
+///
+/// ```text
+/// Continuing execution in parent process, new child has pid: 1234
+/// I'm a new child process
+/// ```
+///
+/// The thing to note is that you end up with two processes continuing execution
+/// immediately after the fork call but with different match arms.
+///
+/// # [`nix::unistd::fork`](https://docs.rs/nix/0.15.0/nix/unistd/fn.fork.html)
+///
+/// The example has been taken from the [`nix::unistd::fork`](https://docs.rs/nix/0.15.0/nix/unistd/fn.fork.html),
+/// please check the [Safety](https://docs.rs/nix/0.15.0/nix/unistd/fn.fork.html#safety) section
+///
+/// # Errors
+/// returns `-1` if error
+pub fn fork() -> Result<Fork, i32> {
+    let res = unsafe { libc::fork() };
+    match res {
+        -1 => Err(-1),
+        0 => Ok(Fork::Child),
+        res => Ok(Fork::Parent(res)),
+    }
+}
+
+/// Which process of a [`double_fork`] sees [`DoubleFork::Parent`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// The calling process, its child is the session leader
+    First,
+    /// The session leader, its child is the final process
+    Intermediate,
+}
+
+/// Result of [`double_fork`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DoubleFork {
+    /// One of the two parents, holding the pid of its own child
+    Parent { child: libc::pid_t, stage: Stage },
+    /// The final process, in a new session it isn't the leader of
+    Child,
+}
+
+/// Fork, start a new session in the child and fork again, the building block
+/// of [`daemon_with`] for callers that compose their own sequence
+///
+/// Three processes return: the caller and the session leader as
+/// [`DoubleFork::Parent`] with their [`Stage`], and the grandchild as
+/// [`DoubleFork::Child`]. Only system calls are made in between.
+///
+/// Example:
+///
+///```
+///use fork::{double_fork, waitpid, DoubleFork, Stage};
+///
+///match double_fork() {
+///    Ok(DoubleFork::Parent { child, stage: Stage::First }) => {
+///        waitpid(child).expect("waitpid failed");
+///    }
+///    Ok(DoubleFork::Parent { stage: Stage::Intermediate, .. }) => std::process::exit(0),
+///    Ok(DoubleFork::Child) => {
+///        // detached, reparented once the session leader exits
+///        std::process::exit(0);
+///    }
+///    Err(_) => eprintln!("double fork failed"),
+///}
+///```
+///
+/// # Errors
+/// returns `-1` if the first `fork` fails, the session leader exits with
+/// [`exitcode::OSERR`] if `setsid` or the second `fork` fails
+pub fn double_fork() -> Result<DoubleFork, i32> {
+    let child = fork()?;
+    if let Fork::Parent(child) = child {
+        return Ok(DoubleFork::Parent {
+            child,
+            stage: Stage::First,
+        });
+    }
+    if unsafe { libc::setsid() } == -1 {
+        unsafe { libc::_exit(exitcode::OSERR) };
+    }
+    match unsafe { libc::fork() } {
+        -1 => unsafe { libc::_exit(exitcode::OSERR) },
+        0 => Ok(DoubleFork::Child),
+        child => Ok(DoubleFork::Parent {
+            child,
+            stage: Stage::Intermediate,
+        }),
+    }
+}
+
+/// Wait for process to change status [see wait(2)](https://man.freebsd.org/cgi/man.cgi?waitpid)
+///
+/// A wait interrupted by a signal handler is restarted, see [`waitpid_with`]
+/// to return instead.
+///
+/// # Errors
+/// returns `-1` if error
+///
+/// Example:
+///
+/// ```
+///use fork::{waitpid, Fork};
+///use std::process::Command;
+///
+///fn main() {
+///  match fork::fork() {
+///     Ok(Fork::Parent(pid)) => {
+///
+///         println!("Child pid: {pid}");
+///
+///         match waitpid(pid) {
+///             Ok(_) => println!("Child existed"),
+///             Err(_) => eprintln!("Failted to wait on child"),
+///         }
+///     }
+///     Ok(Fork::Child) => {
+///         Command::new("sleep")
+///             .arg("1")
+///             .output()
+///             .expect("failed to execute process");
+///     }
+///     Err(_) => eprintln!("Failed to fork"),
+///  }
+///}
+///```
+pub fn waitpid(pid: i32) -> Result<(), i32> {
+    waitpid_status(pid).map(|_| ())
+}
+
+/// What the wait functions do when a signal handler interrupts them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Interrupt {
+    /// Wait again, the default
+    #[default]
+    Retry,
+    /// Return [`WaitStatus::Interrupted`] so the caller can react to the signal
+    Return,
+}
+
+/// Wait for process `pid` to change status, with `interrupt` deciding what
+/// happens on `EINTR`
+///
+/// Example:
+///
+///```no_run
+///use fork::{fork, waitpid_with, Fork, Interrupt, WaitStatus};
+///use std::sync::atomic::{AtomicBool, Ordering};
+///
+///static RELOAD: AtomicBool = AtomicBool::new(false);
+///
+///if let Ok(Fork::Parent(child)) = fork() {
+///    loop {
+///        match waitpid_with(child, Interrupt::Return) {
+///            Ok(WaitStatus::Interrupted) if RELOAD.swap(false, Ordering::SeqCst) => {
+///                // reload configuration, then keep waiting
+///            }
+///            Ok(WaitStatus::Interrupted) => {}
+///            Ok(status) => break println!("child exited: {status:?}"),
+///            Err(_) => break,
+///        }
+///    }
+///}
+///```
+///
+/// # Errors
+/// returns `-1` if `waitpid` fails for another reason than `EINTR`
+pub fn waitpid_with(pid: libc::pid_t, interrupt: Interrupt) -> Result<WaitStatus, i32> {
+    let mut status: libc::c_int = 0;
+    loop {
+        match unsafe { libc::waitpid(pid, &mut status, 0) } {
+            -1 => match (io::Error::last_os_error().raw_os_error(), interrupt) {
+                (Some(libc::EINTR), Interrupt::Retry) => {}
+                (Some(libc::EINTR), Interrupt::Return) => return Ok(WaitStatus::Interrupted),
+                _ => return Err(-1),
+            },
+            _ => return Ok(WaitStatus::from_raw(status)),
+        }
+    }
+}
+
+/// Stop process `pid` with `SIGSTOP` until [`resume`] is called, e.g. to
+/// inspect a misbehaving worker
+///
+/// Example:
+///
+///```
+///use fork::{fork, resume, suspend, wait_state_change, Fork, Signal, WaitStatus};
+///
+///match fork() {
+///    Ok(Fork::Parent(child)) => {
+///        suspend(child).expect("failed to stop child");
+///        assert_eq!(wait_state_change(child), Ok(WaitStatus::Stopped(Signal::SIGSTOP)));
+///        resume(child).expect("failed to continue child");
+///        assert_eq!(wait_state_change(child), Ok(WaitStatus::Continued));
+///        assert_eq!(wait_state_change(child), Ok(WaitStatus::Exited(0)));
+///    }
+///    Ok(Fork::Child) => {
+///        std::thread::sleep(std::time::Duration::from_millis(200));
+///        std::process::exit(0);
+///    }
+///    Err(_) => panic!("fork failed"),
+///}
+///```
+///
+/// # Errors
+/// returns `-1` if the signal can't be sent
+pub fn suspend(pid: libc::pid_t) -> Result<(), i32> {
+    match unsafe { libc::kill(pid, libc::SIGSTOP) } {
+        -1 => Err(-1),
+        _ => Ok(()),
+    }
+}
+
+/// Continue process `pid` stopped by [`suspend`] with `SIGCONT`
+///
+/// # Errors
+/// returns `-1` if the signal can't be sent
+pub fn resume(pid: libc::pid_t) -> Result<(), i32> {
+    match unsafe { libc::kill(pid, libc::SIGCONT) } {
+        -1 => Err(-1),
+        _ => Ok(()),
+    }
+}
+
+/// Wait for child `pid` to exit, stop or continue, reporting
+/// [`WaitStatus::Stopped`] and [`WaitStatus::Continued`] as well, retrying on
+/// `EINTR`
+///
+/// # Errors
+/// returns `-1` if `waitpid` fails
+pub fn wait_state_change(pid: libc::pid_t) -> Result<WaitStatus, i32> {
+    let mut status: libc::c_int = 0;
+    loop {
+        match unsafe { libc::waitpid(pid, &mut status, libc::WUNTRACED | libc::WCONTINUED) } {
+            -1 if io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) => {}
+            -1 => return Err(-1),
+            _ => return Ok(WaitStatus::from_raw(status)),
+        }
+    }
+}
+
+/// A signal number, the common ones are available as associated constants
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Signal(libc::c_int);
+
+impl Signal {
+    pub const SIGHUP: Self = Self(libc::SIGHUP);
+    pub const SIGINT: Self = Self(libc::SIGINT);
+    pub const SIGQUIT: Self = Self(libc::SIGQUIT);
+    pub const SIGKILL: Self = Self(libc::SIGKILL);
+    pub const SIGUSR1: Self = Self(libc::SIGUSR1);
+    pub const SIGUSR2: Self = Self(libc::SIGUSR2);
+    pub const SIGPIPE: Self = Self(libc::SIGPIPE);
+    pub const SIGALRM: Self = Self(libc::SIGALRM);
+    pub const SIGTERM: Self = Self(libc::SIGTERM);
+    pub const SIGCHLD: Self = Self(libc::SIGCHLD);
+    pub const SIGCONT: Self = Self(libc::SIGCONT);
+    pub const SIGSTOP: Self = Self(libc::SIGSTOP);
+    pub const SIGTSTP: Self = Self(libc::SIGTSTP);
+    pub const SIGWINCH: Self = Self(libc::SIGWINCH);
+    pub const SIGXCPU: Self = Self(libc::SIGXCPU);
+
+    /// Signal from its raw number
+    #[must_use]
+    pub const fn from_raw(signum: libc::c_int) -> Self {
+        Self(signum)
+    }
+
+    /// The raw signal number
+    #[must_use]
+    pub const fn as_raw(self) -> libc::c_int {
+        self.0
+    }
+
+    /// Signal from a name like `TERM` or `SIGTERM`, in any case, or from a
+    /// number like `15`
+    ///
+    /// Example:
+    ///
+    ///```
+    ///use fork::Signal;
+    ///
+    ///assert_eq!(Signal::from_name("SIGHUP"), Some(Signal::SIGHUP));
+    ///assert_eq!(Signal::from_name("term"), Some(Signal::SIGTERM));
+    ///assert_eq!(Signal::from_name(&Signal::SIGUSR1.to_string()), Some(Signal::SIGUSR1));
+    ///assert_eq!(Signal::from_name("9"), Some(Signal::SIGKILL));
+    ///assert_eq!(Signal::from_name("NOPE"), None);
+    ///```
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        if let Ok(signum) = name.parse() {
+            return (signum > 0).then_some(Self(signum));
+        }
+        let name = match name.get(..3) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("SIG") => &name[3..],
+            _ => name,
+        };
+        SIGNAL_NAMES
+            .iter()
+            .find(|(_, known)| known.eq_ignore_ascii_case(name))
+            .map(|&(signum, _)| Self(signum))
+    }
+}
+
+/// A set of signals for the signal mask and `sigwait`, built with
+/// [`SignalSet::add`]
+///
+/// Example:
+///
+///```
+///use fork::{Signal, SignalSet};
+///
+///let set = SignalSet::new().add(Signal::SIGHUP).add(Signal::SIGTERM);
+///assert!(set.contains(Signal::SIGTERM));
+///assert!(!set.remove(Signal::SIGTERM).contains(Signal::SIGTERM));
+///assert_eq!(set.signals(), [Signal::SIGHUP, Signal::SIGTERM]);
+///```
+#[derive(Clone, Copy)]
+pub struct SignalSet(libc::sigset_t);
+
+impl SignalSet {
+    /// The empty set
+    #[must_use]
+    pub fn new() -> Self {
+        let mut set = unsafe { std::mem::zeroed::<libc::sigset_t>() };
+        unsafe { libc::sigemptyset(&mut set) };
+        Self(set)
+    }
+
+    /// The set of all signals
+    #[must_use]
+    pub fn all() -> Self {
+        let mut set = unsafe { std::mem::zeroed::<libc::sigset_t>() };
+        unsafe { libc::sigfillset(&mut set) };
+        Self(set)
+    }
+
+    /// Add `signal`, numbers that aren't signals on this platform are ignored
+    #[must_use]
+    #[allow(clippy::should_implement_trait)]
+    pub fn add(mut self, signal: Signal) -> Self {
+        unsafe { libc::sigaddset(&mut self.0, signal.0) };
+        self
+    }
+
+    /// Remove `signal`
+    #[must_use]
+    pub fn remove(mut self, signal: Signal) -> Self {
+        unsafe { libc::sigdelset(&mut self.0, signal.0) };
+        self
+    }
+
+    /// Whether `signal` is in the set
+    #[must_use]
+    pub fn contains(&self, signal: Signal) -> bool {
+        unsafe { libc::sigismember(&self.0, signal.0) == 1 }
+    }
+
+    /// The signals in the set in ascending order
+    #[must_use]
+    pub fn signals(&self) -> Vec<Signal> {
+        (1..=64)
+            .map(Signal)
+            .filter(|&signal| self.contains(signal))
+            .collect()
+    }
+
+    /// The underlying `sigset_t`
+    #[must_use]
+    pub const fn as_raw(&self) -> &libc::sigset_t {
+        &self.0
+    }
+
+    /// Signal mask of the calling thread
+    ///
+    /// # Errors
+    /// returns `-1` if the mask can't be read
+    pub fn blocked() -> Result<Self, i32> {
+        Self::new().mask(libc::SIG_BLOCK)
+    }
+
+    /// Add the set to the signal mask of the calling thread, returning the
+    /// previous mask [see sigprocmask(2)](https://man.freebsd.org/cgi/man.cgi?sigprocmask)
+    ///
+    /// # Errors
+    /// returns `-1` if the mask can't be changed
+    pub fn block(&self) -> Result<Self, i32> {
+        self.mask(libc::SIG_BLOCK)
+    }
+
+    /// Remove the set from the signal mask of the calling thread, returning the
+    /// previous mask
+    ///
+    /// # Errors
+    /// returns `-1` if the mask can't be changed
+    pub fn unblock(&self) -> Result<Self, i32> {
+        self.mask(libc::SIG_UNBLOCK)
+    }
+
+    /// Make the set the signal mask of the calling thread, returning the
+    /// previous mask
+    ///
+    /// # Errors
+    /// returns `-1` if the mask can't be changed
+    pub fn set_mask(&self) -> Result<Self, i32> {
+        self.mask(libc::SIG_SETMASK)
+    }
+
+    fn mask(&self, how: libc::c_int) -> Result<Self, i32> {
+        let mut previous = Self::new();
+        match unsafe { libc::pthread_sigmask(how, &self.0, &mut previous.0) } {
+            0 => Ok(previous),
+            _ => Err(-1),
+        }
+    }
+
+    /// Wait for one of the signals in the set, which must be blocked, and
+    /// return it [see sigwait(2)](https://man.freebsd.org/cgi/man.cgi?sigwait)
+    ///
+    /// # Errors
+    /// returns `-1` if `sigwait` fails
+    pub fn wait(&self) -> Result<Signal, i32> {
+        let mut signum: libc::c_int = 0;
+        loop {
+            match unsafe { libc::sigwait(&self.0, &mut signum) } {
+                0 => return Ok(Signal(signum)),
+                libc::EINTR => {}
+                _ => return Err(-1),
+            }
+        }
+    }
+}
+
+impl Default for SignalSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for SignalSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.signals()).finish()
+    }
+}
+
+impl PartialEq for SignalSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.signals() == other.signals()
+    }
+}
+
+impl Eq for SignalSet {}
+
+impl FromIterator<Signal> for SignalSet {
+    fn from_iter<I: IntoIterator<Item = Signal>>(signals: I) -> Self {
+        signals.into_iter().fold(Self::new(), Self::add)
+    }
+}
+
+impl TryFrom<&[Signal]> for SignalSet {
+    type Error = i32;
+
+    /// Fails with `-1` if one of `signals` isn't a signal on this platform
+    fn try_from(signals: &[Signal]) -> Result<Self, i32> {
+        let mut set = Self::new();
+        for signal in signals {
+            if unsafe { libc::sigaddset(&mut set.0, signal.0) } == -1 {
+                return Err(-1);
+            }
+        }
+        Ok(set)
+    }
+}
+
+/// Names of the signals known to [`Signal::from_name`] and `Display`, without
+/// the `SIG` prefix
+const SIGNAL_NAMES: [(libc::c_int, &str); 29] = [
+    (libc::SIGHUP, "HUP"),
+    (libc::SIGINT, "INT"),
+    (libc::SIGQUIT, "QUIT"),
+    (libc::SIGILL, "ILL"),
+    (libc::SIGTRAP, "TRAP"),
+    (libc::SIGABRT, "ABRT"),
+    (libc::SIGBUS, "BUS"),
+    (libc::SIGFPE, "FPE"),
+    (libc::SIGKILL, "KILL"),
+    (libc::SIGUSR1, "USR1"),
+    (libc::SIGSEGV, "SEGV"),
+    (libc::SIGUSR2, "USR2"),
+    (libc::SIGPIPE, "PIPE"),
+    (libc::SIGALRM, "ALRM"),
+    (libc::SIGTERM, "TERM"),
+    (libc::SIGCHLD, "CHLD"),
+    (libc::SIGCONT, "CONT"),
+    (libc::SIGSTOP, "STOP"),
+    (libc::SIGTSTP, "TSTP"),
+    (libc::SIGTTIN, "TTIN"),
+    (libc::SIGTTOU, "TTOU"),
+    (libc::SIGURG, "URG"),
+    (libc::SIGXCPU, "XCPU"),
+    (libc::SIGXFSZ, "XFSZ"),
+    (libc::SIGVTALRM, "VTALRM"),
+    (libc::SIGPROF, "PROF"),
+    (libc::SIGWINCH, "WINCH"),
+    (libc::SIGIO, "IO"),
+    (libc::SIGSYS, "SYS"),
+];
+
+/// `SIGTERM` for the signals known to [`Signal::from_name`], the number
+/// otherwise
+impl std::fmt::Display for Signal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match SIGNAL_NAMES.iter().find(|&&(signum, _)| signum == self.0) {
+            Some((_, name)) => write!(f, "SIG{name}"),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// Status of a child process as reported by the wait functions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitStatus {
+    /// The child exited with this exit code
+    Exited(libc::c_int),
+    /// The child was terminated by this signal
+    Signaled(Signal),
+    /// The child was stopped by this signal
+    Stopped(Signal),
+    /// The stopped child was resumed by `SIGCONT`
+    Continued,
+    /// The child ran longer than its time budget and was killed
+    TimedOut,
+    /// The wait was interrupted by a signal, see [`Interrupt::Return`]
+    Interrupted,
+}
+
+impl WaitStatus {
+    const fn from_raw(status: libc::c_int) -> Self {
+        if libc::WIFEXITED(status) {
+            Self::Exited(libc::WEXITSTATUS(status))
+        } else if libc::WIFSIGNALED(status) {
+            Self::Signaled(Signal(libc::WTERMSIG(status)))
+        } else if libc::WIFSTOPPED(status) {
+            Self::Stopped(Signal(libc::WSTOPSIG(status)))
+        } else {
+            Self::Continued
+        }
+    }
+}
+
+/// Blocking `waitpid` returning how the child changed state, retrying on
+/// `EINTR`
+fn waitpid_status(pid: libc::pid_t) -> Result<WaitStatus, i32> {
+    waitpid_with(pid, Interrupt::Retry)
+}
+
+/// `waitpid` with `WNOHANG`, `None` while the child is still running
+fn try_waitpid(pid: libc::pid_t) -> Result<Option<WaitStatus>, i32> {
+    let mut status: libc::c_int = 0;
+    match unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } {
+        -1 => Err(-1),
+        0 => Ok(None),
+        _ => Ok(Some(WaitStatus::from_raw(status))),
+    }
+}
+
+/// Wait for a set of children and collect how each of them exited
+///
+/// Children are polled until all of them changed state or `timeout` expired.
+/// Results are best-effort: pids still running at the deadline, or that are not
+/// children of the caller, are missing from the returned map.
+///
+/// Example:
+///
+///```
+///use fork::{fork, wait_all, Fork, WaitStatus};
+///use std::process::exit;
+///use std::time::Duration;
+///
+///let mut children = Vec::new();
+///for code in 0..3 {
+///    match fork() {
+///        Ok(Fork::Parent(child)) => children.push(child),
+///        Ok(Fork::Child) => exit(code),
+///        Err(_) => panic!("fork failed"),
+///    }
+///}
+///
+///let statuses = wait_all(&children, Duration::from_secs(5));
+///assert_eq!(statuses[&children[2]], WaitStatus::Exited(2));
+///```
+#[must_use]
+pub fn wait_all(pids: &[libc::pid_t], timeout: Duration) -> HashMap<libc::pid_t, WaitStatus> {
+    let deadline = Instant::now() + timeout;
+    let mut pending = pids.to_vec();
+    let mut statuses = HashMap::with_capacity(pids.len());
+
+    loop {
+        pending.retain(|&pid| match try_waitpid(pid) {
+            Ok(Some(status)) => {
+                statuses.insert(pid, status);
+                false
+            }
+            Ok(None) => true,
+            Err(_) => false,
+        });
+        if pending.is_empty() || Instant::now() >= deadline {
+            return statuses;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Create session and set process group ID [see setsid(2)](https://www.freebsd.org/cgi/man.cgi?setsid)
+///
+/// Upon successful completion, the `setsid()` system call returns the value of the
+/// process group ID of the new process group, which is the same as the process ID
+/// of the calling process. If an error occurs, `setsid()` returns -1
+///
+/// # Errors
+/// returns `-1` if error
+pub fn setsid() -> Result<libc::pid_t, i32> {
+    let res = unsafe { libc::setsid() };
+    match res {
+        -1 => Err(-1),
+        res => Ok(res),
+    }
+}
+
+/// Set the login name of the session [see setlogin(2)](https://man.freebsd.org/cgi/man.cgi?setlogin)
+///
+/// BSD keeps the login name per session rather than per process, and a new
+/// session started with [`setsid`] inherits the name of the old one. Daemons
+/// started from init-like contexts call this as root right after `setsid`,
+/// and before dropping privileges, so that `getlogin` reports the account
+/// they run for.
+///
+/// Example:
+///
+///```no_run
+///use fork::{setlogin, setsid};
+///
+///setsid().expect("failed to start a session");
+///setlogin("www").expect("failed to set the login name");
+///```
+///
+/// # Errors
+/// returns `-1` if the name contains a nul byte or `setlogin` fails, e.g. when
+/// not running as root
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+pub fn setlogin(name: &str) -> Result<(), i32> {
+    let name = CString::new(name).map_err(|_| -1)?;
+    match unsafe { libc::setlogin(name.as_ptr()) } {
+        -1 => Err(-1),
+        _ => Ok(()),
+    }
+}
+
+/// The process group of the current process [see getgrp(2)](https://www.freebsd.org/cgi/man.cgi?query=getpgrp)
+///
+/// `getpgrp` is always successful.
+#[must_use]
+pub fn getpgrp() -> libc::pid_t {
+    unsafe { libc::getpgrp() }
+}
+
+/// The process ID of the current process [see getpid(2)](https://man.freebsd.org/cgi/man.cgi?getpid)
+///
+/// `getpid` is always successful.
+#[must_use]
+pub fn getpid() -> libc::pid_t {
+    unsafe { libc::getpid() }
+}
+
+/// The parent process ID of the current process [see getppid(2)](https://man.freebsd.org/cgi/man.cgi?getppid)
+///
+/// `getppid` is always successful, once the parent exits it is the pid of the
+/// process the child was reparented to.
+#[must_use]
+pub fn getppid() -> libc::pid_t {
+    unsafe { libc::getppid() }
+}
+
+/// The session ID of the current process [see getsid(2)](https://man.freebsd.org/cgi/man.cgi?getsid)
+///
+/// `getsid(0)` can't fail, errors are only possible when querying other processes.
+#[must_use]
+pub fn current_session_id() -> libc::pid_t {
+    unsafe { libc::getsid(0) }
+}
+
+/// Whether the current process is the leader of its session
+///
+/// A daemon created by [`daemon_with`] is not: the double fork leaves it in a new
+/// session that it can't reacquire a controlling terminal for.
+///
+/// Example:
+///
+///```
+///use fork::{fork, is_session_leader, setsid, waitpid, Fork};
+///
+///match fork() {
+///    Ok(Fork::Child) => {
+///        setsid().expect("setsid failed");
+///        assert!(is_session_leader());
+///    }
+///    Ok(Fork::Parent(child)) => {
+///        waitpid(child).expect("waitpid failed");
+///    }
+///    Err(_) => panic!("fork failed"),
+///}
+///```
+#[must_use]
+pub fn is_session_leader() -> bool {
+    current_session_id() == getpid()
+}
+
+/// Session, terminal and file creation state of the calling process, returned
+/// by [`process_state`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProcessState {
+    /// Process ID
+    pub pid: libc::pid_t,
+    /// Parent process ID
+    pub ppid: libc::pid_t,
+    /// Process group ID
+    pub pgid: libc::pid_t,
+    /// Session ID, the pid of the session leader
+    pub sid: libc::pid_t,
+    /// Whether the process has a controlling terminal
+    pub has_tty: bool,
+    /// Working directory, `None` if it can't be determined
+    pub cwd: Option<PathBuf>,
+    /// File mode creation mask
+    pub umask: libc::mode_t,
+}
+
+/// Gather the state a daemon is expected to be in with one call, for tests and
+/// health checks
+///
+/// The umask is read from `/proc/self/status` on Linux. Elsewhere it can only
+/// be read by setting it, so it is briefly changed and restored, which other
+/// threads creating files at the same time could observe.
+///
+/// Example:
+///
+///```
+///use fork::{fork, process_state, setsid, waitpid, Fork};
+///
+///match fork() {
+///    Ok(Fork::Child) => {
+///        setsid().expect("setsid failed");
+///        let state = process_state();
+///        assert_eq!(state.sid, state.pid);
+///        assert!(!state.has_tty);
+///        std::process::exit(0);
+///    }
+///    Ok(Fork::Parent(child)) => waitpid(child).expect("waitpid failed"),
+///    Err(_) => panic!("fork failed"),
+///}
+///```
+#[must_use]
+pub fn process_state() -> ProcessState {
+    ProcessState {
+        pid: getpid(),
+        ppid: getppid(),
+        pgid: getpgrp(),
+        sid: current_session_id(),
+        has_tty: has_controlling_tty(),
+        cwd: env::current_dir().ok(),
+        umask: current_umask(),
+    }
+}
+
+/// The file mode creation mask of the process
+fn current_umask() -> libc::mode_t {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(mask) = std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            let line = status
+                .lines()
+                .find_map(|line| line.strip_prefix("Umask:"))?;
+            libc::mode_t::from_str_radix(line.trim(), 8).ok()
+        })
+    {
+        return mask;
+    }
+    let mask = unsafe { libc::umask(0o022) };
+    unsafe { libc::umask(mask) };
+    mask
+}
+
+/// Make the terminal open on `fd` the controlling terminal of the calling
+/// process [see tty(4)](https://man.freebsd.org/cgi/man.cgi?tty&sektion=4)
+///
+/// The caller must be a session leader without a controlling terminal, for
+/// example right after [`setsid`] or in a daemon started with
+/// [`Daemon::double_fork`] disabled. This is how pty-based services take full
+/// ownership of the terminal they run programs in.
+///
+/// # Errors
+/// returns `-1` if `TIOCSCTTY` fails
+pub fn acquire_controlling_tty(fd: libc::c_int) -> Result<(), i32> {
+    match unsafe { libc::ioctl(fd, libc::TIOCSCTTY as _, 0) } {
+        -1 => Err(-1),
+        _ => Ok(()),
+    }
+}
+
+/// Start time of process `pid`, to tell it apart from a later process reusing
+/// its pid
+///
+/// The value is only meant to be compared with another reading for the same
+/// pid: clock ticks since boot from `/proc/<pid>/stat` on Linux, microseconds
+/// since the epoch from the kernel's process table on FreeBSD and macOS.
+/// Together with [`boot_id`] it identifies a process across reboots, e.g. to
+/// check that the pid in a pid file still belongs to the daemon that wrote it.
+///
+/// Example:
+///
+///```
+///use fork::{getpid, start_time};
+///
+///let started = start_time(getpid()).expect("failed to get start time");
+///assert_eq!(start_time(getpid()), Ok(started));
+///```
+///
+/// # Errors
+/// returns `-1` if the process doesn't exist or the platform isn't supported
+pub fn start_time(pid: libc::pid_t) -> Result<u64, i32> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).map_err(|_| -1)?;
+        // the command name in parentheses may contain spaces, field 22 is the
+        // 20th after it
+        let fields = &stat[stat.rfind(')').ok_or(-1)? + 1..];
+        fields
+            .split_whitespace()
+            .nth(19)
+            .and_then(|ticks| ticks.parse().ok())
+            .ok_or(-1)
+    }
+    #[cfg(target_os = "freebsd")]
+    {
+        let mut mib = [libc::CTL_KERN, libc::KERN_PROC, libc::KERN_PROC_PID, pid];
+        let mut info = unsafe { std::mem::zeroed::<libc::kinfo_proc>() };
+        let mut size = std::mem::size_of::<libc::kinfo_proc>();
+        let res = unsafe {
+            libc::sysctl(
+                mib.as_mut_ptr(),
+                4,
+                (&mut info as *mut libc::kinfo_proc).cast(),
+                &mut size,
+                std::ptr::null(),
+                0,
+            )
+        };
+        if res == -1 || size == 0 {
+            return Err(-1);
+        }
+        Ok(timeval_micros(info.ki_start))
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let mut info = unsafe { std::mem::zeroed::<libc::proc_bsdinfo>() };
+        let size = std::mem::size_of::<libc::proc_bsdinfo>() as libc::c_int;
+        let res = unsafe {
+            libc::proc_pidinfo(
+                pid,
+                libc::PROC_PIDTBSDINFO,
+                0,
+                (&mut info as *mut libc::proc_bsdinfo).cast(),
+                size,
+            )
+        };
+        if res != size {
+            return Err(-1);
+        }
+        Ok(info.pbi_start_tvsec * 1_000_000 + info.pbi_start_tvusec)
+    }
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos"
+    )))]
+    {
+        let _ = pid;
+        Err(-1)
+    }
+}
+
+/// Identifier of the current boot, changes on every reboot
+///
+/// The random `/proc/sys/kernel/random/boot_id` on Linux, the boot time from
+/// the `kern.boottime` sysctl on FreeBSD and macOS.
+///
+/// Example:
+///
+///```
+///use fork::boot_id;
+///
+///let boot = boot_id().expect("failed to get boot id");
+///assert_eq!(boot_id(), Ok(boot));
+///```
+///
+/// # Errors
+/// returns `-1` if it can't be read or the platform isn't supported
+pub fn boot_id() -> Result<String, i32> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        std::fs::read_to_string("/proc/sys/kernel/random/boot_id")
+            .map(|id| id.trim().to_string())
+            .map_err(|_| -1)
+    }
+    #[cfg(any(target_os = "freebsd", target_os = "macos"))]
+    {
+        let mut boottime = unsafe { std::mem::zeroed::<libc::timeval>() };
+        let mut size = std::mem::size_of::<libc::timeval>();
+        let res = unsafe {
+            libc::sysctlbyname(
+                c"kern.boottime".as_ptr(),
+                (&mut boottime as *mut libc::timeval).cast(),
+                &mut size,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        if res == -1 {
+            return Err(-1);
+        }
+        Ok(timeval_micros(boottime).to_string())
+    }
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos"
+    )))]
+    {
+        Err(-1)
+    }
+}
+
+/// Pids of the processes whose parent is `pid`, sorted
+///
+/// Example:
+///
+///```
+///use fork::{children_of, getpid};
+///use std::process::Command;
+///
+///let mut child = Command::new("sleep").arg("1").spawn().expect("failed to spawn");
+///let children = children_of(getpid()).expect("failed to list processes");
+///assert!(children.contains(&(child.id() as i32)));
+///child.kill().expect("failed to kill");
+///child.wait().expect("failed to wait");
+///```
+///
+/// # Errors
+/// returns `-1` if the process table can't be read or the platform isn't
+/// supported
+pub fn children_of(pid: libc::pid_t) -> Result<Vec<libc::pid_t>, i32> {
+    let mut children: Vec<libc::pid_t> = process_table()?
+        .into_iter()
+        .filter_map(|entry| (entry.ppid == pid).then_some(entry.pid))
+        .collect();
+    children.sort_unstable();
+    Ok(children)
+}
+
+/// Pids of the children of `pid`, their children and so on, sorted
+///
+/// Processes that left the process group or session of `pid`, like the
+/// background jobs of a shell script, are found as long as their parents are
+/// alive. Orphans are adopted by init or a subreaper and no longer show up.
+///
+/// # Errors
+/// returns `-1` if the process table can't be read or the platform isn't
+/// supported
+pub fn descendants_of(pid: libc::pid_t) -> Result<Vec<libc::pid_t>, i32> {
+    let table = process_table()?;
+    let mut descendants = Vec::new();
+    let mut parents = vec![pid];
+    while let Some(parent) = parents.pop() {
+        for child in table.iter().filter(|entry| entry.ppid == parent) {
+            let child = child.pid;
+            if child != pid && !descendants.contains(&child) {
+                descendants.push(child);
+                parents.push(child);
+            }
+        }
+    }
+    descendants.sort_unstable();
+    Ok(descendants)
+}
+
+/// Number of children of the calling process that exited but haven't been
+/// waited for
+///
+/// Example:
+///
+///```
+///use fork::{count_zombie_children, fork, waitpid, Fork};
+///
+///if let Ok(Fork::Parent(child)) = fork() {
+///    waitpid(child).expect("failed to wait");
+///    assert_eq!(count_zombie_children(), Ok(0));
+///} else {
+///    std::process::exit(0);
+///}
+///```
+///
+/// # Errors
+/// returns `-1` if the process table can't be read or the platform isn't
+/// supported
+pub fn count_zombie_children() -> Result<usize, i32> {
+    let pid = getpid();
+    Ok(process_table()?
+        .iter()
+        .filter(|entry| entry.ppid == pid && entry.zombie)
+        .count())
+}
+
+/// Wait until process `pid` exits or `timeout` passes, `true` if it exited
+///
+/// Unlike [`waitpid`] this works for any process, not only children, so a
+/// process started by someone else can be monitored, e.g. when taking over a
+/// service from another init system. Exited children that are not reaped yet
+/// count as exited. Uses a pidfd on Linux and `kqueue` with `EVFILT_PROC` on
+/// FreeBSD and macOS, other systems poll.
+///
+/// Example:
+///
+///```
+///use fork::wait_for_exit;
+///use std::process::Command;
+///use std::time::Duration;
+///
+///let mut child = Command::new("sleep").arg("0.1").spawn().expect("failed to spawn");
+///let pid = child.id() as i32;
+///assert_eq!(wait_for_exit(pid, Some(Duration::from_secs(5))), Ok(true));
+///child.wait().expect("failed to reap");
+///```
+///
+/// # Errors
+/// returns `-1` if the process can't be watched
+pub fn wait_for_exit(pid: libc::pid_t, timeout: Option<Duration>) -> Result<bool, i32> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        if fd >= 0 {
+            let pidfd = unsafe { File::from_raw_fd(fd as libc::c_int) };
+            return Ok(readable_before(pidfd.as_raw_fd(), deadline));
+        }
+        match io::Error::last_os_error().raw_os_error() {
+            Some(libc::ESRCH) => return Ok(true),
+            // kernels before 5.3
+            Some(libc::ENOSYS) => {}
+            _ => return Err(-1),
+        }
+    }
+    #[cfg(any(target_os = "freebsd", target_os = "macos"))]
+    {
+        let kq = unsafe { libc::kqueue() };
+        if kq == -1 {
+            return Err(-1);
+        }
+        let kq = unsafe { File::from_raw_fd(kq) };
+        let mut event = unsafe { std::mem::zeroed::<libc::kevent>() };
+        event.ident = pid as libc::uintptr_t;
+        event.filter = libc::EVFILT_PROC;
+        event.flags = libc::EV_ADD | libc::EV_ONESHOT;
+        event.fflags = libc::NOTE_EXIT;
+        let registered = unsafe {
+            libc::kevent(
+                kq.as_raw_fd(),
+                &event,
+                1,
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null(),
+            )
+        };
+        if registered == -1 {
+            return match io::Error::last_os_error().raw_os_error() {
+                Some(libc::ESRCH) => Ok(true),
+                _ => Err(-1),
+            };
+        }
+        if exited(pid) {
+            return Ok(true);
+        }
+        return Ok(readable_before(kq.as_raw_fd(), deadline));
+    }
+    #[allow(unreachable_code)]
+    loop {
+        if exited(pid) {
+            return Ok(true);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok(false);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Whether `pid` is gone or a zombie
+fn exited(pid: libc::pid_t) -> bool {
+    if unsafe { libc::kill(pid, 0) } == -1
+        && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+    {
+        return true;
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return process_entry(pid).is_some_and(|entry| entry.zombie);
+    #[allow(unreachable_code)]
+    process_table().is_ok_and(|table| table.iter().any(|entry| entry.pid == pid && entry.zombie))
+}
+
+/// The entry of `pid` from `/proc/<pid>/stat`, `None` if it is gone
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn process_entry(pid: libc::pid_t) -> Option<ProcessEntry> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    let end = stat.rfind(')')?;
+    let mut fields = stat[end + 1..].split_whitespace();
+    let state = fields.next();
+    let ppid = fields.next()?.parse().ok()?;
+    Some(ProcessEntry {
+        pid,
+        ppid,
+        zombie: state == Some("Z"),
+    })
+}
+
+/// A process in the table returned by [`process_table`]
+struct ProcessEntry {
+    pid: libc::pid_t,
+    ppid: libc::pid_t,
+    zombie: bool,
+}
+
+/// `SZOMB` from `<sys/proc.h>`
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+const SZOMB: u32 = 5;
+
+/// Every process on the system
+fn process_table() -> Result<Vec<ProcessEntry>, i32> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let entries = std::fs::read_dir("/proc").map_err(|_| -1)?;
+        // processes may have exited since the directory was read
+        Ok(entries
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+            .filter_map(process_entry)
+            .collect())
+    }
+    #[cfg(target_os = "freebsd")]
+    {
+        let mut mib = [libc::CTL_KERN, libc::KERN_PROC, libc::KERN_PROC_PROC, 0];
+        let mut size = 0;
+        let query = |buf: *mut libc::c_void, size: &mut usize| unsafe {
+            libc::sysctl(mib.as_mut_ptr(), 3, buf, size, std::ptr::null(), 0)
+        };
+        if query(std::ptr::null_mut(), &mut size) == -1 {
+            return Err(-1);
+        }
+        // room for processes started in between
+        size += size / 8;
+        let count = size / std::mem::size_of::<libc::kinfo_proc>();
+        let mut procs: Vec<libc::kinfo_proc> = Vec::with_capacity(count);
+        size = count * std::mem::size_of::<libc::kinfo_proc>();
+        if query(procs.as_mut_ptr().cast(), &mut size) == -1 {
+            return Err(-1);
+        }
+        unsafe { procs.set_len(size / std::mem::size_of::<libc::kinfo_proc>()) };
+        Ok(procs
+            .iter()
+            .map(|p| ProcessEntry {
+                pid: p.ki_pid,
+                ppid: p.ki_ppid,
+                zombie: p.ki_stat as u32 == SZOMB,
+            })
+            .collect())
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let count = unsafe { libc::proc_listallpids(std::ptr::null_mut(), 0) };
+        if count <= 0 {
+            return Err(-1);
+        }
+        // room for processes started in between
+        let mut pids: Vec<libc::pid_t> = vec![0; count.unsigned_abs() as usize + 64];
+        let bytes = libc::c_int::try_from(pids.len() * std::mem::size_of::<libc::pid_t>())
+            .map_err(|_| -1)?;
+        let count = unsafe { libc::proc_listallpids(pids.as_mut_ptr().cast(), bytes) };
+        if count <= 0 {
+            return Err(-1);
+        }
+        pids.truncate(count.unsigned_abs() as usize);
+        let size = std::mem::size_of::<libc::proc_bsdinfo>() as libc::c_int;
+        Ok(pids
+            .into_iter()
+            .filter_map(|pid| {
+                let mut info = unsafe { std::mem::zeroed::<libc::proc_bsdinfo>() };
+                let res = unsafe {
+                    libc::proc_pidinfo(
+                        pid,
+                        libc::PROC_PIDTBSDINFO,
+                        0,
+                        (&mut info as *mut libc::proc_bsdinfo).cast(),
+                        size,
+                    )
+                };
+                (res == size).then(|| ProcessEntry {
+                    pid,
+                    ppid: info.pbi_ppid as libc::pid_t,
+                    zombie: info.pbi_status == SZOMB,
+                })
+            })
+            .collect())
+    }
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos"
+    )))]
+    {
+        Err(-1)
+    }
+}
+
+/// Microseconds since the epoch
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+fn timeval_micros(tv: libc::timeval) -> u64 {
+    u64::try_from(tv.tv_sec).unwrap_or(0) * 1_000_000 + u64::try_from(tv.tv_usec).unwrap_or(0)
+}
+
+/// The daemon function is for programs wishing to detach themselves from the
+/// controlling terminal and run in the background as system daemons.
+///
+/// * `nochdir = false`, changes the current working directory to the root (`/`).
+/// * `noclose = false`, will close standard input, standard output, and standard error
+///
+/// Call sites like `daemon(false, true)` are hard to read, [`daemon_with`] takes
+/// the same settings as named [`DaemonOptions`].
+///
+/// # Errors
+/// If an error occurs, returns -1
+#[deprecated(note = "use daemon_with(DaemonOptions) or the Daemon builder")]
+pub fn daemon(nochdir: bool, noclose: bool) -> Result<Fork, i32> {
+    let mut options = DaemonOptions::empty();
+    if !nochdir {
+        options |= DaemonOptions::CHDIR_ROOT;
+    }
+    if !noclose {
+        options |= DaemonOptions::CLOSE_STDIO;
+    }
+    daemon_with(options)
+}
+
+/// Options for [`daemon_with`], combined with `|`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct DaemonOptions(u8);
+
+impl DaemonOptions {
+    /// Change the working directory to `/`
+    pub const CHDIR_ROOT: Self = Self(1);
+    /// Connect stdin, stdout and stderr to `/dev/null`
+    pub const REDIRECT_STDIO: Self = Self(1 << 1);
+    /// Close stdin, stdout and stderr, ignored with `REDIRECT_STDIO`
+    pub const CLOSE_STDIO: Self = Self(1 << 2);
+
+    /// No options: keep the working directory and stdio
+    #[must_use]
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Whether all of `other` is set
+    #[must_use]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for DaemonOptions {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for DaemonOptions {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// Detach from the controlling terminal and run in the background as a system
+/// daemon
+///
+/// * `DaemonOptions::CHDIR_ROOT`, changes the current working directory to the root (`/`).
+/// * `DaemonOptions::REDIRECT_STDIO`, connects standard input, standard output,
+///   and standard error to `/dev/null`.
+/// * `DaemonOptions::CLOSE_STDIO`, closes them instead.
+///
+/// Daemons that talk to other processes over pipes or sockets should call
+/// [`ignore_sigpipe`] in the daemon branch, otherwise a peer going away kills
+/// them silently.
+///
+/// # Errors
+/// If an error occurs, returns -1
+///
+/// Example:
+///
+///```
+///// The parent forks the child
+///// The parent exits
+///// The child calls setsid() to start a new session with no controlling terminals
+///// The child forks a grandchild
+///// The child exits
+///// The grandchild is now the daemon
+///use fork::{daemon_with, DaemonOptions, Fork};
+///use std::process::Command;
+///
+///if let Ok(Fork::Child) = daemon_with(DaemonOptions::CHDIR_ROOT | DaemonOptions::REDIRECT_STDIO) {
+///    Command::new("sleep")
+///        .arg("3")
+///        .output()
+///        .expect("failed to execute process");
+///}
+///```
+pub fn daemon_with(options: DaemonOptions) -> Result<Fork, i32> {
+    let daemon = Daemon::new()
+        .nochdir(!options.contains(DaemonOptions::CHDIR_ROOT))
+        .noclose(
+            !options.contains(DaemonOptions::REDIRECT_STDIO)
+                && !options.contains(DaemonOptions::CLOSE_STDIO),
+        )
+        .redirect_stdio(options.contains(DaemonOptions::REDIRECT_STDIO));
+    match daemon.start()? {
+        Daemonized::Parent(pid) => Ok(Fork::Parent(pid)),
+        Daemonized::Daemon(_) => Ok(Fork::Child),
+    }
+}
+
+/// Run `f` as a daemon and exit with the status it returns, never returns
+///
+/// Performs the [`daemon_with`] sequence: the launching process and the
+/// intermediate session leader exit with status 0, or [`exitcode::OSERR`] if
+/// daemonizing fails, and only the daemon runs `f`. Unlike matching on
+/// [`Fork::Child`] the body can't accidentally run in the parent as well.
+///
+/// Example:
+///
+///```no_run
+///use fork::{daemonize_and_run, DaemonOptions};
+///
+///daemonize_and_run(DaemonOptions::CHDIR_ROOT | DaemonOptions::REDIRECT_STDIO, || {
+///    // serve until told to stop
+///    0
+///});
+///```
+pub fn daemonize_and_run<F: FnOnce() -> i32>(options: DaemonOptions, f: F) -> ! {
+    match daemon_with(options) {
+        Ok(Fork::Child) => exit(f()),
+        Ok(Fork::Parent(_)) => exit(exitcode::OK),
+        Err(_) => exit(exitcode::OSERR),
+    }
+}
+
+/// State of the standard streams of the daemon
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StdioState {
+    /// Left as inherited from the launching process (`noclose`)
+    Inherited,
+    /// stdin, stdout and stderr were closed
+    Closed,
+    /// stdin reads from `/dev/null`, stdout and stderr go to files or `/dev/null`,
+    /// or to the stand-ins of [`Daemon::null_policy`]
+    Redirected,
+}
+
+/// The state a daemon ended up in, returned by [`Daemon::start`] so it can be
+/// logged without querying each attribute again
+#[derive(Debug)]
+pub struct DaemonInfo {
+    /// Process ID of the daemon
+    pub pid: libc::pid_t,
+    /// Session ID, the pid of the session leader
+    pub sid: libc::pid_t,
+    /// Process group ID
+    pub pgid: libc::pid_t,
+    /// Working directory, `None` if it can't be determined
+    pub cwd: Option<PathBuf>,
+    /// State of stdin, stdout and stderr
+    pub stdio: StdioState,
+    /// Path of the pid file written by the daemon
+    pub pidfile: Option<PathBuf>,
+    /// Descriptors returned by [`Daemon::bind_before_drop`], owned by the daemon
+    pub fds: Vec<libc::c_int>,
+    /// Connection to the launcher with [`Daemon::launcher_output`]
+    pub launcher: Option<Launcher>,
+}
+
+/// Connection from a daemon started with [`Daemon::launcher_output`] to the
+/// process that launched it
+///
+/// Until [`Launcher::ready`] is called the daemon's stdout and stderr are a
+/// pipe that the launching process copies to its own stderr.
+#[derive(Debug)]
+pub struct Launcher {
+    /// the final stdout and stderr, `None` if they were closed
+    stdout: Option<OwnedFd>,
+    stderr: Option<OwnedFd>,
+    status: OwnedFd,
+}
+
+impl Launcher {
+    /// Signal that startup is complete: stdout and stderr switch to their final
+    /// target and the launcher exits with status 0
+    ///
+    /// # Errors
+    /// returns `-1` if the descriptors can't be switched or the launcher can't
+    /// be notified
+    pub fn ready(self) -> Result<(), i32> {
+        for (fd, target) in [(1, self.stdout), (2, self.stderr)] {
+            match target {
+                Some(target) => dup_onto(target.as_raw_fd(), fd)?,
+                None => unsafe {
+                    libc::close(fd);
+                },
+            }
+        }
+        match write_ignoring_epipe(self.status.as_raw_fd(), b"R") {
+            Ok(true) => Ok(()),
+            _ => Err(-1),
+        }
+    }
+}
+
+/// Copy the daemon's early output to stderr until it is ready or gone, the
+/// exit status of the launching process
+fn relay_launcher_output(
+    output: libc::c_int,
+    status: libc::c_int,
+    deadline: Option<Instant>,
+) -> i32 {
+    let mut buf = [0u8; 4096];
+    loop {
+        if !readable_before(output, deadline) {
+            return STARTUP_TIMED_OUT;
+        }
+        match unsafe { libc::read(output, buf.as_mut_ptr().cast(), buf.len()) } {
+            0 => break,
+            -1 if io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) => {}
+            -1 => break,
+            n => {
+                let _ = write_ignoring_epipe(2, &buf[..n.unsigned_abs()]);
+            }
+        }
+    }
+    if !readable_before(status, deadline) {
+        return STARTUP_TIMED_OUT;
+    }
+    let mut ready = [0u8];
+    match unsafe { libc::read(status, ready.as_mut_ptr().cast(), 1) } {
+        1 => exitcode::OK,
+        _ => exitcode::UNAVAILABLE,
+    }
+}
+
+/// Wait until `fd` is readable or at EOF, `false` if `deadline` passes first
+fn readable_before(fd: libc::c_int, deadline: Option<Instant>) -> bool {
+    loop {
+        let timeout = deadline.map_or(-1, |deadline| {
+            let left = deadline.saturating_duration_since(Instant::now());
+            libc::c_int::try_from(left.as_millis()).unwrap_or(libc::c_int::MAX)
+        });
+        let mut poll = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut poll, 1, timeout) } {
+            -1 if io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) => {}
+            0 => return false,
+            _ => return true,
+        }
+    }
+}
+
+/// Environment variable holding the path of the notification socket
+pub const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+
+/// Send `state`, e.g. `READY=1` or `STATUS=loading`, to the socket in
+/// [`NOTIFY_SOCKET_ENV`] as in systemd's `sd_notify`, returning `false` if the
+/// variable isn't set
+///
+/// A name starting with `@` is in the abstract namespace on Linux.
+///
+/// # Errors
+/// returns `-1` if the message can't be sent
+pub fn notify(state: &str) -> Result<bool, i32> {
+    let Some(path) = env::var_os(NOTIFY_SOCKET_ENV) else {
+        return Ok(false);
+    };
+    let socket = std::os::unix::net::UnixDatagram::unbound().map_err(|_| -1)?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "android")]
+        use std::os::android::net::SocketAddrExt;
+        #[cfg(target_os = "linux")]
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name).map_err(|_| -1)?;
+        return socket
+            .send_to_addr(state.as_bytes(), &addr)
+            .map(|_| true)
+            .map_err(|_| -1);
+    }
+    socket
+        .send_to(state.as_bytes(), path)
+        .map(|_| true)
+        .map_err(|_| -1)
+}
+
+/// Socket a supervisor passes to its child as [`NOTIFY_SOCKET_ENV`] to learn
+/// when the child is ready, as systemd does for `Type=notify` services
+///
+/// Knowing whether a child crashed before or after reporting `READY=1` lets
+/// restart policies treat startup failures differently. The socket file is
+/// removed on drop.
+///
+/// Example:
+///
+///```
+///use fork::{notify, NotifySocket};
+///use std::process::Command;
+///use std::time::Duration;
+///
+///let path = std::env::temp_dir().join(format!("notify-{}.sock", std::process::id()));
+///let socket = NotifySocket::bind(&path).expect("failed to bind");
+///let mut cmd = Command::new("sh");
+///cmd.arg("-c").arg("exit 0");
+///socket.apply(&mut cmd);
+///cmd.status().expect("failed to run");
+///// `sh` doesn't notify, a service would call notify("READY=1")
+///assert_eq!(socket.recv(Duration::from_millis(10)), Ok(None));
+///```
+#[derive(Debug)]
+pub struct NotifySocket {
+    socket: std::os::unix::net::UnixDatagram,
+    path: PathBuf,
+}
+
+impl NotifySocket {
+    /// Bind a datagram socket at `path`, replacing a stale socket file
+    ///
+    /// # Errors
+    /// returns `-1` if the socket can't be bound
+    pub fn bind<P: Into<PathBuf>>(path: P) -> Result<Self, i32> {
+        let path = path.into();
+        let _ = std::fs::remove_file(&path);
+        let socket = std::os::unix::net::UnixDatagram::bind(&path).map_err(|_| -1)?;
+        Ok(Self { socket, path })
+    }
+
+    /// Path of the socket
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Set [`NOTIFY_SOCKET_ENV`] in the environment of `cmd`
+    pub fn apply(&self, cmd: &mut Command) {
+        cmd.env(NOTIFY_SOCKET_ENV, &self.path);
+    }
+
+    /// Wait up to `timeout` for a notification, `None` if none arrived
+    ///
+    /// # Errors
+    /// returns `-1` if receiving fails
+    pub fn recv(&self, timeout: Duration) -> Result<Option<Notification>, i32> {
+        let deadline = Instant::now() + timeout;
+        if !readable_before(self.socket.as_raw_fd(), Some(deadline)) {
+            return Ok(None);
+        }
+        let mut buf = [0u8; 4096];
+        let len = self.socket.recv(&mut buf).map_err(|_| -1)?;
+        let fields = String::from_utf8_lossy(&buf[..len])
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Ok(Some(Notification { fields }))
+    }
+}
+
+impl Drop for NotifySocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A message received by [`NotifySocket::recv`], `KEY=VALUE` lines
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Notification {
+    /// The fields in the order they were sent
+    pub fields: Vec<(String, String)>,
+}
+
+impl Notification {
+    /// Value of the last field named `key`
+    #[must_use]
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .rev()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the sender reported `READY=1`
+    #[must_use]
+    pub fn is_ready(&self) -> bool {
+        self.get("READY") == Some("1")
+    }
+}
+
+/// Outcome of [`Daemon::start`]
+#[derive(Debug)]
+pub enum Daemonized {
+    /// The session leader that forked the daemon, holding the daemon's pid,
+    /// the [`Stage::Intermediate`] of the sequence
+    Parent(libc::pid_t),
+    /// The daemon itself
+    Daemon(DaemonInfo),
+}
+
+/// Machine-readable status of a daemon, written next to the pid file for
+/// external tooling
+///
+/// The file is a JSON object with the pid, the start time as seconds since the
+/// epoch, the version and the socket paths, e.g.
+/// `{"pid":42,"started":1700000000,"version":"1.2.0","sockets":["/run/app.sock"]}`.
+/// It is replaced atomically, readers never see a partial file.
+///
+/// Example:
+///
+///```no_run
+///use fork::{Daemon, StatusFile};
+///
+///let status = StatusFile::new("/run/myapp.json")
+///    .version(env!("CARGO_PKG_VERSION"))
+///    .socket("/run/myapp.sock");
+///let daemon = Daemon::new().status_file(status.clone()).start();
+///// on SIGHUP, after reloading
+///status.write().expect("failed to update status");
+///```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatusFile {
+    path: PathBuf,
+    version: Option<String>,
+    sockets: Vec<PathBuf>,
+    started: u64,
+}
+
+impl StatusFile {
+    /// Status file at `path`, the start time is the time of this call
+    #[must_use]
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            version: None,
+            sockets: Vec::new(),
+            started: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+        }
+    }
+
+    /// Version string of the application
+    #[must_use]
+    pub fn version(mut self, version: &str) -> Self {
+        self.version = Some(version.to_string());
+        self
+    }
+
+    /// Path of a socket the daemon listens on, can be repeated
+    #[must_use]
+    pub fn socket<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.sockets.push(path.into());
+        self
+    }
+
+    /// Path of the status file
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the status of the calling process, on start and after a reload
+    ///
+    /// # Errors
+    /// returns `-1` if the file can't be written or renamed into place
+    pub fn write(&self) -> Result<(), i32> {
+        self.write_for(getpid())
+    }
+
+    /// Write the status of process `pid`
+    ///
+    /// # Errors
+    /// returns `-1` if the file can't be written or renamed into place
+    pub fn write_for(&self, pid: libc::pid_t) -> Result<(), i32> {
+        let json = format!("{STATUS_PREFIX}{pid}{}", self.json_after_pid());
+        atomic_write(&self.path, json.as_bytes())
+    }
+
+    /// The JSON object after the pid, written by the daemon next to it
+    fn json_after_pid(&self) -> String {
+        let mut json = format!(",\"started\":{}", self.started);
+        json.push_str(",\"version\":");
+        match &self.version {
+            Some(version) => push_json_string(&mut json, version),
+            None => json.push_str("null"),
+        }
+        json.push_str(",\"sockets\":[");
+        for (i, socket) in self.sockets.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            push_json_string(&mut json, &socket.to_string_lossy());
+        }
+        json.push_str("]}\n");
+        json
+    }
+
+    /// Create the file under a temporary name, for the daemon to write its
+    /// pid to without allocating
+    fn stage(&self) -> Result<StagedFile, i32> {
+        let after = self.json_after_pid().into_bytes();
+        StagedFile::create(&self.path, STATUS_PREFIX.as_bytes().to_vec(), after)
+    }
+
+    /// Remove the status file, e.g. on shutdown
+    ///
+    /// # Errors
+    /// returns `-1` if the file can't be removed
+    pub fn remove(&self) -> Result<(), i32> {
+        std::fs::remove_file(&self.path).map_err(|_| -1)
+    }
+
+    /// The same status file with a path that doesn't depend on the working
+    /// directory
+    fn absolute(mut self) -> Result<Self, i32> {
+        if self.path.is_relative() {
+            self.path = env::current_dir().map_err(|_| -1)?.join(&self.path);
+        }
+        Ok(self)
+    }
+}
+
+/// Start of a status file, the pid follows
+const STATUS_PREFIX: &str = "{\"pid\":";
+
+/// Append `value` to `json` as a quoted JSON string
+fn push_json_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+/// Replace `path` with `contents` so that readers see either the old or the
+/// new file, never a partial one, even if the system crashes
+///
+/// `contents` is written to a temporary file next to `path`, flushed to disk
+/// with `fsync`, and renamed over `path`, then the directory is synced so the
+/// rename is durable too. Used for the pid and status files of [`Daemon`].
+///
+/// Example:
+///
+///```
+///use fork::atomic_write;
+///
+///let path = std::env::temp_dir().join("fork-atomic-write.state");
+///atomic_write(&path, b"generation=2\n").expect("failed to write");
+///assert_eq!(std::fs::read(&path).ok(), Some(b"generation=2\n".to_vec()));
+///# std::fs::remove_file(&path).ok();
+///```
+///
+/// # Errors
+/// returns `-1` if the temporary file can't be written or renamed into place
+pub fn atomic_write<P: AsRef<Path>>(path: P, contents: &[u8]) -> Result<(), i32> {
+    let path = path.as_ref();
+    let (tmp, mut file) = create_temporary(path)?;
+    let written = io::Write::write_all(&mut file, contents)
+        .and_then(|()| file.sync_all())
+        .and_then(|()| std::fs::rename(&tmp, path));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(-1);
+    }
+    sync_parent(path)
+}
+
+/// Names tried by [`create_temporary`] before giving up
+const TEMPORARY_ATTEMPTS: usize = 64;
+
+/// Create a temporary file next to `path` used to replace it atomically
+///
+/// The name has a random suffix and the file is created exclusively without
+/// following symlinks, so a link planted in a shared directory like `/tmp`
+/// can't redirect the write to another file. Taken names are retried.
+fn create_temporary(path: &Path) -> Result<(PathBuf, File), i32> {
+    for _ in 0..TEMPORARY_ATTEMPTS {
+        let tmp = temporary_path(path);
+        let created = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .custom_flags(libc::O_CLOEXEC | libc::O_NOFOLLOW)
+            .open(&tmp);
+        match created {
+            Ok(file) => return Ok((tmp, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(_) => return Err(-1),
+        }
+    }
+    Err(-1)
+}
+
+/// `path` with a random `.<suffix>.tmp` appended
+fn temporary_path(path: &Path) -> PathBuf {
+    // seeded from the system's random source, with new keys on every call
+    let suffix = BuildHasher::hash_one(&hash_map::RandomState::new(), getpid());
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{suffix:016x}.tmp"));
+    PathBuf::from(tmp)
+}
+
+/// Open the directory containing `path`, to sync it after a rename
+fn parent_dir(path: &Path) -> Result<File, i32> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_CLOEXEC | libc::O_DIRECTORY)
+        .open(dir)
+        .map_err(|_| -1)
+}
+
+/// `fsync` the directory containing `path`
+fn sync_parent(path: &Path) -> Result<(), i32> {
+    parent_dir(path)?.sync_all().map_err(|_| -1)
+}
+
+/// Environment variable marking a command started by [`Daemon::exec`],
+/// inherited by its children
+///
+/// [`Daemon::start`] leaves the environment alone and marks the daemon in
+/// memory instead, commands it runs itself don't get the variable.
+pub const DAEMON_ENV: &str = "FORK_DAEMON";
+
+/// Set in the daemon by [`PreparedDaemon::start`] once the sequence succeeded
+static DAEMONIZED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the calling process already runs as a daemon: it was started by
+/// [`Daemon::start`] or [`DAEMON_ENV`] is set, and it has no controlling
+/// terminal
+#[must_use]
+pub fn is_daemonized() -> bool {
+    (DAEMONIZED.load(Ordering::Relaxed) || env::var_os(DAEMON_ENV).is_some())
+        && !has_controlling_tty()
+}
+
+/// Whether `/dev/tty` can be opened, which only fails without a controlling
+/// terminal
+fn has_controlling_tty() -> bool {
+    let flags = libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC;
+    match unsafe { libc::open(c"/dev/tty".as_ptr(), flags) } {
+        -1 => false,
+        fd => {
+            unsafe { libc::close(fd) };
+            true
+        }
+    }
+}
+
+/// What [`Daemon::start`] and [`Daemon::exec`] do when the process
+/// [`is_daemonized`] already, see [`Daemon::already_daemon`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AlreadyDaemon {
+    /// Fork and detach again
+    #[default]
+    Daemonize,
+    /// Stay in the calling process as with [`Daemon::foreground`]
+    Continue,
+    /// Fail with `-1`
+    Fail,
+}
+
+/// Builder for the [`daemon_with`] sequence
+///
+/// Defaults to `daemon_with(DaemonOptions::CHDIR_ROOT | DaemonOptions::CLOSE_STDIO)`:
+/// change the working directory to `/` and close standard input, standard
+/// output, and standard error.
+///
+/// Example:
+///
+///```no_run
+///use fork::{Daemon, Daemonized};
+///
+///match Daemon::new().pidfile("/tmp/myapp.pid").start() {
+///    Ok(Daemonized::Daemon(info)) => {
+///        // stdio is closed, log it somewhere else
+///        let _ = std::fs::write("/tmp/myapp.log", format!("{info:?}\n"));
+///    }
+///    Ok(Daemonized::Parent(_)) => {}
+///    Err(_) => eprintln!("failed to daemonize"),
+///}
+///```
+#[derive(Clone, Debug)]
+pub struct Daemon {
+    nochdir: bool,
+    noclose: bool,
+    double_fork: bool,
+    pidfile: Option<PathBuf>,
+    pidfile_mode: Option<libc::mode_t>,
+    pidfile_owner: bool,
+    working_dir: Option<PathBuf>,
+    check_working_dir: bool,
+    umask: Option<libc::mode_t>,
+    nice: Option<libc::c_int>,
+    rlimits: Vec<(Resource, libc::rlim_t, libc::rlim_t)>,
+    user: Option<Account<libc::uid_t>>,
+    group: Option<Account<libc::gid_t>>,
+    supplementary_groups: Option<Vec<Account<libc::gid_t>>>,
+    resolver: Option<ResolverHook>,
+    resolved_user: Option<ResolvedUser>,
+    redirect_stdio: bool,
+    null_policy: NullPolicy,
+    stdout: Option<PathBuf>,
+    stderr: Option<PathBuf>,
+    launcher_output: bool,
+    foreground: bool,
+    already_daemon: AlreadyDaemon,
+    status_file: Option<StatusFile>,
+    startup_timeout: Option<Duration>,
+    exec_env: Option<ExecEnv>,
+    bind: Option<BindHook>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    dumpable: Option<bool>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    private_tmp: bool,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    protect_system: bool,
+    #[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+    login_class: bool,
+    #[cfg(all(feature = "openbsd", target_os = "openbsd"))]
+    pledge: Option<(String, Option<String>)>,
+    #[cfg(all(feature = "openbsd", target_os = "openbsd"))]
+    unveil: Vec<(PathBuf, String)>,
+}
+
+impl Default for Daemon {
+    fn default() -> Self {
+        Self {
+            nochdir: false,
+            noclose: false,
+            double_fork: true,
+            pidfile: None,
+            pidfile_mode: None,
+            pidfile_owner: false,
+            working_dir: None,
+            check_working_dir: false,
+            umask: None,
+            nice: None,
+            rlimits: Vec::new(),
+            user: None,
+            group: None,
+            supplementary_groups: None,
+            resolver: None,
+            resolved_user: None,
+            redirect_stdio: false,
+            null_policy: NullPolicy::DevNull,
+            stdout: None,
+            stderr: None,
+            launcher_output: false,
+            foreground: false,
+            already_daemon: AlreadyDaemon::Daemonize,
+            status_file: None,
+            startup_timeout: None,
+            exec_env: None,
+            bind: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            dumpable: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            private_tmp: false,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            protect_system: false,
+            #[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+            login_class: false,
+            #[cfg(all(feature = "openbsd", target_os = "openbsd"))]
+            pledge: None,
+            #[cfg(all(feature = "openbsd", target_os = "openbsd"))]
+            unveil: Vec::new(),
+        }
+    }
+}
+
+/// User or group of the daemon, by name or by id
+#[derive(Clone, Debug)]
+enum Account<T> {
+    Name(String),
+    Id(T),
+}
+
+/// Resolver of [`Daemon::resolver`]
+#[derive(Clone)]
+struct ResolverHook(Arc<dyn Resolver>);
+
+impl std::fmt::Debug for ResolverHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ResolverHook")
+    }
+}
+
+/// Closure of [`Daemon::bind_before_drop`]
+#[derive(Clone)]
+struct BindHook(Arc<dyn Fn() -> io::Result<Vec<OwnedFd>> + Send + Sync>);
+
+impl std::fmt::Debug for BindHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BindHook")
+    }
+}
+
+/// User and group the daemon switches to, resolved before forking
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Credentials {
+    /// uid and supplementary groups of the user
+    user: Option<(libc::uid_t, Vec<libc::gid_t>)>,
+    gid: libc::gid_t,
+}
+
+impl Credentials {
+    /// Switch to the supplementary groups, the group and the user, then
+    /// [`Credentials::verify`] the switch
+    fn apply(&self) -> Result<(), i32> {
+        if let Some((_, groups)) = &self.user {
+            if unsafe { libc::setgroups(groups.len() as _, groups.as_ptr()) } == -1 {
+                return Err(-1);
+            }
+        }
+        if unsafe { libc::setgid(self.gid) } == -1 {
+            return Err(-1);
+        }
+        if let Some((uid, _)) = self.user {
+            if unsafe { libc::setuid(uid) } == -1 {
+                return Err(-1);
+            }
+        }
+        self.verify()
+    }
+
+    /// Fail unless the real and effective ids are the configured ones, the
+    /// supplementary groups were replaced and root can't be regained
+    ///
+    /// Only makes system calls, the groups are read into a buffer on the stack
+    /// and only counted if there are more than fit.
+    fn verify(&self) -> Result<(), i32> {
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        if gid != self.gid || unsafe { libc::getegid() } != gid {
+            return Err(-1);
+        }
+        let Some((expected, groups)) = &self.user else {
+            return Ok(());
+        };
+        if uid != *expected || unsafe { libc::geteuid() } != uid {
+            return Err(-1);
+        }
+        // macOS resolves group membership dynamically, getgroups(2) doesn't
+        // report what setgroups(2) set
+        if cfg!(not(target_vendor = "apple")) {
+            let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+            if usize::try_from(count).map_err(|_| -1)? != groups.len() {
+                return Err(-1);
+            }
+            let mut current = [0; 256];
+            if groups.len() <= current.len() {
+                let count = unsafe { libc::getgroups(current.len() as _, current.as_mut_ptr()) };
+                let count = usize::try_from(count).map_err(|_| -1)?;
+                if current[..count].iter().any(|gid| !groups.contains(gid)) {
+                    return Err(-1);
+                }
+            }
+        }
+        if uid != 0 && unsafe { libc::setuid(0) } != -1 {
+            return Err(-1);
+        }
+        Ok(())
+    }
+}
+
+impl Daemon {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the current working directory instead of changing to `/`
+    #[must_use]
+    pub const fn nochdir(mut self, nochdir: bool) -> Self {
+        self.nochdir = nochdir;
+        self
+    }
+
+    /// Keep stdin, stdout and stderr open
+    #[must_use]
+    pub const fn noclose(mut self, noclose: bool) -> Self {
+        self.noclose = noclose;
+        self
+    }
+
+    /// Fork a second time after `setsid`, enabled by default
+    ///
+    /// The second fork leaves the daemon in a new session without being its
+    /// leader, so opening a terminal can never make it the daemon's controlling
+    /// terminal. With `double_fork(false)` only fork and `setsid` are performed
+    /// and the daemon stays session leader, which is what services that later
+    /// acquire a controlling terminal for a pty need. There is no
+    /// [`Daemonized::Parent`] in that case, the only parent is the calling
+    /// process and it exits.
+    #[must_use]
+    pub const fn double_fork(mut self, double_fork: bool) -> Self {
+        self.double_fork = double_fork;
+        self
+    }
+
+    /// Don't fork or start a session, only apply the other settings to the
+    /// calling process
+    ///
+    /// Service managers like launchd, systemd with `Type=simple` or a container
+    /// runtime expect the process they started to keep running in the
+    /// foreground, a daemon that forks away seems to exit immediately. In this
+    /// mode [`Daemon::start`] changes directory, sets the umask, redirects
+    /// stdio, writes the pid file, sets limits and drops privileges in place
+    /// and returns [`Daemonized::Daemon`]. [`Daemon::launcher_output`] has no
+    /// effect.
+    ///
+    /// Example:
+    ///
+    ///```no_run
+    ///use fork::{launched_by_launchd, Daemon, Daemonized};
+    ///
+    ///let daemon = Daemon::new().foreground(launched_by_launchd());
+    ///if let Ok(Daemonized::Daemon(info)) = daemon.start() {
+    ///    // the same code path whether launchd or a shell started us
+    ///}
+    ///```
+    #[must_use]
+    pub const fn foreground(mut self, foreground: bool) -> Self {
+        self.foreground = foreground;
+        self
+    }
+
+    /// What to do when the calling process [`is_daemonized`] already, by
+    /// default it is daemonized again
+    ///
+    /// Example:
+    ///
+    ///```no_run
+    ///use fork::{AlreadyDaemon, Daemon};
+    ///
+    ///// a library entry point that may run in a daemon or from a shell
+    ///let started = Daemon::new().already_daemon(AlreadyDaemon::Continue).start();
+    ///```
+    #[must_use]
+    pub const fn already_daemon(mut self, policy: AlreadyDaemon) -> Self {
+        self.already_daemon = policy;
+        self
+    }
+
+    /// Apply the resource limits, priority, umask and environment of the login
+    /// class of [`Daemon::user`] from `login.conf`, like native daemons do
+    /// [see setusercontext(3)](https://man.freebsd.org/cgi/man.cgi?setusercontext)
+    ///
+    /// The class is looked up in the system's user database before forking.
+    /// Limits, priority and umask are applied before dropping privileges, the
+    /// settings of the builder override them. The environment is only set with
+    /// [`Daemon::start`].
+    #[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+    #[must_use]
+    pub const fn login_class(mut self, login_class: bool) -> Self {
+        self.login_class = login_class;
+        self
+    }
+
+    /// Restrict the daemon to `promises` once it is set up, and programs it
+    /// executes to `execpromises` [see pledge(2)](https://man.openbsd.org/pledge)
+    ///
+    /// Applied last, after privileges are dropped and [`Daemon::unveil`].
+    ///
+    /// Example:
+    ///
+    ///```no_run
+    ///use fork::Daemon;
+    ///
+    ///let daemon = Daemon::new()
+    ///    .unveil("/var/www", "r")
+    ///    .pledge("stdio rpath inet", None);
+    ///```
+    #[cfg(all(feature = "openbsd", target_os = "openbsd"))]
+    #[must_use]
+    pub fn pledge(mut self, promises: &str, execpromises: Option<&str>) -> Self {
+        self.pledge = Some((promises.to_string(), execpromises.map(str::to_string)));
+        self
+    }
+
+    /// Make `path` visible to the daemon with `permissions` out of `r`, `w`,
+    /// `x` and `c`, hiding the rest of the file system [see unveil(2)](https://man.openbsd.org/unveil)
+    ///
+    /// Can be repeated, the list is locked once the daemon is set up.
+    #[cfg(all(feature = "openbsd", target_os = "openbsd"))]
+    #[must_use]
+    pub fn unveil<P: Into<PathBuf>>(mut self, path: P, permissions: &str) -> Self {
+        self.unveil.push((path.into(), permissions.to_string()));
+        self
+    }
+
+    /// Write the pid of the daemon to `path` once it is running
+    ///
+    /// The file is created under a temporary name next to `path` and renamed
+    /// over it once it holds the pid, like [`atomic_write`].
+    #[must_use]
+    pub fn pidfile<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.pidfile = Some(path.into());
+        self
+    }
+
+    /// Set the permissions of the pid file regardless of the umask, e.g.
+    /// `0o644` for the conventional layout in `/run` that unprivileged tools
+    /// can read
+    #[must_use]
+    pub const fn pidfile_mode(mut self, mode: libc::mode_t) -> Self {
+        self.pidfile_mode = Some(mode);
+        self
+    }
+
+    /// Give the pid file to [`Daemon::user`] and [`Daemon::group`] when it is
+    /// created, while the caller still has the privileges to do so
+    ///
+    /// Lets the daemon remove its own pid file after dropping privileges. By
+    /// default the pid file belongs to the caller.
+    #[must_use]
+    pub const fn pidfile_owner(mut self, owner: bool) -> Self {
+        self.pidfile_owner = owner;
+        self
+    }
+
+    /// Write `status` once the daemon is running, before privileges are dropped
+    ///
+    /// With [`Daemon::exec`] the calling process writes it with the daemon's pid.
+    #[must_use]
+    pub fn status_file(mut self, status: StatusFile) -> Self {
+        self.status_file = Some(status);
+        self
+    }
+
+    /// Change to `dir` instead of `/`, ignored with [`Daemon::nochdir`]
+    #[must_use]
+    pub fn working_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.working_dir = Some(dir.into());
+        self
+    }
+
+    /// Make [`Daemon::prepare`] reject a working directory on `tmpfs` or
+    /// `ramfs`, whose contents are gone after a reboot, or one that
+    /// [`Daemon::user`] can't search once privileges are dropped
+    ///
+    /// Only checked when the daemon changes directory. Every component of the
+    /// path needs search permission for the user, its group or supplementary
+    /// groups. Catches misconfigurations at startup rather than when the daemon
+    /// first touches the directory.
+    #[must_use]
+    pub const fn check_working_dir(mut self, check: bool) -> Self {
+        self.check_working_dir = check;
+        self
+    }
+
+    /// Set the file mode creation mask right after `setsid`, by default the
+    /// inherited mask is kept
+    #[must_use]
+    pub const fn umask(mut self, mode: libc::mode_t) -> Self {
+        self.umask = Some(mode);
+        self
+    }
+
+    /// Set the scheduling priority of the daemon [see setpriority(2)](https://man.freebsd.org/cgi/man.cgi?setpriority)
+    #[must_use]
+    pub const fn nice(mut self, nice: libc::c_int) -> Self {
+        self.nice = Some(nice);
+        self
+    }
+
+    /// Set a resource limit of the daemon, can be called once per resource
+    #[must_use]
+    pub fn rlimit(mut self, resource: Resource, soft: libc::rlim_t, hard: libc::rlim_t) -> Self {
+        self.rlimits.push((resource, soft, hard));
+        self
+    }
+
+    /// Switch to this user, its primary group and supplementary groups once the
+    /// daemon is running
+    ///
+    /// The name is resolved before forking. The pid file is written and the
+    /// resource limits and priority are set before dropping privileges. The
+    /// daemon fails unless the ids and groups read back as configured and
+    /// `setuid(0)` is refused afterwards.
+    #[must_use]
+    pub fn user<S: Into<String>>(mut self, name: S) -> Self {
+        self.user = Some(Account::Name(name.into()));
+        self
+    }
+
+    /// Switch to this group instead of the primary group of [`Daemon::user`]
+    #[must_use]
+    pub fn group<S: Into<String>>(mut self, name: S) -> Self {
+        self.group = Some(Account::Name(name.into()));
+        self
+    }
+
+    /// Switch to the user `uid` without looking it up, for systems without
+    /// passwd entries like scratch containers
+    ///
+    /// There is no primary group to take, [`Daemon::group_id`] or
+    /// [`Daemon::group`] is required. The group is the only supplementary group
+    /// unless [`Daemon::supplementary_groups`] are given.
+    ///
+    /// Example:
+    ///
+    ///```no_run
+    ///use fork::Daemon;
+    ///
+    ///let daemon = Daemon::new().user_id(65534).group_id(65534);
+    ///```
+    #[must_use]
+    pub fn user_id(mut self, uid: libc::uid_t) -> Self {
+        self.user = Some(Account::Id(uid));
+        self
+    }
+
+    /// Switch to the group `gid` without looking it up
+    #[must_use]
+    pub fn group_id(mut self, gid: libc::gid_t) -> Self {
+        self.group = Some(Account::Id(gid));
+        self
+    }
+
+    /// Supplementary groups of the daemon by name, instead of the groups the
+    /// user is a member of, can be repeated and combined with
+    /// [`Daemon::supplementary_group_ids`]
+    ///
+    /// Only applies when switching to a [`Daemon::user`] or
+    /// [`Daemon::user_id`]. The primary group isn't added to the list.
+    ///
+    /// Example:
+    ///
+    ///```no_run
+    ///use fork::Daemon;
+    ///
+    ///let daemon = Daemon::new().user("camera").supplementary_groups(["video"]);
+    ///```
+    #[must_use]
+    pub fn supplementary_groups<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.supplementary_groups
+            .get_or_insert_with(Vec::new)
+            .extend(names.into_iter().map(|name| Account::Name(name.into())));
+        self
+    }
+
+    /// Supplementary groups of the daemon by gid, see
+    /// [`Daemon::supplementary_groups`]
+    ///
+    /// An empty list drops all supplementary groups.
+    #[must_use]
+    pub fn supplementary_group_ids<I: IntoIterator<Item = libc::gid_t>>(mut self, gids: I) -> Self {
+        self.supplementary_groups
+            .get_or_insert_with(Vec::new)
+            .extend(gids.into_iter().map(Account::Id));
+        self
+    }
+
+    /// Switch to a user resolved earlier, instead of [`Daemon::user`],
+    /// [`Daemon::group`] and their lookups
+    #[must_use]
+    pub fn resolved_user(mut self, user: ResolvedUser) -> Self {
+        self.resolved_user = Some(user);
+        self
+    }
+
+    /// Resolve the names of [`Daemon::user`] and [`Daemon::group`] with
+    /// `resolver` instead of the system's user database
+    #[must_use]
+    pub fn resolver<R: Resolver + 'static>(mut self, resolver: R) -> Self {
+        self.resolver = Some(ResolverHook(Arc::new(resolver)));
+        self
+    }
+
+    /// Whether the daemon may dump core and be attached to with `ptrace` by
+    /// its user [see prctl(2)](https://man7.org/linux/man-pages/man2/prctl.2.html)
+    ///
+    /// The kernel clears the flag when a process switches users, so it is set
+    /// after dropping privileges and read back. By default it is left as the
+    /// switch made it.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[must_use]
+    pub const fn dumpable(mut self, dumpable: bool) -> Self {
+        self.dumpable = Some(dumpable);
+        self
+    }
+
+    /// Give the daemon its own empty `/tmp` and `/var/tmp`, like `PrivateTmp`
+    /// of systemd units
+    ///
+    /// The daemon enters a new mount namespace and mounts a `tmpfs` on both
+    /// before dropping privileges, so its temporary files are invisible to
+    /// other processes and gone when it exits. A missing `/var/tmp` is
+    /// skipped. Requires `CAP_SYS_ADMIN`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[must_use]
+    pub const fn private_tmp(mut self, private: bool) -> Self {
+        self.private_tmp = private;
+        self
+    }
+
+    /// Make `/usr` and `/etc` read-only for the daemon, like
+    /// `ProtectSystem=full` of systemd units
+    ///
+    /// The daemon enters a new mount namespace and bind mounts both onto
+    /// themselves read-only before dropping privileges, even root can't
+    /// modify them from there. Mounts below them stay writable if they were.
+    /// Requires `CAP_SYS_ADMIN`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[must_use]
+    pub const fn protect_system(mut self, protect: bool) -> Self {
+        self.protect_system = protect;
+        self
+    }
+
+    /// Create sockets and other descriptors with the caller's privileges,
+    /// before the daemon switches to [`Daemon::user`]
+    ///
+    /// `bind` runs in the calling process before forking, so binding a
+    /// privileged port or opening a root-only file fails while the caller
+    /// still has its terminal. The returned descriptors are kept open in the
+    /// daemon and listed in [`DaemonInfo::fds`], with [`Daemon::exec`] the
+    /// command inherits them under the same numbers.
+    ///
+    /// Example:
+    ///
+    ///```no_run
+    ///use fork::{Daemon, Daemonized};
+    ///use std::net::TcpListener;
+    ///use std::os::unix::io::FromRawFd;
+    ///
+    ///let daemon = Daemon::new()
+    ///    .user("www")
+    ///    .bind_before_drop(|| Ok(vec![TcpListener::bind("0.0.0.0:80")?.into()]));
+    ///if let Ok(Daemonized::Daemon(info)) = daemon.start() {
+    ///    // running as www, still listening on port 80
+    ///    let listener = unsafe { TcpListener::from_raw_fd(info.fds[0]) };
+    ///}
+    ///```
+    #[must_use]
+    pub fn bind_before_drop<F>(mut self, bind: F) -> Self
+    where
+        F: Fn() -> io::Result<Vec<OwnedFd>> + Send + Sync + 'static,
+    {
+        self.bind = Some(BindHook(Arc::new(bind)));
+        self
+    }
+
+    /// Environment of the commands started by [`Daemon::exec`] and
+    /// [`Daemon::run_in_context`], by default they inherit the caller's
+    #[must_use]
+    pub fn exec_env(mut self, env: ExecEnv) -> Self {
+        self.exec_env = Some(env);
+        self
+    }
+
+    /// Connect stdin, stdout and stderr to `/dev/null` instead of closing them,
+    /// ignored with [`Daemon::noclose`]
+    #[must_use]
+    pub const fn redirect_stdio(mut self, redirect: bool) -> Self {
+        self.redirect_stdio = redirect;
+        self
+    }
+
+    /// What replaces `/dev/null` for redirected stdio, see [`NullPolicy`]
+    ///
+    /// The discarding stand-in for stdout and stderr is created in the daemon
+    /// after the last fork, so commands it starts can write to them once the
+    /// launcher is gone.
+    #[must_use]
+    pub const fn null_policy(mut self, policy: NullPolicy) -> Self {
+        self.null_policy = policy;
+        self
+    }
+
+    /// Append stdout to this file instead of closing it, ignored with
+    /// [`Daemon::noclose`]
+    ///
+    /// Once stdout or stderr is redirected the other standard streams are
+    /// connected to `/dev/null` rather than closed. The files are opened before
+    /// forking, relative paths are relative to the launcher's directory.
+    #[must_use]
+    pub fn stdout<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.stdout = Some(path.into());
+        self
+    }
+
+    /// Append stderr to this file instead of closing it, see [`Daemon::stdout`]
+    #[must_use]
+    pub fn stderr<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.stderr = Some(path.into());
+        self
+    }
+
+    /// Send the daemon's stdout and stderr to the launching process until
+    /// [`Launcher::ready`] is called
+    ///
+    /// Instead of exiting right away the calling process copies everything the
+    /// daemon writes to its own stderr, so launch scripts see startup errors on
+    /// their terminal. Once the daemon signals readiness its output switches to
+    /// the final target and the launcher exits with status 0, or
+    /// [`exitcode::UNAVAILABLE`] if the daemon went away without signaling
+    /// readiness. Processes the daemon spawns
+    /// before that inherit the pipe and keep the launcher waiting while they run.
+    ///
+    /// Example:
+    ///
+    ///```no_run
+    ///use fork::{Daemon, Daemonized};
+    ///
+    ///if let Ok(Daemonized::Daemon(info)) = Daemon::new().launcher_output(true).start() {
+    ///    eprintln!("loading configuration"); // shown by the launcher
+    ///    if let Some(launcher) = info.launcher {
+    ///        launcher.ready().expect("failed to notify launcher");
+    ///    }
+    ///    eprintln!("running"); // stderr is closed now
+    ///}
+    ///```
+    #[must_use]
+    pub const fn launcher_output(mut self, launcher_output: bool) -> Self {
+        self.launcher_output = launcher_output;
+        self
+    }
+
+    /// Give up on a daemon that doesn't call [`Launcher::ready`] within
+    /// `timeout` of [`Daemon::start`], only with [`Daemon::launcher_output`]
+    ///
+    /// The launcher kills the process group of the session the daemon runs in
+    /// with `SIGKILL` and exits with [`exitcode::STARTUP_TIMED_OUT`].
+    #[must_use]
+    pub const fn startup_timeout(mut self, timeout: Duration) -> Self {
+        self.startup_timeout = Some(timeout);
+        self
+    }
+
+    /// Run the [`daemon_with`] sequence, the calling process exits
+    ///
+    /// The session leader changes directory, sets the umask and closes or
+    /// redirects stdio.
+    /// The daemon then writes the pid file, sets resource limits and priority
+    /// and finally drops privileges.
+    ///
+    /// Same as [`Daemon::prepare`] followed by [`PreparedDaemon::start`].
+    ///
+    /// # Errors
+    /// returns `-1` if the user or group can't be resolved, if any of the steps
+    /// fails or if the pid file can't be written
+    pub fn start(&self) -> Result<Daemonized, i32> {
+        self.prepare()?.start()
+    }
+
+    /// Perform every lookup of the daemon now, in the calling process, and
+    /// return a builder that needs none
+    ///
+    /// [`Daemon::user`], [`Daemon::group`] and
+    /// [`Daemon::supplementary_groups`] become numeric ids with the resolved
+    /// supplementary groups, and the paths of the pid, status and log files
+    /// and of the working directory become absolute. The crate never looks up
+    /// names between `fork` and `exec`, [`Daemon::prepare`] does it before
+    /// forking, but glibc's NSS modules may start threads, open sockets or
+    /// load libraries. Resolving up front keeps that out of a sandboxed or
+    /// multithreaded program and out of every later [`Daemon::prepare`] and
+    /// [`Daemon::run_in_context`], and reports a missing user at startup.
+    ///
+    /// Example:
+    ///
+    ///```no_run
+    ///use fork::Daemon;
+    ///
+    ///let daemon = Daemon::new()
+    ///    .user("www")
+    ///    .pidfile("myapp.pid")
+    ///    .resolve_all()
+    ///    .expect("invalid daemon configuration");
+    ///// start threads, chroot, ... then daemon.start()
+    ///```
+    ///
+    /// # Errors
+    /// returns `-1` if a user or group can't be resolved or the current
+    /// directory can't be read for a relative path
+    pub fn resolve_all(mut self) -> Result<Self, i32> {
+        if let Some(credentials) = self.credentials()? {
+            if let Some((uid, groups)) = credentials.user {
+                self.user = Some(Account::Id(uid));
+                self.supplementary_groups = Some(groups.into_iter().map(Account::Id).collect());
+            }
+            self.group = Some(Account::Id(credentials.gid));
+        }
+        self.resolved_user = None;
+        self.resolver = None;
+        let absolute = |path: &mut Option<PathBuf>| -> Result<(), i32> {
+            if let Some(relative) = path.as_ref().filter(|path| path.is_relative()) {
+                *path = Some(env::current_dir().map_err(|_| -1)?.join(relative));
+            }
+            Ok(())
+        };
+        absolute(&mut self.pidfile)?;
+        absolute(&mut self.stdout)?;
+        absolute(&mut self.stderr)?;
+        absolute(&mut self.working_dir)?;
+        if let Some(status) = self.status_file.take() {
+            self.status_file = Some(status.absolute()?);
+        }
+        Ok(self)
+    }
+
+    /// Resolve and open everything the daemon needs without forking yet
+    ///
+    /// Users and groups are looked up, `/dev/null`, the log files and the pid
+    /// file are opened and pipes are created here, so the forked processes
+    /// only make system calls that can't run out of memory or block on locks
+    /// held by other threads of a multithreaded parent. Errors like a missing
+    /// user are reported while the caller still has its terminal.
+    ///
+    /// Example:
+    ///
+    ///```no_run
+    ///use fork::{Daemon, Daemonized};
+    ///
+    ///let prepared = match Daemon::new().pidfile("/run/myapp.pid").user("nobody").prepare() {
+    ///    Ok(prepared) => prepared,
+    ///    Err(_) => {
+    ///        eprintln!("invalid daemon configuration");
+    ///        std::process::exit(1);
+    ///    }
+    ///};
+    ///if let Ok(Daemonized::Daemon(_)) = prepared.start() {
+    ///    // running as nobody
+    ///}
+    ///```
+    ///
+    /// # Errors
+    /// returns `-1` if the user or group can't be resolved, a file or pipe
+    /// can't be opened, or the process [`is_daemonized`] with
+    /// [`AlreadyDaemon::Fail`]
+    pub fn prepare(&self) -> Result<PreparedDaemon, i32> {
+        let foreground = match self.already_daemon {
+            AlreadyDaemon::Daemonize => self.foreground,
+            AlreadyDaemon::Continue => self.foreground || is_daemonized(),
+            AlreadyDaemon::Fail if is_daemonized() => return Err(-1),
+            AlreadyDaemon::Fail => self.foreground,
+        };
+        let (chdir, cwd) = if self.nochdir {
+            (None, env::current_dir().ok())
+        } else {
+            let dir = self
+                .working_dir
+                .as_deref()
+                .unwrap_or_else(|| Path::new("/"));
+            let path = CString::new(dir.as_os_str().as_bytes()).map_err(|_| -1)?;
+            (Some(path), std::fs::canonicalize(dir).ok())
+        };
+        let credentials = self.credentials()?;
+        if self.check_working_dir && !self.nochdir {
+            check_working_dir(cwd.as_deref().ok_or(-1)?, credentials.as_ref())?;
+        }
+        let pidfile = match &self.pidfile {
+            Some(path) => Some(self.open_pidfile(path, credentials.as_ref())?),
+            None => None,
+        };
+        #[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+        let login_class = if self.login_class {
+            let user = credentials.as_ref().and_then(|c| c.user.as_ref());
+            Some(LoginClass::new(user.ok_or(-1)?.0)?)
+        } else {
+            None
+        };
+        let fds = match &self.bind {
+            Some(BindHook(bind)) => bind().map_err(|_| -1)?,
+            None => Vec::new(),
+        };
+        let launcher = if self.launcher_output && !foreground {
+            let [output, output_end] = cloexec_pipe()?;
+            let [status, status_end] = cloexec_pipe()?;
+            Some(unsafe {
+                [output, output_end, status, status_end].map(|fd| File::from_raw_fd(fd))
+            })
+        } else {
+            None
+        };
+        Ok(PreparedDaemon {
+            double_fork: self.double_fork,
+            foreground,
+            chdir,
+            umask: self.umask,
+            noclose: self.noclose,
+            stdio: self.open_stdio()?,
+            launcher,
+            pidfile,
+            fd_numbers: fds.iter().map(AsRawFd::as_raw_fd).collect(),
+            fds,
+            rlimits: self.rlimits.clone(),
+            nice: self.nice,
+            credentials,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            dumpable: self.dumpable,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            private_tmp: self.private_tmp,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            protect_system: self.protect_system,
+            #[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+            login_class,
+            cwd,
+            stdio_state: if self.noclose {
+                StdioState::Inherited
+            } else if self.redirects_stdio() {
+                StdioState::Redirected
+            } else {
+                StdioState::Closed
+            },
+            pidfile_path: self.pidfile.clone(),
+            startup_timeout: self.startup_timeout,
+            status: match &self.status_file {
+                Some(status) => Some(status.stage()?),
+                None => None,
+            },
+            status_file: match &self.status_file {
+                Some(status) => Some(status.clone().absolute()?),
+                None => None,
+            },
+            #[cfg(all(feature = "openbsd", target_os = "openbsd"))]
+            pledge: match &self.pledge {
+                Some((promises, execpromises)) => Some((
+                    CString::new(promises.as_str()).map_err(|_| -1)?,
+                    match execpromises {
+                        Some(execpromises) => {
+                            Some(CString::new(execpromises.as_str()).map_err(|_| -1)?)
+                        }
+                        None => None,
+                    },
+                )),
+                None => None,
+            },
+            #[cfg(all(feature = "openbsd", target_os = "openbsd"))]
+            unveil: self
+                .unveil
+                .iter()
+                .map(|(path, permissions)| {
+                    Ok((
+                        CString::new(path.as_os_str().as_bytes()).map_err(|_| -1)?,
+                        CString::new(permissions.as_str()).map_err(|_| -1)?,
+                    ))
+                })
+                .collect::<Result<_, i32>>()?,
+        })
+    }
+
+    /// Run the [`daemon_with`] sequence in a child and replace the daemon with
+    /// `cmd`, returning the pid of the daemon
+    ///
+    /// Every option of the builder is applied before `exec`, which makes it
+    /// possible to daemonize programs that don't use this crate, like
+    /// `daemonize(1)` does. Unlike [`Daemon::start`] the calling process keeps
+    /// running. The command is started with [`Command::spawn`] and the daemon
+    /// sequence runs in its `pre_exec` hook, so a failing `exec` is reported
+    /// here. With `double_fork(false)` the daemon is a child of the caller,
+    /// which should exit or reap it.
+    ///
+    /// The standard streams of the daemon come from the builder, whatever `cmd`
+    /// configures is replaced, and [`Daemon::launcher_output`] has no effect.
+    /// The hook stays on `cmd` but does nothing on later spawns, [`DAEMON_ENV`]
+    /// stays set in its environment.
+    ///
+    /// Example:
+    ///
+    ///```no_run
+    ///use fork::Daemon;
+    ///use std::process::{exit, Command};
+    ///
+    ///let daemon = Daemon::new()
+    ///    .pidfile("/run/redis.pid")
+    ///    .user("redis")
+    ///    .umask(0o027);
+    ///match daemon.exec(Command::new("redis-server").arg("/etc/redis.conf")) {
+    ///    Ok(_) => exit(0),
+    ///    Err(_) => exit(1),
+    ///}
+    ///```
+    ///
+    /// # Errors
+    /// returns `-1` if daemonizing or `exec` fails
+    pub fn exec(&self, cmd: &mut Command) -> Result<libc::pid_t, i32> {
+        if let Some(env) = &self.exec_env {
+            env.apply(cmd);
+        }
+        self.prepare()?.exec(cmd)
+    }
+
+    /// Run a helper command from within the daemon under the daemon's settings
+    /// and wait for it, like `system(3)`
+    ///
+    /// The command gets the working directory, umask, resource limits,
+    /// priority, user and group of the builder and its [`Daemon::exec_env`].
+    /// It shares the daemon's standard streams unless `cmd` configures them.
+    /// User and group are only switched when they differ from the caller's, so
+    /// this also works after the daemon dropped its privileges. The settings
+    /// are applied in a `pre_exec` hook that stays on `cmd`.
+    ///
+    /// Example:
+    ///
+    ///```no_run
+    ///use fork::{Daemon, Daemonized};
+    ///use std::process::Command;
+    ///
+    ///let daemon = Daemon::new().user("www").umask(0o027);
+    ///if let Ok(Daemonized::Daemon(_)) = daemon.start() {
+    ///    // the hook can't escape the confinement of the service
+    ///    let _ = daemon.run_in_context(&mut Command::new("/etc/myapp/reload-hook"));
+    ///}
+    ///```
+    ///
+    /// # Errors
+    /// returns `-1` if the user or group can't be resolved, or if the command
+    /// can't be started under the settings
+    pub fn run_in_context(&self, cmd: &mut Command) -> Result<ExitStatus, i32> {
+        if let Some(env) = &self.exec_env {
+            env.apply(cmd);
+        }
+        if !self.nochdir {
+            cmd.current_dir(
+                self.working_dir
+                    .as_deref()
+                    .unwrap_or_else(|| Path::new("/")),
+            );
+        }
+        let credentials = self.credentials()?.filter(|credentials| {
+            let uid = credentials.user.as_ref().map(|(uid, _)| *uid);
+            unsafe {
+                credentials.gid != libc::getgid() || uid.is_some_and(|uid| uid != libc::getuid())
+            }
+        });
+        let umask = self.umask;
+        let rlimits = self.rlimits.clone();
+        let nice = self.nice;
+        let error = |_| io::Error::last_os_error();
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(mode) = umask {
+                    libc::umask(mode);
+                }
+                for &(resource, soft, hard) in &rlimits {
+                    setrlimit(resource, soft, hard).map_err(error)?;
+                }
+                if let Some(nice) = nice {
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, nice) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                credentials
+                    .as_ref()
+                    .map_or(Ok(()), Credentials::apply)
+                    .map_err(error)
+            })
+        };
+        cmd.status().map_err(|_| -1)
+    }
+
+    /// Create the temporary pid file with the configured permissions and owner
+    fn open_pidfile(
+        &self,
+        path: &Path,
+        credentials: Option<&Credentials>,
+    ) -> Result<StagedFile, i32> {
+        let pidfile = StagedFile::create(path, Vec::new(), b"\n".to_vec())?;
+        let fd = pidfile.file.as_raw_fd();
+        if let Some(mode) = self.pidfile_mode {
+            if unsafe { libc::fchmod(fd, mode) } == -1 {
+                return Err(-1);
+            }
+        }
+        if let Some(credentials) = credentials.filter(|_| self.pidfile_owner) {
+            let uid = credentials
+                .user
+                .as_ref()
+                .map_or(libc::uid_t::MAX, |(uid, _)| *uid);
+            if unsafe { libc::fchown(fd, uid, credentials.gid) } == -1 {
+                return Err(-1);
+            }
+        }
+        Ok(pidfile)
+    }
+
+    const fn redirects_stdio(&self) -> bool {
+        self.redirect_stdio || self.stdout.is_some() || self.stderr.is_some()
+    }
+
+    /// stdin, stdout and stderr to redirect to, `None` to close them
+    ///
+    /// stdin is always set, stdout and stderr are `None` when the daemon
+    /// discards them, see [`PreparedDaemon::discard_output`].
+    fn open_stdio(&self) -> Result<Option<[Option<File>; 3]>, i32> {
+        if self.noclose || !self.redirects_stdio() {
+            return Ok(None);
+        }
+        let null = match self.null_policy {
+            NullPolicy::DevNull => Some(null_file()?),
+            NullPolicy::DevNullOrDiscard => null_file().ok(),
+            NullPolicy::Discard => None,
+        };
+        let open = |path: Option<&PathBuf>| match (path, &null) {
+            (Some(path), _) => OpenOptions::new()
+                .append(true)
+                .create(true)
+                .custom_flags(libc::O_CLOEXEC)
+                .open(path)
+                .map(Some)
+                .map_err(|_| -1),
+            (None, Some(null)) => null.try_clone().map(Some).map_err(|_| -1),
+            (None, None) => Ok(None),
+        };
+        let input = match &null {
+            Some(null) => null.try_clone().map_err(|_| -1)?,
+            None => unsafe { File::from_raw_fd(eof_pipe()?) },
+        };
+        Ok(Some([
+            Some(input),
+            open(self.stdout.as_ref())?,
+            open(self.stderr.as_ref())?,
+        ]))
+    }
+
+    fn credentials(&self) -> Result<Option<Credentials>, i32> {
+        if let Some(ResolvedUser(credentials)) = &self.resolved_user {
+            return Ok(Some(Credentials::clone(credentials)));
+        }
+        let resolver: &dyn Resolver = match &self.resolver {
+            Some(ResolverHook(resolver)) => resolver.as_ref(),
+            None => &NssResolver,
+        };
+        // uid, and the name and primary gid of a user looked up by name
+        let user = match &self.user {
+            Some(Account::Name(name)) => {
+                let (uid, gid) = resolver.user(name)?;
+                Some((uid, Some((name, gid))))
+            }
+            Some(Account::Id(uid)) => Some((*uid, None)),
+            None => None,
+        };
+        let gid = match (&self.group, &user) {
+            (Some(Account::Name(name)), _) => resolver.group(name)?,
+            (Some(Account::Id(gid)), _) => *gid,
+            (None, Some((_, Some((_, gid))))) => *gid,
+            // a bare uid has no primary group
+            (None, Some((_, None))) => return Err(-1),
+            (None, None) => return Ok(None),
+        };
+        let user = match (user, &self.supplementary_groups) {
+            (Some((uid, _)), Some(accounts)) => {
+                let mut groups = Vec::with_capacity(accounts.len());
+                for account in accounts {
+                    let gid = match account {
+                        Account::Name(name) => resolver.group(name)?,
+                        Account::Id(gid) => *gid,
+                    };
+                    if !groups.contains(&gid) {
+                        groups.push(gid);
+                    }
+                }
+                Some((uid, groups))
+            }
+            (Some((uid, Some((name, _)))), None) => Some((uid, resolver.groups(name, gid)?)),
+            (Some((uid, None)), None) => Some((uid, vec![gid])),
+            (None, _) => None,
+        };
+        Ok(Some(Credentials { user, gid }))
+    }
+}
+
+/// `RAMFS_MAGIC` from `<linux/magic.h>`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const RAMFS_MAGIC: u32 = 0x8584_58f6;
+
+/// Fail if `dir` is on a volatile file system or not searchable with
+/// `credentials`
+fn check_working_dir(dir: &Path, credentials: Option<&Credentials>) -> Result<(), i32> {
+    let path = CString::new(dir.as_os_str().as_bytes()).map_err(|_| -1)?;
+    let mut fs = unsafe { std::mem::zeroed::<libc::statfs>() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut fs) } == -1 {
+        return Err(-1);
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let volatile = [libc::TMPFS_MAGIC as u32, RAMFS_MAGIC].contains(&(fs.f_type as u32));
+    #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "openbsd"))]
+    let volatile =
+        unsafe { std::ffi::CStr::from_ptr(fs.f_fstypename.as_ptr()) }.to_bytes() == b"tmpfs";
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "openbsd"
+    )))]
+    let volatile = false;
+    if volatile {
+        return Err(-1);
+    }
+
+    let Some(credentials) = credentials else {
+        return Ok(());
+    };
+    let uid = credentials
+        .user
+        .as_ref()
+        .map_or_else(|| unsafe { libc::getuid() }, |(uid, _)| *uid);
+    if uid == 0 {
+        return Ok(());
+    }
+    let groups = credentials
+        .user
+        .as_ref()
+        .map_or(&[][..], |(_, groups)| groups);
+    for dir in dir.ancestors() {
+        let meta = std::fs::metadata(dir).map_err(|_| -1)?;
+        let mode = meta.mode();
+        let search = if meta.uid() == uid {
+            mode & 0o100
+        } else if meta.gid() == credentials.gid || groups.contains(&meta.gid()) {
+            mode & 0o010
+        } else {
+            mode & 0o001
+        };
+        if search == 0 {
+            return Err(-1);
+        }
+    }
+    Ok(())
+}
+
+/// A [`Daemon`] with users resolved and files opened, returned by
+/// [`Daemon::prepare`]
+#[derive(Debug)]
+pub struct PreparedDaemon {
+    double_fork: bool,
+    foreground: bool,
+    chdir: Option<CString>,
+    umask: Option<libc::mode_t>,
+    noclose: bool,
+    stdio: Option<[Option<File>; 3]>,
+    /// read and write ends of the output and status pipes
+    launcher: Option<[File; 4]>,
+    pidfile: Option<StagedFile>,
+    /// the status file for the daemon to commit, [`Daemon::exec`] writes
+    /// `status_file` from the launcher instead
+    status: Option<StagedFile>,
+    fds: Vec<OwnedFd>,
+    /// `fds` for [`DaemonInfo::fds`], collected before forking
+    fd_numbers: Vec<libc::c_int>,
+    rlimits: Vec<(Resource, libc::rlim_t, libc::rlim_t)>,
+    nice: Option<libc::c_int>,
+    credentials: Option<Credentials>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    dumpable: Option<bool>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    private_tmp: bool,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    protect_system: bool,
+    #[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+    login_class: Option<LoginClass>,
+    cwd: Option<PathBuf>,
+    stdio_state: StdioState,
+    pidfile_path: Option<PathBuf>,
+    startup_timeout: Option<Duration>,
+    status_file: Option<StatusFile>,
+    #[cfg(all(feature = "openbsd", target_os = "openbsd"))]
+    pledge: Option<(CString, Option<CString>)>,
+    #[cfg(all(feature = "openbsd", target_os = "openbsd"))]
+    unveil: Vec<(CString, CString)>,
+}
+
+impl PreparedDaemon {
+    /// Run the [`daemon_with`] sequence, the calling process exits
+    ///
+    /// Between the forks only system calls are made, nothing is allocated.
+    ///
+    /// # Errors
+    /// returns `-1` if any of the steps fails
+    pub fn start(mut self) -> Result<Daemonized, i32> {
+        if self.foreground {
+            self.enter_session()?;
+            self.discard_output(None)?;
+            self.write_status()?;
+            self.finish()?;
+            #[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+            self.login_environment()?;
+            DAEMONIZED.store(true, Ordering::Relaxed);
+            return Ok(Daemonized::Daemon(self.into_info(None)));
+        }
+        let deadline = self.startup_timeout.map(|timeout| Instant::now() + timeout);
+        match fork()? {
+            Fork::Parent(child) => match self.launcher.take() {
+                Some([output, output_end, status, status_end]) => {
+                    drop((output_end, status_end));
+                    let code =
+                        relay_launcher_output(output.as_raw_fd(), status.as_raw_fd(), deadline);
+                    if code == STARTUP_TIMED_OUT {
+                        // the session leader's group holds the daemon too
+                        unsafe { libc::kill(-child, libc::SIGKILL) };
+                    }
+                    exit(code)
+                }
+                None => exit(0),
+            },
+            Fork::Child => {
+                self.enter_session()?;
+                let mut launcher = match self.launcher.take() {
+                    Some([output, output_end, status, status_end]) => {
+                        drop((output, status));
+                        let save = |fd| match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 3) } {
+                            -1 => None,
+                            dup => Some(unsafe { OwnedFd::from_raw_fd(dup) }),
+                        };
+                        let launcher = Launcher {
+                            stdout: save(1),
+                            stderr: save(2),
+                            status: status_end.into(),
+                        };
+                        dup_onto(output_end.as_raw_fd(), 1)?;
+                        dup_onto(output_end.as_raw_fd(), 2)?;
+                        Some(launcher)
+                    }
+                    None => None,
+                };
+                if self.double_fork {
+                    if let Fork::Parent(pid) = fork()? {
+                        drop(launcher);
+                        return Ok(Daemonized::Parent(pid));
+                    }
+                }
+                self.discard_output(launcher.as_mut())?;
+                self.write_status()?;
+                self.finish()?;
+                #[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+                self.login_environment()?;
+                DAEMONIZED.store(true, Ordering::Relaxed);
+                Ok(Daemonized::Daemon(self.into_info(launcher)))
+            }
+        }
+    }
+
+    /// Run the [`daemon_with`] sequence in a child and replace the daemon with
+    /// `cmd`, see [`Daemon::exec`]
+    ///
+    /// # Errors
+    /// returns `-1` if daemonizing or `exec` fails
+    pub fn exec(mut self, cmd: &mut Command) -> Result<libc::pid_t, i32> {
+        cmd.env(DAEMON_ENV, "1");
+        if self.foreground {
+            self.enter_session()?;
+            self.discard_output(None)?;
+            self.write_status()?;
+            self.finish()?;
+            self.inherit_fds()?;
+            let _ = cmd.exec();
+            return Err(-1);
+        }
+        let double_fork = self.double_fork;
+        let status_file = self.status_file.clone();
+        if let Some(status) = self.status.take() {
+            status.discard();
+        }
+        let [report, report_end] = cloexec_pipe()?;
+        let (report, report_end) =
+            unsafe { (File::from_raw_fd(report), File::from_raw_fd(report_end)) };
+        let report_fd = report_end.as_raw_fd();
+        let active = Arc::new(AtomicBool::new(true));
+        let hook_active = Arc::clone(&active);
+        let error = |_| io::Error::last_os_error();
+        unsafe {
+            cmd.pre_exec(move || {
+                if !hook_active.load(Ordering::Acquire) {
+                    return Ok(());
+                }
+                self.enter_session().map_err(error)?;
+                if self.double_fork {
+                    match libc::fork() {
+                        -1 => return Err(io::Error::last_os_error()),
+                        0 => {}
+                        pid => {
+                            let pid = pid.to_ne_bytes();
+                            libc::write(report_fd, pid.as_ptr().cast(), pid.len());
+                            libc::_exit(0)
+                        }
+                    }
+                }
+                self.discard_output(None).map_err(error)?;
+                self.finish().map_err(error)?;
+                self.inherit_fds().map_err(error)
+            })
+        };
+        let spawned = cmd.spawn();
+        active.store(false, Ordering::Release);
+        drop(report_end);
+        let mut child = spawned.map_err(|_| -1)?;
+        let pid = if double_fork {
+            child.wait().map_err(|_| -1)?;
+            read_pid(report.as_raw_fd())?
+        } else {
+            libc::pid_t::try_from(child.id()).map_err(|_| -1)?
+        };
+        if let Some(status) = status_file {
+            status.write_for(pid)?;
+        }
+        Ok(pid)
+    }
+
+    /// Clear `FD_CLOEXEC` on the descriptors of [`Daemon::bind_before_drop`]
+    /// so the command started by [`Daemon::exec`] inherits them
+    fn inherit_fds(&self) -> Result<(), i32> {
+        for fd in &self.fds {
+            if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, 0) } == -1 {
+                return Err(-1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the status file of the daemon, if any
+    fn write_status(&self) -> Result<(), i32> {
+        self.status
+            .as_ref()
+            .map_or(Ok(()), |status| status.commit(getpid()))
+    }
+
+    /// `setsid`, change directory, set the umask and close or redirect stdio
+    fn enter_session(&self) -> Result<(), i32> {
+        if !self.foreground {
+            setsid()?;
+        }
+        if let Some(dir) = &self.chdir {
+            if unsafe { libc::chdir(dir.as_ptr()) } == -1 {
+                return Err(-1);
+            }
+        }
+        if let Some(mode) = self.umask {
+            unsafe { libc::umask(mode) };
+        }
+        if !self.noclose {
+            match &self.stdio {
+                Some(files) => {
+                    // discarded streams read from stdin until the daemon
+                    // creates their sink, writes fail meanwhile
+                    for (fd, file) in (0..).zip(files) {
+                        let file = file.as_ref().or(files[0].as_ref()).ok_or(-1)?;
+                        dup_onto(file.as_raw_fd(), fd)?;
+                    }
+                }
+                None => close_fd()?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Connect the discarded stdout and stderr to a new sink, or hand it to
+    /// `launcher` to switch to once ready, in the daemon after the last fork
+    fn discard_output(&self, launcher: Option<&mut Launcher>) -> Result<(), i32> {
+        let Some([_, stdout, stderr]) = &self.stdio else {
+            return Ok(());
+        };
+        if stdout.is_some() && stderr.is_some() {
+            return Ok(());
+        }
+        let sink = unsafe { OwnedFd::from_raw_fd(discard_sink()?) };
+        match launcher {
+            Some(launcher) => {
+                for (file, saved) in [
+                    (stdout, &mut launcher.stdout),
+                    (stderr, &mut launcher.stderr),
+                ] {
+                    if file.is_none() {
+                        *saved = Some(sink.try_clone().map_err(|_| -1)?);
+                    }
+                }
+            }
+            None => {
+                for (fd, file) in [(1, stdout), (2, stderr)] {
+                    if file.is_none() {
+                        dup_onto(sink.as_raw_fd(), fd)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the pid file, set up mounts, limits and priority and drop
+    /// privileges
+    fn finish(&self) -> Result<(), i32> {
+        if let Some(pidfile) = &self.pidfile {
+            pidfile.commit(getpid())?;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.private_tmp || self.protect_system {
+            namespace::unshare_current(libc::CLONE_NEWNS).map_err(|_| -1)?;
+            if self.private_tmp {
+                namespace::mount_private_tmp()?;
+            }
+            if self.protect_system {
+                namespace::bind(c"/usr", c"/usr", true)?;
+                namespace::bind(c"/etc", c"/etc", true)?;
+            }
+        }
+        #[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+        if let Some(class) = &self.login_class {
+            let mut flags = LOGIN_SETRESOURCES | LOGIN_SETPRIORITY;
+            if self.umask.is_none() {
+                flags |= LOGIN_SETUMASK;
+            }
+            class.apply(flags)?;
+        }
+        for &(resource, soft, hard) in &self.rlimits {
+            setrlimit(resource, soft, hard)?;
+        }
+        if let Some(nice) = self.nice {
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == -1 {
+                return Err(-1);
+            }
+        }
+        if let Some(credentials) = &self.credentials {
+            credentials.apply()?;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(dumpable) = self.dumpable {
+            let flag = libc::c_ulong::from(dumpable);
+            if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, flag) } == -1
+                || unsafe { libc::prctl(libc::PR_GET_DUMPABLE) } != libc::c_int::from(dumpable)
+            {
+                return Err(-1);
+            }
+        }
+        #[cfg(all(feature = "openbsd", target_os = "openbsd"))]
+        self.sandbox()?;
+        Ok(())
+    }
+
+    /// Set the environment of the login class, after dropping privileges
+    #[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+    fn login_environment(&self) -> Result<(), i32> {
+        self.login_class
+            .as_ref()
+            .map_or(Ok(()), |class| class.apply(LOGIN_SETENV | LOGIN_SETPATH))
+    }
+
+    /// `unveil` the configured paths, lock them and `pledge`
+    #[cfg(all(feature = "openbsd", target_os = "openbsd"))]
+    fn sandbox(&self) -> Result<(), i32> {
+        for (path, permissions) in &self.unveil {
+            if unsafe { libc::unveil(path.as_ptr(), permissions.as_ptr()) } == -1 {
+                return Err(-1);
+            }
+        }
+        if !self.unveil.is_empty()
+            && unsafe { libc::unveil(std::ptr::null(), std::ptr::null()) } == -1
+        {
+            return Err(-1);
+        }
+        if let Some((promises, execpromises)) = &self.pledge {
+            let execpromises = execpromises
+                .as_ref()
+                .map_or(std::ptr::null(), |execpromises| execpromises.as_ptr());
+            if unsafe { libc::pledge(promises.as_ptr(), execpromises) } == -1 {
+                return Err(-1);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PreparedDaemon {
+    fn into_info(self, launcher: Option<Launcher>) -> DaemonInfo {
+        // the daemon owns the descriptors now, listed in `fd_numbers`
+        std::mem::forget(self.fds);
+        DaemonInfo {
+            pid: getpid(),
+            sid: current_session_id(),
+            pgid: getpgrp(),
+            cwd: self.cwd,
+            stdio: self.stdio_state,
+            pidfile: self.pidfile_path,
+            fds: self.fd_numbers,
+            launcher,
+        }
+    }
+}
+
+/// Set the title `ps` and `top` show for the process, like nginx and
+/// PostgreSQL label their workers with `myapp: worker 3`
+///
+/// On Linux the command name becomes the first 15 bytes of the title
+/// [see prctl(2)](https://man7.org/linux/man-pages/man2/prctl.2.html) for the
+/// calling thread, the main thread for the process name, and the memory holding
+/// the arguments of the process is overwritten, so the full command line shows
+/// the title. It is cut to the length of the original arguments, which
+/// [`std::env::args`] returns no longer, [`reexec_self`] restarts with a copy
+/// saved before the first title. The BSDs use `setproctitle`
+/// [see setproctitle(3)](https://man.freebsd.org/cgi/man.cgi?setproctitle).
+///
+/// Example:
+///
+///```
+///use fork::{fork, set_process_title, Fork};
+///
+///if let Ok(Fork::Child) = fork() {
+///    // the forked child runs a single thread
+///    unsafe { set_process_title("myapp: worker 3") }.expect("failed to set title");
+///    std::process::exit(0);
+///}
+///```
+///
+/// # Errors
+/// returns `-1` if the title contains a nul byte or the process arguments
+/// can't be located
+///
+/// # Safety
+/// On Linux the title is written into the memory [`std::env::args`] and
+/// [`std::env::args_os`] read, no other thread may read the arguments of the
+/// process while it runs. Call it before spawning threads, or in a forked
+/// child, which only runs the calling thread.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+pub unsafe fn set_process_title(title: &str) -> Result<(), i32> {
+    let name = CString::new(title).map_err(|_| -1)?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        if unsafe { libc::prctl(libc::PR_SET_NAME, name.as_ptr()) } == -1 {
+            return Err(-1);
+        }
+        let stat = std::fs::read_to_string("/proc/self/stat").map_err(|_| -1)?;
+        let end = stat.rfind(')').ok_or(-1)?;
+        // arg_start and arg_end are fields 48 and 49, the first after the
+        // command name is field 3
+        let mut fields = stat[end + 1..].split_whitespace().skip(45);
+        let mut field =
+            || -> Result<usize, i32> { fields.next().and_then(|f| f.parse().ok()).ok_or(-1) };
+        let (start, end) = (field()?, field()?);
+        let Some(len) = end.checked_sub(start).filter(|&len| len > 0) else {
+            return Err(-1);
+        };
+        ORIGINAL_ARGS.get_or_init(|| env::args_os().collect());
+        // raw writes, a `&mut [u8]` would alias the arguments std hands out
+        let args = start as *mut u8;
+        let copied = title.len().min(len - 1);
+        unsafe {
+            std::ptr::copy_nonoverlapping(title.as_ptr(), args, copied);
+            std::ptr::write_bytes(args.add(copied), 0, len - copied);
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    unsafe {
+        libc::setproctitle(c"-%s".as_ptr(), name.as_ptr());
+    }
+    Ok(())
+}
+
+/// The arguments of the process before [`set_process_title`] overwrote them
+static ORIGINAL_ARGS: OnceLock<Vec<OsString>> = OnceLock::new();
+
+/// Environment variable set by [`reexec_self`] in the new image
+pub const REEXEC_ENV: &str = "FORK_REEXEC";
+
+/// Whether the process was started by [`reexec_self`]
+#[must_use]
+pub fn is_reexec() -> bool {
+    env::var_os(REEXEC_ENV).is_some()
+}
+
+/// Replace the process with a fresh copy of its executable, keeping the pid,
+/// the arguments and the descriptors that aren't close-on-exec
+///
+/// The executable is looked up at the path it was started from, so a binary
+/// replaced by a package upgrade starts in its new version. [`REEXEC_ENV`] and
+/// `extra_env` are added to the environment, letting the new image tell a
+/// restart from a cold start with [`is_reexec`] and find inherited
+/// descriptors. Only returns if `exec` fails.
+///
+/// Example:
+///
+///```no_run
+///use fork::{is_reexec, reexec_self, wait_for_signals, Signal};
+///
+///if is_reexec() {
+///    println!("restarted");
+///}
+///if let Ok(Signal::SIGUSR2) = wait_for_signals(&[Signal::SIGUSR2]) {
+///    let _ = reexec_self(&[("LISTEN_FD", "3")]);
+///}
+///```
+///
+/// # Errors
+/// returns `-1` if the executable can't be found or `exec` fails
+pub fn reexec_self<K, V>(extra_env: &[(K, V)]) -> Result<std::convert::Infallible, i32>
+where
+    K: AsRef<OsStr>,
+    V: AsRef<OsStr>,
+{
+    let mut cmd = self_command()?;
+    for (key, value) in extra_env {
+        cmd.env(key, value);
+    }
+    let _ = cmd.exec();
+    Err(-1)
+}
+
+/// The executable of the process with the same arguments, [`REEXEC_ENV`] set
+/// and [`handoff::HANDOFF_ENV`] removed
+fn self_command() -> Result<Command, i32> {
+    let mut exe = env::current_exe().map_err(|_| -1)?;
+    // Linux reports a replaced executable as "<path> (deleted)"
+    if let Some(path) = exe
+        .to_str()
+        .and_then(|path| path.strip_suffix(" (deleted)"))
+    {
+        exe = PathBuf::from(path);
+    }
+    let mut args = match ORIGINAL_ARGS.get() {
+        Some(args) => args.clone().into_iter(),
+        None => env::args_os().collect::<Vec<_>>().into_iter(),
+    };
+    let mut cmd = Command::new(exe);
+    if let Some(arg0) = args.next() {
+        cmd.arg0(arg0);
+    }
+    // the socket of a finished handoff is gone, `upgrade` sets a new one
+    cmd.args(args)
+        .env(REEXEC_ENV, "1")
+        .env_remove(handoff::HANDOFF_ENV);
+    Ok(cmd)
+}
+
+/// Whether the process is a launchd job, always `false` outside macOS
+///
+/// launchd starts jobs directly as its children and sets `XPC_SERVICE_NAME` to
+/// the job label. Jobs must not fork away, see [`Daemon::foreground`].
+#[must_use]
+pub fn launched_by_launchd() -> bool {
+    cfg!(target_os = "macos")
+        && getppid() == 1
+        && env::var_os("XPC_SERVICE_NAME").is_some_and(|label| label != "0")
+}
+
+#[cfg(target_os = "macos")]
+extern "C" {
+    fn launch_activate_socket(
+        name: *const libc::c_char,
+        fds: *mut *mut libc::c_int,
+        cnt: *mut libc::size_t,
+    ) -> libc::c_int;
+}
+
+/// Descriptors of the socket `name` declared in the `Sockets` dictionary of the
+/// launchd job [see launch_activate_socket(3)](https://developer.apple.com/documentation/xpc/1505523-launch_activate_socket)
+///
+/// Example:
+///
+///```no_run
+///use fork::launchd_sockets;
+///use std::net::TcpListener;
+///use std::os::unix::io::FromRawFd;
+///
+///let fds = launchd_sockets("Listeners").expect("not started by launchd");
+///let listener = unsafe { TcpListener::from_raw_fd(fds[0]) };
+///```
+///
+/// # Errors
+/// returns `-1` if the job has no such socket or wasn't started by launchd
+#[cfg(target_os = "macos")]
+pub fn launchd_sockets(name: &str) -> Result<Vec<libc::c_int>, i32> {
+    let name = CString::new(name).map_err(|_| -1)?;
+    let mut fds: *mut libc::c_int = std::ptr::null_mut();
+    let mut count: libc::size_t = 0;
+    if unsafe { launch_activate_socket(name.as_ptr(), &mut fds, &mut count) } != 0 {
+        return Err(-1);
+    }
+    let sockets = unsafe { std::slice::from_raw_parts(fds, count) }.to_vec();
+    unsafe { libc::free(fds.cast()) };
+    Ok(sockets)
+}
+
+/// Read a pid written with `to_ne_bytes` from `fd`, `-1` if none arrived
+fn read_pid(fd: libc::c_int) -> Result<libc::pid_t, i32> {
+    let mut buf = [0u8; std::mem::size_of::<libc::pid_t>()];
+    let read = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+    let pid = libc::pid_t::from_ne_bytes(buf);
+    if read.unsigned_abs() == buf.len() && pid > 0 {
+        Ok(pid)
+    } else {
+        Err(-1)
+    }
+}
+
+/// Pid or status file created by [`Daemon::prepare`] under a temporary name
+/// in its directory, renamed into place once the daemon wrote its pid
+#[derive(Debug)]
+struct StagedFile {
+    file: File,
+    dir: File,
+    tmp: CString,
+    name: CString,
+    /// written before and after the pid
+    before: Vec<u8>,
+    after: Vec<u8>,
+}
+
+impl StagedFile {
+    /// Create the temporary file next to `path`
+    fn create(path: &Path, before: Vec<u8>, after: Vec<u8>) -> Result<Self, i32> {
+        let name = |path: &Path| {
+            path.file_name()
+                .ok_or(-1)
+                .and_then(|name| CString::new(name.as_bytes()).map_err(|_| -1))
+        };
+        let dir = parent_dir(path)?;
+        let name = name(path)?;
+        let (tmp, file) = create_temporary(path)?;
+        Ok(Self {
+            // the suffix is ASCII, the name is valid if the path's was
+            tmp: CString::new(tmp.file_name().ok_or(-1)?.as_bytes()).map_err(|_| -1)?,
+            name,
+            dir,
+            file,
+            before,
+            after,
+        })
+    }
+
+    /// Write `pid`, sync and rename the file into place, only makes system
+    /// calls so it can run between `fork` and `exec`
+    ///
+    /// The rename is relative to the directory, the daemon may have changed
+    /// its working directory.
+    fn commit(&self, pid: libc::pid_t) -> Result<(), i32> {
+        let mut buf = [0; 16];
+        let pid = pid_digits(pid, &mut buf);
+        let parts = [&self.before[..], pid, &self.after[..]];
+        let iov = parts.map(|part| libc::iovec {
+            iov_base: part.as_ptr().cast_mut().cast(),
+            iov_len: part.len(),
+        });
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        let fd = self.file.as_raw_fd();
+        let dir = self.dir.as_raw_fd();
+        unsafe {
+            if libc::writev(fd, iov.as_ptr(), 3).unsigned_abs() != len
+                || libc::fsync(fd) == -1
+                || libc::renameat(dir, self.tmp.as_ptr(), dir, self.name.as_ptr()) == -1
+                || libc::fsync(dir) == -1
+            {
+                return Err(-1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the temporary file, when it won't be committed
+    fn discard(&self) {
+        unsafe { libc::unlinkat(self.dir.as_raw_fd(), self.tmp.as_ptr(), 0) };
+    }
+}
+
+#[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+#[link(name = "util")]
+extern "C" {
+    fn login_getpwclass(pwd: *const libc::passwd) -> *mut libc::c_void;
+    fn login_close(lc: *mut libc::c_void);
+    fn setusercontext(
+        lc: *mut libc::c_void,
+        pwd: *const libc::passwd,
+        uid: libc::uid_t,
+        flags: libc::c_uint,
+    ) -> libc::c_int;
+}
+
+/// `setusercontext` flags from `<login_cap.h>`
+#[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+const LOGIN_SETPATH: libc::c_uint = 0x0004;
+#[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+const LOGIN_SETPRIORITY: libc::c_uint = 0x0008;
+#[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+const LOGIN_SETRESOURCES: libc::c_uint = 0x0010;
+#[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+const LOGIN_SETUMASK: libc::c_uint = 0x0020;
+#[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+const LOGIN_SETENV: libc::c_uint = 0x0080;
+
+/// Login class of the daemon user with the passwd entry it was read from,
+/// see [`Daemon::login_class`]
+#[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+struct LoginClass {
+    cap: *mut libc::c_void,
+    pwd: libc::passwd,
+    /// holds the strings `pwd` points to
+    _buf: Vec<libc::c_char>,
+}
+
+// the capability database entry is only read, and only by one process
+#[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+unsafe impl Send for LoginClass {}
+#[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+unsafe impl Sync for LoginClass {}
+
+#[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+impl LoginClass {
+    /// Look up the passwd entry of `uid` and its login class [see getpwuid(3)](https://man.freebsd.org/cgi/man.cgi?getpwuid)
+    fn new(uid: libc::uid_t) -> Result<Self, i32> {
+        let mut pwd = unsafe { std::mem::zeroed::<libc::passwd>() };
+        let mut buf = vec![0 as libc::c_char; NSS_BUFFER_SIZE];
+        let mut result = std::ptr::null_mut();
+        let res =
+            unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+        if res != 0 || result.is_null() {
+            return Err(-1);
+        }
+        let cap = unsafe { login_getpwclass(&pwd) };
+        if cap.is_null() {
+            return Err(-1);
+        }
+        Ok(Self {
+            cap,
+            pwd,
+            _buf: buf,
+        })
+    }
+
+    /// `setusercontext` with `flags`, only makes system calls unless `flags`
+    /// sets the environment
+    fn apply(&self, flags: libc::c_uint) -> Result<(), i32> {
+        match unsafe { setusercontext(self.cap, &self.pwd, self.pwd.pw_uid, flags) } {
+            -1 => Err(-1),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+impl Drop for LoginClass {
+    fn drop(&mut self) {
+        unsafe { login_close(self.cap) };
+    }
+}
+
+#[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+impl std::fmt::Debug for LoginClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoginClass")
+            .field("uid", &self.pwd.pw_uid)
+            .finish_non_exhaustive()
+    }
+}
+
+/// `pid` in decimal, formatted without allocating
+fn pid_digits(pid: libc::pid_t, buf: &mut [u8; 16]) -> &[u8] {
+    let mut n = pid.unsigned_abs();
+    let mut start = buf.len();
+    loop {
+        start -= 1;
+        buf[start] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    &buf[start..]
+}
+
+/// Turns the user and group names of [`Daemon::user`] and [`Daemon::group`]
+/// into ids, see [`Daemon::resolver`]
+///
+/// The default, [`NssResolver`], asks the system's user database. Another
+/// resolver can read a file shipped with the application or a fixed table, so
+/// nothing is loaded from NSS modules.
+///
+/// Example:
+///
+///```
+///use fork::{Daemon, Resolver};
+///
+///struct Fixed;
+///
+///impl Resolver for Fixed {
+///    fn user(&self, name: &str) -> Result<(libc::uid_t, libc::gid_t), i32> {
+///        match name {
+///            "app" => Ok((1000, 1000)),
+///            _ => Err(-1),
+///        }
+///    }
+///
+///    fn group(&self, name: &str) -> Result<libc::gid_t, i32> {
+///        match name {
+///            "app" => Ok(1000),
+///            _ => Err(-1),
+///        }
+///    }
+///}
+///
+///let daemon = Daemon::new().user("app").resolver(Fixed);
+///```
+pub trait Resolver: Send + Sync {
+    /// uid and primary gid of the user `name`
+    ///
+    /// # Errors
+    /// returns `-1` if the user doesn't exist
+    fn user(&self, name: &str) -> Result<(libc::uid_t, libc::gid_t), i32>;
+
+    /// gid of the group `name`
+    ///
+    /// # Errors
+    /// returns `-1` if the group doesn't exist
+    fn group(&self, name: &str) -> Result<libc::gid_t, i32>;
+
+    /// Supplementary groups of the user `name` with primary group `gid`, only
+    /// `gid` unless implemented
+    ///
+    /// # Errors
+    /// returns `-1` if the groups can't be listed
+    fn groups(&self, name: &str, gid: libc::gid_t) -> Result<Vec<libc::gid_t>, i32> {
+        let _ = name;
+        Ok(vec![gid])
+    }
+}
+
+/// [`Resolver`] using the system's user database, `getpwnam_r`, `getgrnam_r`
+/// and `getgrouplist`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NssResolver;
+
+impl Resolver for NssResolver {
+    fn user(&self, name: &str) -> Result<(libc::uid_t, libc::gid_t), i32> {
+        lookup_user(name)
+    }
+
+    fn group(&self, name: &str) -> Result<libc::gid_t, i32> {
+        lookup_group(name)
+    }
+
+    fn groups(&self, name: &str, gid: libc::gid_t) -> Result<Vec<libc::gid_t>, i32> {
+        group_list(name, gid)
+    }
+}
+
+/// Supplementary groups of `user` with `gid` as primary group [see getgrouplist(3)](https://man.freebsd.org/cgi/man.cgi?getgrouplist)
+fn group_list(user: &str, gid: libc::gid_t) -> Result<Vec<libc::gid_t>, i32> {
+    let user = CString::new(user).map_err(|_| -1)?;
+    let mut size: libc::c_int = 64;
+    while size <= 65536 {
+        let mut groups: Vec<libc::gid_t> = vec![0; size.unsigned_abs() as usize];
+        let mut count = size;
+        let res = unsafe {
+            libc::getgrouplist(
+                user.as_ptr(),
+                gid as _,
+                groups.as_mut_ptr().cast(),
+                &mut count,
+            )
+        };
+        if res != -1 {
+            groups.truncate(count.unsigned_abs() as usize);
+            return Ok(groups);
+        }
+        size *= 2;
+    }
+    Err(-1)
+}
+
+/// Size of the buffer for `getpwnam_r` and `getgrnam_r` when the system has no
+/// suggestion
+const NSS_BUFFER_SIZE: usize = 16384;
+
+/// Resolve a user name to its uid and primary gid [see getpwnam(3)](https://man.freebsd.org/cgi/man.cgi?getpwnam)
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t), i32> {
+    let name = CString::new(name).map_err(|_| -1)?;
+    let mut pwd = unsafe { std::mem::zeroed::<libc::passwd>() };
+    let mut buf = vec![0 as libc::c_char; NSS_BUFFER_SIZE];
+    let mut result = std::ptr::null_mut();
+    let res = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if res != 0 || result.is_null() {
+        return Err(-1);
+    }
+    Ok((pwd.pw_uid, pwd.pw_gid))
+}
+
+/// Resolve a group name to its gid [see getgrnam(3)](https://man.freebsd.org/cgi/man.cgi?getgrnam)
+fn lookup_group(name: &str) -> Result<libc::gid_t, i32> {
+    let name = CString::new(name).map_err(|_| -1)?;
+    let mut grp = unsafe { std::mem::zeroed::<libc::group>() };
+    let mut buf = vec![0 as libc::c_char; NSS_BUFFER_SIZE];
+    let mut result = std::ptr::null_mut();
+    let res = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if res != 0 || result.is_null() {
+        return Err(-1);
+    }
+    Ok(grp.gr_gid)
+}
+
+/// Ignore `SIGPIPE` so writes to a closed pipe or socket fail with `EPIPE`
+/// instead of terminating the process [see signal(3)](https://man.freebsd.org/cgi/man.cgi?signal)
+///
+/// A daemon talking to its launcher or to control sockets over pipes otherwise
+/// dies silently as soon as the peer goes away. Call it in the daemon branch
+/// after [`daemon_with`], the disposition is inherited across `fork` and `exec`.
+///
+/// # Errors
+/// returns `-1` if error
+pub fn ignore_sigpipe() -> Result<(), i32> {
+    match unsafe { libc::signal(libc::SIGPIPE, libc::SIG_IGN) } {
+        libc::SIG_ERR => Err(-1),
+        _ => Ok(()),
+    }
+}
+
+/// Write the whole buffer to `fd`, treating a closed reading end as a normal
+/// outcome rather than an error
+///
+/// Returns `Ok(true)` once everything was written and `Ok(false)` if the write
+/// failed with `EPIPE`. Interrupted writes are retried. Use together with
+/// [`ignore_sigpipe`], otherwise the process is killed before `EPIPE` is seen.
+///
+/// # Errors
+/// returns `-1` if the write fails for any other reason
+pub fn write_ignoring_epipe(fd: libc::c_int, buf: &[u8]) -> Result<bool, i32> {
+    let mut written = 0;
+    while written < buf.len() {
+        let rest = &buf[written..];
+        match unsafe { libc::write(fd, rest.as_ptr().cast(), rest.len()) } {
+            -1 => match io::Error::last_os_error().raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EPIPE) => return Ok(false),
+                _ => return Err(-1),
+            },
+            n => written += n.unsigned_abs(),
+        }
+    }
+    Ok(true)
+}
+
+/// Block the calling thread until one of `signals` is delivered and return it
+/// [see sigwait(2)](https://man.freebsd.org/cgi/man.cgi?sigwait)
+///
+/// The signals are added to the thread's signal mask and stay blocked after
+/// returning, so the ones arriving between two calls are kept pending rather
+/// than running their default action. This gives daemons a synchronous main loop
+/// without installing handlers. Call it, or block the same signals, before
+/// spawning other threads so that they inherit the mask.
+///
+/// Example:
+///
+///```no_run
+///use fork::{wait_for_signals, Signal};
+///
+///loop {
+///    match wait_for_signals(&[Signal::SIGHUP, Signal::SIGTERM]) {
+///        Ok(Signal::SIGHUP) => println!("reloading"),
+///        Ok(_) => break,
+///        Err(_) => panic!("sigwait failed"),
+///    }
+///}
+///```
+///
+/// # Errors
+/// returns `-1` if the signals can't be blocked or waited on
+pub fn wait_for_signals(signals: &[Signal]) -> Result<Signal, i32> {
+    let set = SignalSet::try_from(signals)?;
+    set.block()?;
+    set.wait()
+}
+
+/// Send `signal` with `value` attached to process `pid` [see sigqueue(2)](https://man.freebsd.org/cgi/man.cgi?sigqueue)
+///
+/// The receiver reads the value with [`wait_for_queued_signal`], e.g. a small
+/// command code from a parent to its workers. Unlike plain signals, queued
+/// realtime signals aren't merged when several are pending.
+///
+/// Example:
+///
+///```no_run
+///use fork::{queue_signal, wait_for_queued_signal, Signal};
+///
+///const REOPEN_LOGS: i32 = 1;
+///const STOP: i32 = 2;
+///
+///# let worker = 0;
+///// in the parent
+///queue_signal(worker, Signal::SIGUSR1, REOPEN_LOGS).expect("failed to queue");
+///
+///// in the worker, SIGUSR1 stays blocked between the calls
+///loop {
+///    match wait_for_queued_signal(&[Signal::SIGUSR1]) {
+///        Ok((_, REOPEN_LOGS)) => println!("reopening logs"),
+///        Ok((_, STOP)) | Err(_) => break,
+///        Ok(_) => {}
+///    }
+///}
+///```
+///
+/// # Errors
+/// returns `-1` if the signal can't be queued
+#[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "netbsd"))]
+pub fn queue_signal(pid: libc::pid_t, signal: Signal, value: i32) -> Result<(), i32> {
+    // the int member of the union overlaps the pointer, read back the same way
+    let value = libc::sigval {
+        sival_ptr: value as isize as *mut libc::c_void,
+    };
+    match unsafe { libc::sigqueue(pid, signal.0, value) } {
+        -1 => Err(-1),
+        _ => Ok(()),
+    }
+}
+
+/// Block the calling thread until one of `signals` is delivered and return it
+/// with the value sent by [`queue_signal`] [see sigwaitinfo(2)](https://man.freebsd.org/cgi/man.cgi?sigwaitinfo)
+///
+/// The signals stay blocked after returning as with [`wait_for_signals`]. The
+/// value of a signal sent with `kill` is unspecified.
+///
+/// # Errors
+/// returns `-1` if the signals can't be blocked or waited on
+#[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "netbsd"))]
+pub fn wait_for_queued_signal(signals: &[Signal]) -> Result<(Signal, i32), i32> {
+    let set = SignalSet::try_from(signals)?;
+    set.block()?;
+
+    let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+    loop {
+        match unsafe { libc::sigwaitinfo(set.as_raw(), &mut info) } {
+            -1 if io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) => {}
+            -1 => return Err(-1),
+            signum => {
+                let value = unsafe { info.si_value() }.sival_ptr as isize as i32;
+                return Ok((Signal(signum), value));
+            }
+        }
+    }
+}
+
+const extern "C" fn noop_handler(_: libc::c_int) {}
+
+/// Reap children as they exit, calling `callback` with the pid and status of
+/// each one
+///
+/// `SIGCHLD` gets a no-op handler, so that it is reliably delivered on every
+/// platform, and is blocked in the calling thread which then parks in `sigwait`
+/// between rounds of `waitpid(-1, WNOHANG)`. No exit is lost: a child exiting
+/// while others are being reaped leaves `SIGCHLD` pending for the next round.
+///
+/// The loop returns once `callback` returns `false` or when there are no
+/// children left to wait for.
+///
+/// Example:
+///
+///```
+///use fork::{fork, run_reaper, Fork};
+///use std::process::exit;
+///
+///for code in 0..3 {
+///    if let Ok(Fork::Child) = fork() {
+///        exit(code);
+///    }
+///}
+///
+///run_reaper(|pid, status| {
+///    println!("child {pid} exited: {status:?}");
+///    true
+///})
+///.expect("reaper failed");
+///```
+///
+/// # Errors
+/// returns `-1` if the signal setup, `sigwait` or `waitpid` fails
+pub fn run_reaper<F>(mut callback: F) -> Result<(), i32>
+where
+    F: FnMut(libc::pid_t, WaitStatus) -> bool,
+{
+    let set = SignalSet::new().add(Signal::SIGCHLD);
+    set.block()?;
+
+    let mut action = unsafe { std::mem::zeroed::<libc::sigaction>() };
+    action.sa_sigaction = noop_handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART | libc::SA_NOCLDSTOP;
+    if unsafe { libc::sigaction(libc::SIGCHLD, &action, std::ptr::null_mut()) } == -1 {
+        return Err(-1);
+    }
+
+    loop {
+        loop {
+            let mut status: libc::c_int = 0;
+            match unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) } {
+                -1 => {
+                    return match io::Error::last_os_error().raw_os_error() {
+                        Some(libc::ECHILD) => Ok(()),
+                        Some(libc::EINTR) => continue,
+                        _ => Err(-1),
+                    }
+                }
+                0 => break,
+                pid => {
+                    if !callback(pid, WaitStatus::from_raw(status)) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        set.wait()?;
+    }
+}
+
+/// Reap every child on a dedicated thread, calling `callback` with the pid and
+/// status of each one
+///
+/// For applications that spawn commands and don't wait on all of them, which
+/// otherwise pile up as zombies. `SIGCHLD` is blocked in the calling thread,
+/// so threads it starts later inherit the blocked mask and the signal reaches
+/// the reaper. Call it early, before other threads exist. The reaper blocks in
+/// `waitpid(-1)` while there are children and in `sigwait` while there are
+/// none. Since it reaps every child, [`Child::wait`](std::process::Child::wait)
+/// and [`waitpid`] fail for children started afterwards.
+///
+/// The thread ends once `callback` returns `false`.
+///
+/// Example:
+///
+///```no_run
+///use fork::spawn_reaper_thread;
+///use std::process::Command;
+///
+///spawn_reaper_thread(|pid, status| {
+///    eprintln!("child {pid} exited: {status:?}");
+///    true
+///})
+///.expect("failed to start reaper");
+///// never waited on, but doesn't stay a zombie
+///Command::new("true").spawn().expect("failed to spawn");
+///```
+///
+/// # Errors
+/// returns `-1` if the signal setup or starting the thread fails, the thread
+/// returns `-1` if `sigwait` or `waitpid` fails
+pub fn spawn_reaper_thread<F>(mut callback: F) -> Result<thread::JoinHandle<Result<(), i32>>, i32>
+where
+    F: FnMut(libc::pid_t, WaitStatus) -> bool + Send + 'static,
+{
+    let set = SignalSet::new().add(Signal::SIGCHLD);
+    set.block()?;
+
+    let mut action = unsafe { std::mem::zeroed::<libc::sigaction>() };
+    action.sa_sigaction = noop_handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART | libc::SA_NOCLDSTOP;
+    if unsafe { libc::sigaction(libc::SIGCHLD, &action, std::ptr::null_mut()) } == -1 {
+        return Err(-1);
+    }
+
+    let reaper = move || loop {
+        let mut status: libc::c_int = 0;
+        match unsafe { libc::waitpid(-1, &mut status, 0) } {
+            -1 => match io::Error::last_os_error().raw_os_error() {
+                Some(libc::EINTR) => {}
+                Some(libc::ECHILD) => {
+                    set.wait()?;
+                }
+                _ => return Err(-1),
+            },
+            pid => {
+                if !callback(pid, WaitStatus::from_raw(status)) {
+                    return Ok(());
+                }
+            }
+        }
+    };
+    thread::Builder::new()
+        .name("reaper".into())
+        .spawn(reaper)
+        .map_err(|_| -1)
+}
+
+/// Adopt the orphaned descendants of the calling process instead of init
+///
+/// Daemons started by a supervisor usually fork away, after which their parent
+/// exits and they would be reparented to init. A reaper gets them instead and
+/// can wait for and signal them, see [`run_reaper`]. Uses
+/// `prctl(PR_SET_CHILD_SUBREAPER)` on Linux and `procctl(PROC_REAP_ACQUIRE)`
+/// on FreeBSD [see procctl(2)](https://man.freebsd.org/cgi/man.cgi?procctl).
+///
+/// Example:
+///
+///```
+///use fork::{become_reaper, children_of, getpid};
+///use std::process::Command;
+///
+///become_reaper().expect("failed to become reaper");
+///Command::new("sh").arg("-c").arg("sleep 1 &").status().expect("failed to run sh");
+///// the orphaned sleep is now our child
+///assert_eq!(children_of(getpid()).map(|children| children.len()), Ok(1));
+///```
+///
+/// # Errors
+/// returns `-1` if error
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub fn become_reaper() -> Result<(), i32> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let res = unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1) };
+    #[cfg(target_os = "freebsd")]
+    let res = unsafe {
+        libc::procctl(
+            libc::P_PID,
+            getpid() as libc::id_t,
+            libc::PROC_REAP_ACQUIRE,
+            std::ptr::null_mut(),
+        )
+    };
+    match res {
+        -1 => Err(-1),
+        _ => Ok(()),
+    }
+}
+
+/// Notice in a child when its parent exits, without `PR_SET_PDEATHSIG`
+///
+/// Created before `fork`, it is a pipe whose write end only the parent keeps
+/// open. The child watches the read end on a thread and runs a callback once
+/// it reads end of file, which happens when the parent exits or drops its
+/// [`ParentAlive`]. Other children forked while the parent holds it also hold
+/// the write end and delay the notice until they exit as well. Both ends are
+/// close-on-exec.
+///
+/// Example:
+///
+///```no_run
+///use fork::{fork, Fork, ParentWatch};
+///
+///let watch = ParentWatch::new().expect("failed to create pipe");
+///match fork() {
+///    Ok(Fork::Parent(_)) => {
+///        let _alive = watch.parent();
+///        // run, the child exits with us
+///    }
+///    Ok(Fork::Child) => {
+///        watch
+///            .child(|| std::process::exit(1))
+///            .expect("failed to watch parent");
+///        // work
+///    }
+///    Err(_) => panic!("fork failed"),
+///}
+///```
+#[derive(Debug)]
+pub struct ParentWatch {
+    read: File,
+    write: File,
+}
+
+/// The parent's side of a [`ParentWatch`], dropping it counts as exiting
+#[derive(Debug)]
+pub struct ParentAlive {
+    _write: File,
+}
+
+impl ParentWatch {
+    /// Create the pipe, call before `fork`
+    ///
+    /// # Errors
+    /// returns `-1` if the pipe can't be created
+    pub fn new() -> Result<Self, i32> {
+        let [read, write] = cloexec_pipe()?;
+        Ok(unsafe {
+            Self {
+                read: File::from_raw_fd(read),
+                write: File::from_raw_fd(write),
+            }
+        })
+    }
+
+    /// Keep the write end in the parent, hold the result as long as it runs
+    #[must_use = "dropping it notifies the child"]
+    pub fn parent(self) -> ParentAlive {
+        ParentAlive { _write: self.write }
+    }
+
+    /// Close the write end in the child and call `on_parent_exit` on a
+    /// background thread once the parent is gone
+    ///
+    /// # Errors
+    /// returns `-1` if the thread can't be started
+    pub fn child<F>(self, on_parent_exit: F) -> Result<thread::JoinHandle<()>, i32>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        drop(self.write);
+        let read = self.read;
+        thread::Builder::new()
+            .name("parent-watch".into())
+            .spawn(move || {
+                let mut buf = [0u8; 1];
+                loop {
+                    match unsafe { libc::read(read.as_raw_fd(), buf.as_mut_ptr().cast(), 1) } {
+                        -1 if io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) => {}
+                        // only end of file or an error, nothing is ever written
+                        _ => break,
+                    }
+                }
+                on_parent_exit();
+            })
+            .map_err(|_| -1)
+    }
+}
+
+/// Signals [`pid1_mode`] passes on to its child
+const FORWARDED_SIGNALS: [libc::c_int; 7] = [
+    libc::SIGHUP,
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTERM,
+    libc::SIGUSR1,
+    libc::SIGUSR2,
+    libc::SIGWINCH,
+];
+
+/// Whether the calling process is PID 1, usually the entry point of a container
+#[must_use]
+pub fn is_pid1() -> bool {
+    getpid() == 1
+}
+
+/// Run `cmd` under a minimal init and return its status once it exits
+///
+/// Meant for a service started as PID 1 in a container, where daemonizing
+/// would detach it from the container's lifetime and where nobody else reaps
+/// orphans. The calling process stays in the foreground, forwards `SIGHUP`,
+/// `SIGINT`, `SIGQUIT`, `SIGTERM`, `SIGUSR1`, `SIGUSR2` and `SIGWINCH` to the
+/// child, and reaps every process that gets reparented to it. The command
+/// starts with the signal mask of the caller, the forwarded signals and
+/// `SIGCHLD` stay blocked in the calling thread after returning.
+///
+/// Example:
+///
+///```no_run
+///use fork::{is_pid1, pid1_mode, WaitStatus};
+///use std::process::{exit, Command};
+///
+///if is_pid1() {
+///    match pid1_mode(&mut Command::new("/usr/local/bin/server")) {
+///        Ok(WaitStatus::Exited(code)) => exit(code),
+///        Ok(WaitStatus::Signaled(signal)) => exit(128 + signal.as_raw()),
+///        _ => exit(1),
+///    }
+///}
+///```
+///
+/// # Errors
+/// returns `-1` if the signal setup, spawning `cmd` or `waitpid` fails
+pub fn pid1_mode(cmd: &mut Command) -> Result<WaitStatus, i32> {
+    let set = FORWARDED_SIGNALS
+        .into_iter()
+        .map(Signal)
+        .chain([Signal::SIGCHLD])
+        .collect::<SignalSet>();
+    let previous = set.block()?;
+
+    let mut action = unsafe { std::mem::zeroed::<libc::sigaction>() };
+    action.sa_sigaction = noop_handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART | libc::SA_NOCLDSTOP;
+    if unsafe { libc::sigaction(libc::SIGCHLD, &action, std::ptr::null_mut()) } == -1 {
+        return Err(-1);
+    }
+
+    unsafe {
+        cmd.pre_exec(move || {
+            match libc::pthread_sigmask(libc::SIG_SETMASK, previous.as_raw(), std::ptr::null_mut())
+            {
+                0 => Ok(()),
+                e => Err(io::Error::from_raw_os_error(e)),
+            }
+        })
+    };
+    let child = cmd.spawn().map_err(|_| -1)?;
+    let pid = libc::pid_t::try_from(child.id()).map_err(|_| -1)?;
+
+    loop {
+        loop {
+            let mut status: libc::c_int = 0;
+            match unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) } {
+                -1 => match io::Error::last_os_error().raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    _ => return Err(-1),
+                },
+                0 => break,
+                reaped if reaped == pid => return Ok(WaitStatus::from_raw(status)),
+                // an orphan
+                _ => {}
+            }
+        }
+
+        match set.wait()? {
+            Signal::SIGCHLD => {}
+            signal => unsafe {
+                libc::kill(pid, signal.0);
+            },
+        }
+    }
+}
+
+/// A resource whose limit can be set with [`setrlimit`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resource {
+    /// Size of core files, `RLIMIT_CORE`
+    Core,
+    /// CPU time in seconds, `RLIMIT_CPU`
+    Cpu,
+    /// Size of the data segment, `RLIMIT_DATA`
+    Data,
+    /// Size of created files, `RLIMIT_FSIZE`
+    FileSize,
+    /// Number of open files, `RLIMIT_NOFILE`
+    NoFile,
+    /// Number of processes of the user, `RLIMIT_NPROC`
+    NProc,
+    /// Size of the stack, `RLIMIT_STACK`
+    Stack,
+    /// Size of the address space, `RLIMIT_AS`
+    AddressSpace,
+}
+
+/// Set the soft and hard limit of a resource [see setrlimit(2)](https://man.freebsd.org/cgi/man.cgi?setrlimit)
+///
+/// # Errors
+/// returns `-1` if error
+pub fn setrlimit(resource: Resource, soft: libc::rlim_t, hard: libc::rlim_t) -> Result<(), i32> {
+    let limit = libc::rlimit {
+        rlim_cur: soft,
+        rlim_max: hard,
+    };
+    let res = unsafe {
+        match resource {
+            Resource::Core => libc::setrlimit(libc::RLIMIT_CORE, &limit),
+            Resource::Cpu => libc::setrlimit(libc::RLIMIT_CPU, &limit),
+            Resource::Data => libc::setrlimit(libc::RLIMIT_DATA, &limit),
+            Resource::FileSize => libc::setrlimit(libc::RLIMIT_FSIZE, &limit),
+            Resource::NoFile => libc::setrlimit(libc::RLIMIT_NOFILE, &limit),
+            Resource::NProc => libc::setrlimit(libc::RLIMIT_NPROC, &limit),
+            Resource::Stack => libc::setrlimit(libc::RLIMIT_STACK, &limit),
+            Resource::AddressSpace => libc::setrlimit(libc::RLIMIT_AS, &limit),
+        }
+    };
+    match res {
+        -1 => Err(-1),
+        _ => Ok(()),
+    }
+}
+
+/// `OPEN_MAX` from `<sys/syslimits.h>`, macOS rejects higher soft limits
+#[cfg(target_os = "macos")]
+const OPEN_MAX: libc::rlim_t = 10240;
+
+/// Raise the soft limit of open file descriptors towards `target`
+/// [see setrlimit(2)](https://man.freebsd.org/cgi/man.cgi?setrlimit)
+///
+/// The soft limit is bumped up to `target`, capped at the hard limit, and the
+/// achieved soft limit is returned. The limit is never lowered, if it is already
+/// at or above `target` it is left untouched.
+///
+/// Example:
+///
+///```
+///use fork::raise_nofile_limit;
+///
+///let limit = raise_nofile_limit(4096).expect("failed to raise RLIMIT_NOFILE");
+///println!("can open up to {limit} files");
+///```
+///
+/// # Errors
+/// returns `-1` if the limits can't be read or set
+pub fn raise_nofile_limit(target: libc::rlim_t) -> Result<libc::rlim_t, i32> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == -1 {
+        return Err(-1);
+    }
+    if limit.rlim_cur >= target {
+        return Ok(limit.rlim_cur);
+    }
+
+    limit.rlim_cur = target.min(limit.rlim_max);
+    #[cfg(target_os = "macos")]
+    {
+        limit.rlim_cur = limit.rlim_cur.min(OPEN_MAX);
+    }
+
+    match unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } {
+        -1 => Err(-1),
+        _ => Ok(limit.rlim_cur),
+    }
+}
+
+/// Resource limits for commands started by this crate or [`Command`]
+///
+/// The limits are set in the child right before `exec`, so the kernel enforces
+/// them without the parent having to poll, and the parent keeps its own limits.
+///
+/// Example:
+///
+///```
+///use fork::{spawn_with_timeout, ChildLimits, Signal, WaitStatus};
+///use std::process::Command;
+///use std::time::Duration;
+///
+///let mut cmd = Command::new("sh");
+///cmd.arg("-c").arg("while :; do :; done");
+///let status = spawn_with_timeout(ChildLimits::new().cpu(1).apply(&mut cmd), Duration::from_secs(30));
+///assert_eq!(status, Ok(WaitStatus::Signaled(Signal::SIGXCPU)));
+///```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChildLimits {
+    limits: Vec<(Resource, libc::rlim_t, libc::rlim_t)>,
+}
+
+impl ChildLimits {
+    /// No limits, the child inherits those of the parent
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit CPU time to `seconds`, `RLIMIT_CPU`
+    ///
+    /// The child receives `SIGXCPU` once it used `seconds` of CPU time and
+    /// `SIGKILL` one second later.
+    #[must_use]
+    pub fn cpu(self, seconds: libc::rlim_t) -> Self {
+        self.limit(Resource::Cpu, seconds, seconds.saturating_add(1))
+    }
+
+    /// Limit the address space to `bytes`, `RLIMIT_AS`
+    #[must_use]
+    pub fn address_space(self, bytes: libc::rlim_t) -> Self {
+        self.limit(Resource::AddressSpace, bytes, bytes)
+    }
+
+    /// Limit the data segment to `bytes`, `RLIMIT_DATA`
+    #[must_use]
+    pub fn data(self, bytes: libc::rlim_t) -> Self {
+        self.limit(Resource::Data, bytes, bytes)
+    }
+
+    /// Set the soft and hard limit of any resource, see [`setrlimit`]
+    #[must_use]
+    pub fn limit(mut self, resource: Resource, soft: libc::rlim_t, hard: libc::rlim_t) -> Self {
+        self.limits.push((resource, soft, hard));
+        self
+    }
+
+    /// Set the limits in `cmd` before it runs, with a `pre_exec` hook
+    ///
+    /// Works with [`spawn_detached`], [`spawn_with_timeout`] and
+    /// [`Daemon::exec`], spawning `cmd` fails if a limit can't be set.
+    pub fn apply<'a>(&self, cmd: &'a mut Command) -> &'a mut Command {
+        let limits = self.limits.clone();
+        unsafe {
+            cmd.pre_exec(move || {
+                for &(resource, soft, hard) in &limits {
+                    setrlimit(resource, soft, hard).map_err(|_| io::Error::last_os_error())?;
+                }
+                Ok(())
+            })
+        }
+    }
+}
+
+/// User, primary group and supplementary groups resolved once, to switch to
+/// in many children without looking them up again
+///
+/// Looking up users and groups after `fork` in a multithreaded parent can
+/// deadlock on locks held by other threads of the parent, and asks NSS modules
+/// and remote directories on every spawn. Resolve the user in the parent and
+/// clone it into each child, cloning doesn't allocate.
+///
+/// Example:
+///
+///```no_run
+///use fork::{spawn_worker, ResolvedUser, WorkerId};
+///
+///let nobody = ResolvedUser::resolve("nobody").expect("no such user");
+///for index in 0..4 {
+///    let user = nobody.clone();
+///    spawn_worker(WorkerId { index, generation: 0 }, move || {
+///        if user.switch().is_err() {
+///            return 1;
+///        }
+///        // serve requests as nobody
+///        0
+///    })
+///    .expect("failed to spawn worker");
+///}
+///```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolvedUser(Arc<Credentials>);
+
+impl ResolvedUser {
+    /// Look up `name`, its primary group and the groups it is a member of in
+    /// the system's user database
+    ///
+    /// # Errors
+    /// returns `-1` if the user doesn't exist or its groups can't be listed
+    pub fn resolve(name: &str) -> Result<Self, i32> {
+        Self::resolve_with(name, &NssResolver)
+    }
+
+    /// Look up `name` with `resolver`, see [`Daemon::resolver`]
+    ///
+    /// # Errors
+    /// returns `-1` if the user doesn't exist or its groups can't be listed
+    pub fn resolve_with<R: Resolver + ?Sized>(name: &str, resolver: &R) -> Result<Self, i32> {
+        let (uid, gid) = resolver.user(name)?;
+        Ok(Self::new(uid, gid, resolver.groups(name, gid)?))
+    }
+
+    /// The user `uid` with primary group `gid` and supplementary `groups`,
+    /// without any lookup
+    #[must_use]
+    pub fn new(uid: libc::uid_t, gid: libc::gid_t, groups: Vec<libc::gid_t>) -> Self {
+        Self(Arc::new(Credentials {
+            user: Some((uid, groups)),
+            gid,
+        }))
+    }
+
+    /// User ID
+    #[must_use]
+    pub fn uid(&self) -> libc::uid_t {
+        self.0.user.as_ref().map_or(0, |(uid, _)| *uid)
+    }
+
+    /// Primary group ID
+    #[must_use]
+    pub fn gid(&self) -> libc::gid_t {
+        self.0.gid
+    }
+
+    /// Supplementary groups
+    #[must_use]
+    pub fn groups(&self) -> &[libc::gid_t] {
+        self.0.user.as_ref().map_or(&[], |(_, groups)| groups)
+    }
+
+    /// Switch the calling process to the user, its groups and supplementary
+    /// groups, and verify that root can't be regained
+    ///
+    /// Only makes system calls, safe to call right after `fork`.
+    ///
+    /// # Errors
+    /// returns `-1` if the ids can't be set or don't read back as expected
+    pub fn switch(&self) -> Result<(), i32> {
+        self.0.apply()
+    }
+
+    /// Switch to the user in `cmd` before it runs, with a `pre_exec` hook
+    pub fn apply<'a>(&self, cmd: &'a mut Command) -> &'a mut Command {
+        let credentials = Arc::clone(&self.0);
+        unsafe { cmd.pre_exec(move || credentials.apply().map_err(|_| io::Error::last_os_error())) }
+    }
+}
+
+/// Whose resource usage [`resource_usage`] reports
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Who {
+    /// The calling process, `RUSAGE_SELF`
+    Self_,
+    /// Terminated children that have been waited for, and their waited-for
+    /// descendants, `RUSAGE_CHILDREN`
+    Children,
+}
+
+/// Resource usage returned by [`resource_usage`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// Time spent in user mode
+    pub user_time: Duration,
+    /// Time spent in the kernel
+    pub system_time: Duration,
+    /// Largest resident set size in bytes, for [`Who::Children`] the largest
+    /// of a single child
+    pub max_rss: u64,
+    /// Page faults served without I/O
+    pub minor_faults: u64,
+    /// Page faults that required I/O
+    pub major_faults: u64,
+    /// Context switches because the process waited for a resource
+    pub voluntary_switches: u64,
+    /// Context switches because the time slice ran out or a higher priority
+    /// process became runnable
+    pub involuntary_switches: u64,
+}
+
+/// Resource usage of the calling process or of its reaped children [see getrusage(2)](https://man.freebsd.org/cgi/man.cgi?getrusage)
+///
+/// Example:
+///
+///```
+///use fork::{resource_usage, Who};
+///use std::process::Command;
+///
+///Command::new("true").status().expect("failed to run true");
+///let usage = resource_usage(Who::Children).expect("failed to get usage");
+///println!("children used {:?} of CPU", usage.user_time + usage.system_time);
+///```
+///
+/// # Errors
+/// returns `-1` if error
+pub fn resource_usage(who: Who) -> Result<ResourceUsage, i32> {
+    let who = match who {
+        Who::Self_ => libc::RUSAGE_SELF,
+        Who::Children => libc::RUSAGE_CHILDREN,
+    };
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    if unsafe { libc::getrusage(who, &mut usage) } == -1 {
+        return Err(-1);
+    }
+    let time = |tv: libc::timeval| {
+        Duration::new(
+            u64::try_from(tv.tv_sec).unwrap_or(0),
+            u32::try_from(tv.tv_usec).unwrap_or(0) * 1000,
+        )
+    };
+    let count = |n: libc::c_long| u64::try_from(n).unwrap_or(0);
+    // kilobytes everywhere but on Apple systems
+    let rss_unit = if cfg!(target_vendor = "apple") {
+        1
+    } else {
+        1024
+    };
+    Ok(ResourceUsage {
+        user_time: time(usage.ru_utime),
+        system_time: time(usage.ru_stime),
+        max_rss: count(usage.ru_maxrss) * rss_unit,
+        minor_faults: count(usage.ru_minflt),
+        major_faults: count(usage.ru_majflt),
+        voluntary_switches: count(usage.ru_nvcsw),
+        involuntary_switches: count(usage.ru_nivcsw),
+    })
+}
+
+/// Kind of lock taken by [`FileLock`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockMode {
+    /// Any number of holders, excludes exclusive locks
+    Shared,
+    /// A single holder
+    Exclusive,
+}
+
+/// System call used by [`FileLock`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockMethod {
+    /// Locks belong to the open file and are inherited across `fork`
+    /// [see flock(2)](https://man.freebsd.org/cgi/man.cgi?flock)
+    Flock,
+    /// POSIX record locks on the whole file, they belong to the process and are
+    /// not inherited by children, and work on NFS
+    /// [see fcntl(2)](https://man.freebsd.org/cgi/man.cgi?fcntl)
+    Fcntl,
+}
+
+/// Advisory lock on a file, released when dropped
+///
+/// Serializes access to state directories and other resources between
+/// processes. The lock file is created if needed and never removed, since
+/// removing it would let a new process lock a different file while the old one
+/// is still held.
+///
+/// With [`LockMethod::Fcntl`] closing any descriptor of the file in the process
+/// releases the lock, don't open the lock file elsewhere.
+///
+/// Example:
+///
+///```
+///use fork::{FileLock, LockMode};
+///use std::time::Duration;
+///
+///let path = std::env::temp_dir().join("fork-state.lock");
+///let lock = FileLock::options(&path).try_lock().expect("failed to lock");
+///assert!(lock.is_some());
+///
+///// held above, so this gives up after the timeout
+///let shared = FileLock::options(&path)
+///    .mode(LockMode::Shared)
+///    .lock_timeout(Duration::from_millis(50))
+///    .expect("failed to lock");
+///assert!(shared.is_none());
+///```
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+    mode: LockMode,
+}
+
+/// Options to acquire a [`FileLock`], created with [`FileLock::options`]
+#[derive(Clone, Debug)]
+pub struct FileLockOptions {
+    path: PathBuf,
+    mode: LockMode,
+    method: LockMethod,
+}
+
+impl FileLock {
+    /// Lock `path` exclusively with `flock` unless configured otherwise
+    pub fn options<P: Into<PathBuf>>(path: P) -> FileLockOptions {
+        FileLockOptions {
+            path: path.into(),
+            mode: LockMode::Exclusive,
+            method: LockMethod::Flock,
+        }
+    }
+
+    /// Mode the lock was taken with
+    #[must_use]
+    pub const fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// The locked file
+    #[must_use]
+    pub const fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl AsRawFd for FileLock {
+    fn as_raw_fd(&self) -> libc::c_int {
+        self.file.as_raw_fd()
+    }
+}
+
+impl FileLockOptions {
+    /// Take a shared or an exclusive lock, the default is exclusive
+    #[must_use]
+    pub const fn mode(mut self, mode: LockMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Lock with `flock` or `fcntl`, the default is `flock`
+    #[must_use]
+    pub const fn method(mut self, method: LockMethod) -> Self {
+        self.method = method;
+        self
+    }
+
+    /// Wait until the lock is acquired
+    ///
+    /// # Errors
+    /// returns `-1` if the lock file can't be opened or locked
+    pub fn lock(&self) -> Result<FileLock, i32> {
+        let file = self.open()?;
+        loop {
+            match self.acquire(&file, true) {
+                Ok(true) => return Ok(self.held(file)),
+                Err(libc::EINTR) => {}
+                _ => return Err(-1),
+            }
+        }
+    }
+
+    /// Acquire the lock if nobody else holds a conflicting one, `None` otherwise
+    ///
+    /// # Errors
+    /// returns `-1` if the lock file can't be opened or locked
+    pub fn try_lock(&self) -> Result<Option<FileLock>, i32> {
+        let file = self.open()?;
+        match self.acquire(&file, false) {
+            Ok(true) => Ok(Some(self.held(file))),
+            Ok(false) => Ok(None),
+            Err(_) => Err(-1),
+        }
+    }
+
+    /// Retry for up to `timeout`, `None` if the lock is still held elsewhere
+    ///
+    /// # Errors
+    /// returns `-1` if the lock file can't be opened or locked
+    pub fn lock_timeout(&self, timeout: Duration) -> Result<Option<FileLock>, i32> {
+        let deadline = Instant::now() + timeout;
+        let file = self.open()?;
+        loop {
+            match self.acquire(&file, false) {
+                Ok(true) => return Ok(Some(self.held(file))),
+                Ok(false) if Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Ok(false) => return Ok(None),
+                Err(_) => return Err(-1),
+            }
+        }
+    }
+
+    fn open(&self) -> Result<File, i32> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(&self.path)
+            .map_err(|_| -1)
+    }
+
+    const fn held(&self, file: File) -> FileLock {
+        FileLock {
+            file,
+            mode: self.mode,
+        }
+    }
+
+    /// `Ok(false)` if the lock is held elsewhere, `Err(errno)` on failure
+    fn acquire(&self, file: &File, wait: bool) -> Result<bool, i32> {
+        let fd = file.as_raw_fd();
+        let result = match self.method {
+            LockMethod::Flock => {
+                let operation = match self.mode {
+                    LockMode::Shared => libc::LOCK_SH,
+                    LockMode::Exclusive => libc::LOCK_EX,
+                };
+                let operation = if wait {
+                    operation
+                } else {
+                    operation | libc::LOCK_NB
+                };
+                unsafe { libc::flock(fd, operation) }
+            }
+            LockMethod::Fcntl => {
+                let mut lock = unsafe { std::mem::zeroed::<libc::flock>() };
+                lock.l_type = match self.mode {
+                    LockMode::Shared => libc::F_RDLCK,
+                    LockMode::Exclusive => libc::F_WRLCK,
+                } as _;
+                lock.l_whence = libc::SEEK_SET as _;
+                let command = if wait { libc::F_SETLKW } else { libc::F_SETLK };
+                unsafe { libc::fcntl(fd, command, &lock) }
+            }
+        };
+        if result == 0 {
+            return Ok(true);
+        }
+        match io::Error::last_os_error().raw_os_error() {
+            Some(errno) if errno == libc::EWOULDBLOCK || errno == libc::EAGAIN => Ok(false),
+            // fcntl fails with EACCES instead on some systems
+            Some(libc::EACCES) if self.method == LockMethod::Fcntl => Ok(false),
+            errno => Err(errno.unwrap_or(-1)),
+        }
+    }
+}
+
+/// Outcome of [`run_once`]
+pub enum RunOnce {
+    /// The lock was acquired, the job runs in the child with this pid
+    Parent(libc::pid_t),
+    /// Another invocation still holds the lock, nothing was started
+    AlreadyRunning,
+}
+
+/// Run a job in a new child process unless another invocation holding the
+/// same lock file is still running [see flock(2)](https://man.freebsd.org/cgi/man.cgi?flock)
+///
+/// The lock file is created if needed and locked exclusively before forking.
+/// The child inherits the lock, runs `job` and exits with its return value, or
+/// 101 if it panics, the lock is released when the child exits. Overlapping cron-style invocations are
+/// skipped by returning `RunOnce::AlreadyRunning` instead of piling up.
+///
+/// The lock descriptor is opened with `O_CLOEXEC`, if the job runs a command it
+/// should wait for it so that the lock is held for the whole run.
+///
+/// Example:
+///
+///```
+///use fork::{run_once, waitpid, RunOnce};
+///use std::process::Command;
+///
+///match run_once("/tmp/fork-backup.lock", || {
+///    match Command::new("true").status() {
+///        Ok(status) if status.success() => 0,
+///        _ => 1,
+///    }
+///}) {
+///    Ok(RunOnce::Parent(pid)) => {
+///        waitpid(pid).expect("failed to wait on job");
+///    }
+///    Ok(RunOnce::AlreadyRunning) => println!("backup still running, skipping"),
+///    Err(_) => eprintln!("failed to start backup"),
+///}
+///```
+///
+/// # Errors
+/// returns `-1` if the lock file can't be opened or locked, or if `fork` fails
+pub fn run_once<P, F>(lock_path: P, job: F) -> Result<RunOnce, i32>
+where
+    P: AsRef<Path>,
+    F: FnOnce() -> i32,
+{
+    let Some(lock) = FileLock::options(lock_path.as_ref()).try_lock()? else {
+        return Ok(RunOnce::AlreadyRunning);
+    };
+
+    match fork()? {
+        Fork::Parent(child) => Ok(RunOnce::Parent(child)),
+        Fork::Child => exit_child(|| {
+            let code = job();
+            drop(lock);
+            code
+        }),
+    }
+}
+
+/// Run `f` in a forked child and leave the process with its return value
+///
+/// A panic in `f` exits with status 101 instead of unwinding into the code of
+/// the caller. std's stdout is flushed, then `_exit` skips `atexit` handlers
+/// and the stdio buffers of the C library copied from the parent.
+fn exit_child<F: FnOnce() -> i32>(f: F) -> ! {
+    let code = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or(101);
+    let _ = io::Write::flush(&mut io::stdout());
+    unsafe { libc::_exit(code) }
+}
+
+/// Handle to a process group created by [`spawn_group`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GroupHandle {
+    pgid: libc::pid_t,
+}
+
+impl GroupHandle {
+    /// Process group ID, also the pid of the group leader
+    #[must_use]
+    pub const fn pgid(&self) -> libc::pid_t {
+        self.pgid
+    }
+
+    /// Send `signal` to every process in the group [see killpg(2)](https://man.freebsd.org/cgi/man.cgi?killpg)
+    ///
+    /// # Errors
+    /// returns `-1` if error
+    pub fn kill_all(&self, signal: Signal) -> Result<(), i32> {
+        match unsafe { libc::killpg(self.pgid, signal.0) } {
+            -1 => Err(-1),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait until every member of the group that is a child of the caller has
+    /// exited, returning their pids and statuses
+    ///
+    /// Only the leader is a child of the process that called [`spawn_group`],
+    /// processes it forked are reaped by the leader itself.
+    ///
+    /// # Errors
+    /// returns `-1` if `waitpid` fails
+    pub fn wait_all(&self) -> Result<Vec<(libc::pid_t, WaitStatus)>, i32> {
+        let mut statuses = Vec::new();
+        loop {
+            let mut status: libc::c_int = 0;
+            match unsafe { libc::waitpid(-self.pgid, &mut status, 0) } {
+                -1 => {
+                    return match io::Error::last_os_error().raw_os_error() {
+                        Some(libc::ECHILD) => Ok(statuses),
+                        Some(libc::EINTR) => continue,
+                        _ => Err(-1),
+                    }
+                }
+                pid => statuses.push((pid, WaitStatus::from_raw(status))),
+            }
+        }
+    }
+}
+
+/// Fork a child that leads a new process group and runs `f`, exiting with its
+/// return value [see setpgid(2)](https://man.freebsd.org/cgi/man.cgi?setpgid)
+///
+/// Both the parent and the child call `setpgid`, so the group exists before
+/// either of them continues and a signal sent through the returned handle
+/// can't miss processes the leader forks early. Everything started by `f`
+/// stays in the group unless it moves itself, which lets the whole tree be
+/// terminated at once. A panic in `f` doesn't return into the caller, the
+/// leader exits with 101.
+///
+/// Example:
+///
+///```
+///use fork::{spawn_group, Signal, WaitStatus};
+///use std::process::Command;
+///
+///let group = spawn_group(|| {
+///    let _ = Command::new("sleep").arg("10").status();
+///    0
+///})
+///.expect("failed to spawn group");
+///
+///group.kill_all(Signal::SIGTERM).expect("failed to signal group");
+///let statuses = group.wait_all().expect("failed to wait on group");
+///assert_eq!(statuses, vec![(group.pgid(), WaitStatus::Signaled(Signal::SIGTERM))]);
+///```
+///
+/// # Errors
+/// returns `-1` if `fork` fails
+pub fn spawn_group<F: FnOnce() -> i32>(f: F) -> Result<GroupHandle, i32> {
+    match fork()? {
+        Fork::Child => {
+            unsafe { libc::setpgid(0, 0) };
+            exit_child(f)
+        }
+        Fork::Parent(child) => {
+            // fails harmlessly if the child already exec'd or exited
+            unsafe { libc::setpgid(child, child) };
+            Ok(GroupHandle { pgid: child })
+        }
+    }
+}
+
+/// Identity of a prefork worker started by [`spawn_worker`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WorkerId {
+    /// Slot of the worker in the pool, stays the same when it is replaced
+    pub index: u32,
+    /// How many times the slot was filled before, to tell replacements apart
+    pub generation: u32,
+}
+
+/// Index of the calling worker, `u64::MAX` outside of workers
+static WORKER_INDEX: AtomicU64 = AtomicU64::new(u64::MAX);
+static WORKER_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Identity of the calling process if it is a worker started by
+/// [`spawn_worker`], for attributing logs and metrics
+///
+/// Example:
+///
+///```
+///use fork::{spawn_worker, waitpid, worker_id, WorkerId};
+///
+///assert_eq!(worker_id(), None);
+///for index in 0..4 {
+///    let id = WorkerId { index, generation: 0 };
+///    let pid = spawn_worker(id, || {
+///        let id = worker_id().expect("not a worker");
+///        eprintln!("worker {}.{} ready", id.index, id.generation);
+///        0
+///    })
+///    .expect("failed to spawn worker");
+///    waitpid(pid).expect("failed to wait on worker");
+///}
+///```
+#[must_use]
+pub fn worker_id() -> Option<WorkerId> {
+    let index = WORKER_INDEX.load(Ordering::Relaxed);
+    Some(WorkerId {
+        index: u32::try_from(index).ok()?,
+        generation: u32::try_from(WORKER_GENERATION.load(Ordering::Relaxed)).ok()?,
+    })
+}
+
+/// Fork a worker that runs `f` as `id` and exits with its return value,
+/// returning its pid
+///
+/// The identity is set right after `fork` in the child, without allocating,
+/// and read there with [`worker_id`]. A panic in `f` doesn't return into the
+/// caller, the worker exits with 101.
+///
+/// # Errors
+/// returns `-1` if `fork` fails
+pub fn spawn_worker<F: FnOnce() -> i32>(id: WorkerId, f: F) -> Result<libc::pid_t, i32> {
+    match fork()? {
+        Fork::Parent(child) => Ok(child),
+        Fork::Child => {
+            WORKER_GENERATION.store(u64::from(id.generation), Ordering::Relaxed);
+            WORKER_INDEX.store(u64::from(id.index), Ordering::Relaxed);
+            exit_child(f)
+        }
+    }
+}
+
+/// Shut down prefork workers: ask them to finish with `signal`, wait up to
+/// `timeout` and kill the ones still running
+///
+/// Stop dispatching first, e.g. by closing the listening socket in the parent,
+/// so workers only finish in-flight work. Workers that exit in time are
+/// reported with their status, the killed ones as [`WaitStatus::TimedOut`].
+/// Every worker that is a child of the caller is reaped before returning.
+///
+/// Example:
+///
+///```
+///use fork::{drain_workers, spawn_worker, Signal, WaitStatus, WorkerId};
+///use std::time::Duration;
+///
+///let workers: Vec<_> = (0..2)
+///    .map(|index| {
+///        spawn_worker(WorkerId { index, generation: 0 }, || {
+///            std::thread::sleep(Duration::from_secs(10));
+///            0
+///        })
+///        .expect("failed to spawn worker")
+///    })
+///    .collect();
+///
+///let statuses = drain_workers(&workers, Signal::SIGTERM, Duration::from_secs(5));
+///for worker in workers {
+///    assert_eq!(statuses[&worker], WaitStatus::Signaled(Signal::SIGTERM));
+///}
+///```
+#[must_use]
+pub fn drain_workers(
+    workers: &[libc::pid_t],
+    signal: Signal,
+    timeout: Duration,
+) -> HashMap<libc::pid_t, WaitStatus> {
+    for &worker in workers {
+        unsafe { libc::kill(worker, signal.0) };
+    }
+    let mut statuses = wait_all(workers, timeout);
+    for &worker in workers {
+        if let hash_map::Entry::Vacant(entry) = statuses.entry(worker) {
+            unsafe { libc::kill(worker, libc::SIGKILL) };
+            if waitpid_status(worker).is_ok() {
+                entry.insert(WaitStatus::TimedOut);
+            }
+        }
+    }
+    statuses
+}
+
+/// Bind a listening TCP socket with `SO_REUSEPORT` set, so that every worker
+/// can bind the same address after `fork` [see setsockopt(2)](https://man.freebsd.org/cgi/man.cgi?setsockopt)
+///
+/// Instead of all workers accepting on one inherited socket, each gets its own
+/// and Linux spreads incoming connections over them evenly. A replaced worker
+/// calls it again. Other systems accept the option but may route connections
+/// to a single socket. The socket is close-on-exec.
+///
+/// Example:
+///
+///```no_run
+///use fork::{bind_reuseport, spawn_worker, WorkerId};
+///
+///for index in 0..4 {
+///    spawn_worker(WorkerId { index, generation: 0 }, || {
+///        let listener = bind_reuseport("0.0.0.0:8080".parse().unwrap(), 1024)
+///            .expect("failed to bind");
+///        for _stream in listener.incoming() {
+///            // serve
+///        }
+///        0
+///    })
+///    .expect("failed to spawn worker");
+///}
+///```
+///
+/// # Errors
+/// returns `-1` if the socket can't be created, configured, bound or put into
+/// listening state
+pub fn bind_reuseport(addr: SocketAddr, backlog: i32) -> Result<TcpListener, i32> {
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM, 0) };
+    if fd == -1 {
+        return Err(-1);
+    }
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(-1);
+    }
+    let on: libc::c_int = 1;
+    for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let set = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                std::ptr::from_ref(&on).cast(),
+                std::mem::size_of_val(&on) as libc::socklen_t,
+            )
+        };
+        if set == -1 {
+            return Err(-1);
+        }
+    }
+
+    let mut storage = unsafe { std::mem::zeroed::<libc::sockaddr_storage>() };
+    let len = match addr {
+        SocketAddr::V4(v4) => {
+            let sin = std::ptr::from_mut(&mut storage).cast::<libc::sockaddr_in>();
+            unsafe {
+                (*sin).sin_family = libc::AF_INET as libc::sa_family_t;
+                (*sin).sin_port = v4.port().to_be();
+                (*sin).sin_addr.s_addr = u32::from(*v4.ip()).to_be();
+            }
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(v6) => {
+            let sin6 = std::ptr::from_mut(&mut storage).cast::<libc::sockaddr_in6>();
+            unsafe {
+                (*sin6).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                (*sin6).sin6_port = v6.port().to_be();
+                (*sin6).sin6_addr.s6_addr = v6.ip().octets();
+                (*sin6).sin6_flowinfo = v6.flowinfo();
+                (*sin6).sin6_scope_id = v6.scope_id();
+            }
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    let bound = unsafe {
+        libc::bind(
+            fd,
+            std::ptr::from_ref(&storage).cast(),
+            len as libc::socklen_t,
+        )
+    };
+    if bound == -1 || unsafe { libc::listen(fd, backlog) } == -1 {
+        return Err(-1);
+    }
+    Ok(listener)
+}
+
+/// Time a child gets to exit after `SIGTERM` before it is sent `SIGKILL`
+const KILL_GRACE: Duration = Duration::from_secs(1);
+
+/// Spawn a command and kill it if it runs longer than `timeout`
+///
+/// The command is polled until it exits or the wall-clock budget is spent, in
+/// which case it receives `SIGTERM`, then `SIGKILL` if it is still around one
+/// second later. The child is always reaped before returning.
+///
+/// Example:
+///
+///```
+///use fork::{spawn_with_timeout, WaitStatus};
+///use std::process::Command;
+///use std::time::Duration;
+///
+///let status = spawn_with_timeout(Command::new("sleep").arg("10"), Duration::from_millis(100));
+///assert_eq!(status, Ok(WaitStatus::TimedOut));
+///```
+///
+/// # Errors
+/// returns `-1` if the command can't be spawned or waiting on it fails
+pub fn spawn_with_timeout(cmd: &mut Command, timeout: Duration) -> Result<WaitStatus, i32> {
+    let child = cmd.spawn().map_err(|_| -1)?;
+    let pid = libc::pid_t::try_from(child.id()).map_err(|_| -1)?;
+    let deadline = Instant::now() + timeout;
+
+    while Instant::now() < deadline {
+        if let Some(status) = try_waitpid(pid)? {
+            return Ok(status);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    unsafe { libc::kill(pid, libc::SIGTERM) };
+    let deadline = Instant::now() + KILL_GRACE;
+    while Instant::now() < deadline {
+        if try_waitpid(pid)?.is_some() {
+            return Ok(WaitStatus::TimedOut);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    unsafe { libc::kill(pid, libc::SIGKILL) };
+    waitpid_status(pid)?;
+    Ok(WaitStatus::TimedOut)
+}
+
+/// Run a command in its own process group and collect its output, killing the
+/// whole group if it runs longer than `timeout`
+///
+/// Like [`Command::output`] with stdin connected to `/dev/null`. At the
+/// deadline every process in the group gets `SIGKILL`, so a command killed for
+/// running too long reports that signal in its status. Output written before
+/// that is kept, and descendants that left the group get one more second to
+/// close the pipes before they are abandoned.
+///
+/// Example:
+///
+///```
+///use fork::run_with_timeout;
+///use std::os::unix::process::ExitStatusExt;
+///use std::process::Command;
+///use std::time::Duration;
+///
+///let mut cmd = Command::new("sh");
+///cmd.args(["-c", "echo started; sleep 10"]);
+///let output = run_with_timeout(&mut cmd, Duration::from_millis(200)).expect("failed to run");
+///assert_eq!(output.stdout, b"started\n");
+///assert_eq!(output.status.signal(), Some(libc::SIGKILL));
+///```
+///
+/// # Errors
+/// returns `-1` if the command can't be spawned, or if polling or waiting on it
+/// fails
+pub fn run_with_timeout(cmd: &mut Command, timeout: Duration) -> Result<Output, i32> {
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .map_err(|_| -1)?;
+    let pid = libc::pid_t::try_from(child.id()).map_err(|_| -1)?;
+    let mut pipes = [
+        child
+            .stdout
+            .take()
+            .map(|pipe| File::from(OwnedFd::from(pipe))),
+        child
+            .stderr
+            .take()
+            .map(|pipe| File::from(OwnedFd::from(pipe))),
+    ];
+    let mut output = [Vec::new(), Vec::new()];
+    let mut deadline = Instant::now() + timeout;
+    let mut killed = false;
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            if killed {
+                break;
+            }
+            unsafe { libc::kill(-pid, libc::SIGKILL) };
+            let _ = child.kill();
+            killed = true;
+            deadline = now + KILL_GRACE;
+            continue;
+        }
+        let mut fds: Vec<libc::pollfd> = pipes
+            .iter()
+            .flatten()
+            .map(|pipe| libc::pollfd {
+                fd: pipe.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        if fds.is_empty() {
+            if child.try_wait().map_err(|_| -1)?.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+            continue;
+        }
+        let wait = deadline.saturating_duration_since(now).as_millis();
+        let wait = libc::c_int::try_from(wait).unwrap_or(libc::c_int::MAX);
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, wait) } == -1 {
+            if io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) {
+                continue;
+            }
+            return Err(-1);
+        }
+        let mut ready = fds.iter().map(|fd| fd.revents != 0);
+        for (pipe, output) in pipes.iter_mut().zip(&mut output) {
+            let Some(file) = pipe else {
+                continue;
+            };
+            if !ready.next().unwrap_or(false) {
+                continue;
+            }
+            let mut buf = [0; 4096];
+            match file.read(&mut buf) {
+                Ok(0) => *pipe = None,
+                Ok(n) => output.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => *pipe = None,
+            }
+        }
+    }
+
+    let [stdout, stderr] = output;
+    Ok(Output {
+        status: child.wait().map_err(|_| -1)?,
+        stdout,
+        stderr,
+    })
+}
+
+/// Callback of [`Tee::format_lines`]
+type LineFormat = dyn FnMut(&mut Vec<u8>, &[u8]) + Send;
+
+/// Copy the output of children to a log file and to live viewers
+///
+/// [`Tee::pipe`] returns the write end of a pipe to hand to children and a
+/// forwarder thread that appends everything read from the pipe to the log
+/// file, which is created if missing, and writes it to every sink, like a
+/// socket streaming the output to a `tail -f`-style client. A sink that fails
+/// is dropped while the others carry on. With [`Tee::rotate`] the forwarder
+/// also rotates the log file, so simple daemons don't need `logrotate`, and
+/// with [`Tee::format_lines`] it decorates every line.
+///
+/// Example:
+///
+///```
+///use fork::Tee;
+///use std::io::Read;
+///use std::os::unix::net::UnixStream;
+///use std::process::Command;
+///
+///let log = std::env::temp_dir().join(format!("fork-tee-{}.log", std::process::id()));
+///let (viewer, mut live) = UnixStream::pair().expect("failed to create socket pair");
+///let (output, forwarder) = Tee::new(&log).sink(viewer).pipe().expect("failed to create pipe");
+///
+///let mut cmd = Command::new("echo");
+///cmd.arg("hello").stdout(output);
+///cmd.status().expect("failed to run echo");
+///// the forwarder finishes once every copy of the write end is closed
+///drop(cmd);
+///forwarder.join().expect("forwarder panicked").expect("failed to forward");
+///
+///let mut streamed = String::new();
+///live.read_to_string(&mut streamed).expect("failed to read");
+///assert_eq!(streamed, "hello\n");
+///assert_eq!(std::fs::read_to_string(&log).ok(), Some("hello\n".into()));
+///# std::fs::remove_file(&log).ok();
+///```
+pub struct Tee {
+    log: PathBuf,
+    sinks: Vec<Box<dyn io::Write + Send>>,
+    rotate: Option<(u64, usize)>,
+    format: Option<Box<LineFormat>>,
+}
+
+impl Tee {
+    /// Forward to the log file at `log`
+    #[must_use]
+    pub fn new<P: Into<PathBuf>>(log: P) -> Self {
+        Self {
+            log: log.into(),
+            sinks: Vec::new(),
+            rotate: None,
+            format: None,
+        }
+    }
+
+    /// Decorate every line before it is written, for example with a
+    /// timestamp, a name and a pid
+    ///
+    /// `format` gets the buffer to append the decorated line to and the line
+    /// without its newline, which is added back afterwards. A last line without
+    /// a newline is decorated when the pipe is closed.
+    ///
+    /// Example:
+    ///
+    ///```
+    ///use fork::{rfc3339, Tee};
+    ///use std::io::Write;
+    ///use std::sync::atomic::{AtomicI32, Ordering};
+    ///use std::sync::Arc;
+    ///use std::time::SystemTime;
+    ///
+    ///// the pid is only known once the child is spawned
+    ///let pid = Arc::new(AtomicI32::new(0));
+    ///let child_pid = Arc::clone(&pid);
+    ///let tee = Tee::new("/tmp/worker.log").format_lines(move |out, line| {
+    ///    let pid = child_pid.load(Ordering::Relaxed);
+    ///    let _ = write!(out, "{} worker[{pid}]: ", rfc3339(SystemTime::now()));
+    ///    out.extend_from_slice(line);
+    ///});
+    ///```
+    #[must_use]
+    pub fn format_lines<F>(mut self, format: F) -> Self
+    where
+        F: FnMut(&mut Vec<u8>, &[u8]) + Send + 'static,
+    {
+        self.format = Some(Box::new(format));
+        self
+    }
+
+    /// Rotate the log file before it grows beyond `max_size` bytes, keeping
+    /// `max_files` old logs as `<log>.1`, the most recent, to `<log>.<max_files>`
+    ///
+    /// Rotation renames the files and reopens the log, output is never split
+    /// across a rotation unless a single read is larger than `max_size`.
+    #[must_use]
+    pub const fn rotate(mut self, max_size: u64, max_files: usize) -> Self {
+        self.rotate = Some((max_size, max_files));
+        self
+    }
+
+    /// Also write the output to `sink`
+    #[must_use]
+    pub fn sink<W: io::Write + Send + 'static>(mut self, sink: W) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Open the log file and start forwarding, returning the write end of the
+    /// pipe and the forwarder thread
+    ///
+    /// The write end is close-on-exec until it is given to a child as stdout
+    /// or stderr, use [`File::try_clone`] to give it to both. The thread
+    /// returns once every copy of it is closed.
+    ///
+    /// # Errors
+    /// returns `-1` if the log file can't be opened, or if the pipe or the
+    /// thread can't be created
+    pub fn pipe(self) -> Result<(File, thread::JoinHandle<Result<(), i32>>), i32> {
+        let open = |path: &Path| {
+            OpenOptions::new()
+                .append(true)
+                .create(true)
+                .custom_flags(libc::O_CLOEXEC)
+                .open(path)
+                .map_err(|_| -1)
+        };
+        let mut log = open(&self.log)?;
+        let mut size = log.metadata().map_err(|_| -1)?.len();
+        let [read, write] = cloexec_pipe()?;
+        let (mut read, write) = unsafe { (File::from_raw_fd(read), File::from_raw_fd(write)) };
+        let (path, mut sinks, rotate, mut format) =
+            (self.log, self.sinks, self.rotate, self.format);
+        let forwarder = thread::Builder::new()
+            .name("fork-tee".into())
+            .spawn(move || {
+                let mut emit = |data: &[u8]| -> Result<(), i32> {
+                    if let Some((max_size, max_files)) = rotate {
+                        if size > 0 && size + data.len() as u64 > max_size {
+                            rotate_log(&path, max_files).map_err(|_| -1)?;
+                            log = open(&path)?;
+                            size = 0;
+                        }
+                    }
+                    io::Write::write_all(&mut log, data).map_err(|_| -1)?;
+                    size += data.len() as u64;
+                    sinks.retain_mut(|sink| sink.write_all(data).is_ok());
+                    Ok(())
+                };
+                let mut buf = [0; 8192];
+                let mut pending = Vec::new();
+                let mut decorated = Vec::new();
+                loop {
+                    let n = match read.read(&mut buf) {
+                        Ok(n) => n,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(_) => return Err(-1),
+                    };
+                    let Some(format) = &mut format else {
+                        if n == 0 {
+                            return Ok(());
+                        }
+                        emit(&buf[..n])?;
+                        continue;
+                    };
+                    pending.extend_from_slice(&buf[..n]);
+                    decorated.clear();
+                    let mut start = 0;
+                    while let Some(end) = pending[start..].iter().position(|&b| b == b'\n') {
+                        format(&mut decorated, &pending[start..start + end]);
+                        decorated.push(b'\n');
+                        start += end + 1;
+                    }
+                    pending.drain(..start);
+                    if n == 0 && !pending.is_empty() {
+                        format(&mut decorated, &pending);
+                        decorated.push(b'\n');
+                    }
+                    if !decorated.is_empty() {
+                        emit(&decorated)?;
+                    }
+                    if n == 0 {
+                        return Ok(());
+                    }
+                }
+            })
+            .map_err(|_| -1)?;
+        Ok((write, forwarder))
+    }
+}
+
+/// Format `time` as an RFC 3339 timestamp in UTC with milliseconds, like
+/// `2024-05-01T12:30:45.123Z`
+///
+/// Example:
+///
+///```
+///use fork::rfc3339;
+///use std::time::{Duration, UNIX_EPOCH};
+///
+///let time = UNIX_EPOCH + Duration::from_millis(1_714_566_645_123);
+///assert_eq!(rfc3339(time), "2024-05-01T12:30:45.123Z");
+///```
+#[must_use]
+pub fn rfc3339(time: std::time::SystemTime) -> String {
+    let since = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        since.subsec_millis()
+    )
+}
+
+/// Shift `path.1` to `path.2` and so on, dropping the oldest, and move `path`
+/// to `path.1`
+fn rotate_log(path: &Path, max_files: usize) -> io::Result<()> {
+    let numbered = |i: usize| {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(format!(".{i}"));
+        PathBuf::from(name)
+    };
+    if max_files == 0 {
+        return std::fs::remove_file(path);
+    }
+    for i in (1..max_files).rev() {
+        match std::fs::rename(numbered(i), numbered(i + 1)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    std::fs::rename(path, numbered(1))
+}
+
+impl std::fmt::Debug for Tee {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tee")
+            .field("log", &self.log)
+            .field("sinks", &self.sinks.len())
+            .field("rotate", &self.rotate)
+            .field("format", &self.format.is_some())
+            .finish()
+    }
+}
+
+/// `PATH` used by [`ExecEnv`] when it is neither inherited nor set
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// Environment for exec'd children, built from scratch instead of inheriting
+/// the operator's shell environment
+///
+/// Only the variables explicitly inherited or set are passed on. If `PATH`
+/// ends up unset the fallback is used, `/usr/local/bin:/usr/bin:/bin` unless
+/// changed with [`ExecEnv::path_fallback`].
+///
+/// Example:
+///
+///```
+///use fork::ExecEnv;
+///use std::process::Command;
+///
+///let env = ExecEnv::new().inherit("LANG").var("RUST_LOG", "info");
+///let output = env
+///    .apply(&mut Command::new("env"))
+///    .output()
+///    .expect("failed to run env");
+///let output = String::from_utf8_lossy(&output.stdout);
+///assert!(output.contains("RUST_LOG=info"));
+///assert!(!output.contains("HOME="));
+///```
+#[derive(Clone, Debug)]
+pub struct ExecEnv {
+    inherit: Vec<OsString>,
+    vars: Vec<(OsString, OsString)>,
+    path_fallback: Option<OsString>,
+}
+
+impl Default for ExecEnv {
+    fn default() -> Self {
+        Self {
+            inherit: Vec::new(),
+            vars: Vec::new(),
+            path_fallback: Some(DEFAULT_PATH.into()),
+        }
+    }
+}
+
+impl ExecEnv {
+    /// An empty environment, apart from the `PATH` fallback
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pass `key` through from the current environment if it is set
+    #[must_use]
+    pub fn inherit<K: AsRef<OsStr>>(mut self, key: K) -> Self {
+        self.inherit.push(key.as_ref().to_os_string());
+        self
+    }
+
+    /// Set `key` to `value`, taking precedence over an inherited value
+    #[must_use]
+    pub fn var<K: AsRef<OsStr>, V: AsRef<OsStr>>(mut self, key: K, value: V) -> Self {
+        self.vars
+            .push((key.as_ref().to_os_string(), value.as_ref().to_os_string()));
+        self
+    }
+
+    /// `PATH` to use when it is neither inherited nor set, `None` leaves it unset
+    #[must_use]
+    pub fn path_fallback<P: AsRef<OsStr>>(mut self, path: Option<P>) -> Self {
+        self.path_fallback = path.map(|path| path.as_ref().to_os_string());
+        self
+    }
+
+    /// The resolved environment
+    #[must_use]
+    pub fn vars(&self) -> Vec<(OsString, OsString)> {
+        let mut vars: Vec<(OsString, OsString)> = self
+            .inherit
+            .iter()
+            .filter_map(|key| env::var_os(key).map(|value| (key.clone(), value)))
+            .collect();
+        for (key, value) in &self.vars {
+            vars.retain(|(k, _)| k != key);
+            vars.push((key.clone(), value.clone()));
+        }
+        if let Some(path) = &self.path_fallback {
+            if !vars.iter().any(|(key, _)| key == "PATH") {
+                vars.push(("PATH".into(), path.clone()));
+            }
+        }
+        vars
+    }
+
+    /// Replace the environment of `cmd` with this one
+    pub fn apply<'a>(&self, cmd: &'a mut Command) -> &'a mut Command {
+        cmd.env_clear().envs(self.vars())
+    }
+}
+
+/// Where a standard stream of a detached command is connected
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum StdioTarget {
+    /// `/dev/null`
+    #[default]
+    Null,
+    /// A file opened in append mode, created if missing
+    Append(PathBuf),
+    /// A pipe, the other end is returned in [`DetachedChild`]
+    Pipe,
+    /// A duplicate of a descriptor the caller keeps open
+    Fd(libc::c_int),
+}
+
+/// Standard streams of a command started by [`spawn_detached`], all connected
+/// to `/dev/null` by default
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DetachedStdio {
+    pub stdin: StdioTarget,
+    pub stdout: StdioTarget,
+    pub stderr: StdioTarget,
+}
+
+/// A command started by [`spawn_detached`]
+///
+/// The streams wired to [`StdioTarget::Pipe`] hold the caller's end of the
+/// pipe, like the fields of [`std::process::Child`]. The command is not a child
+/// of the caller, so its exit status is only available to a caller that became
+/// a reaper before spawning it, see [`become_reaper`].
+#[derive(Debug)]
+pub struct DetachedChild {
+    pid: libc::pid_t,
+    exit: Option<DetachedExit>,
+    pub stdin: Option<File>,
+    pub stdout: Option<File>,
+    pub stderr: Option<File>,
+}
+
+/// How a [`DetachedChild`] ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DetachedExit {
+    /// The caller reaped the command, which was reparented to it
+    Status(WaitStatus),
+    /// The command was reaped by init, its exit status is lost
+    Unknown,
+}
+
+impl DetachedChild {
+    /// Process ID of the command
+    #[must_use]
+    pub const fn id(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    /// Send `SIGKILL` to the command, does nothing if it is known to have exited
+    ///
+    /// # Errors
+    /// returns `-1` if the signal can't be sent
+    pub fn kill(&mut self) -> Result<(), i32> {
+        if self.exit.is_some() {
+            return Ok(());
+        }
+        match unsafe { libc::kill(self.pid, libc::SIGKILL) } {
+            -1 if io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH) => Err(-1),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait for the command to exit, closing its stdin first
+    ///
+    /// # Errors
+    /// returns `-1` if the command can't be watched or reaped
+    pub fn wait(&mut self) -> Result<DetachedExit, i32> {
+        drop(self.stdin.take());
+        loop {
+            if let Some(exit) = self.try_wait()? {
+                return Ok(exit);
+            }
+            wait_for_exit(self.pid, None)?;
+        }
+    }
+
+    /// How the command ended, `None` while it is still running
+    ///
+    /// # Errors
+    /// returns `-1` if the command can't be reaped
+    pub fn try_wait(&mut self) -> Result<Option<DetachedExit>, i32> {
+        if self.exit.is_none() {
+            self.exit = match try_waitpid(self.pid) {
+                Ok(status) => status.map(DetachedExit::Status),
+                Err(_) if io::Error::last_os_error().raw_os_error() == Some(libc::ECHILD) => {
+                    exited(self.pid).then_some(DetachedExit::Unknown)
+                }
+                Err(_) => return Err(-1),
+            };
+        }
+        Ok(self.exit)
+    }
+}
+
+/// `pipe` with both ends close-on-exec
+fn cloexec_pipe() -> Result<[libc::c_int; 2], i32> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(-1);
+    }
+    for fd in fds {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            unsafe {
+                libc::close(fds[0]);
+                libc::close(fds[1]);
+            }
+            return Err(-1);
+        }
+    }
+    Ok(fds)
+}
+
+impl StdioTarget {
+    /// The command's side of the stream and, for pipes, the caller's side
+    fn open(&self, input: bool) -> Result<(Stdio, Option<File>), i32> {
+        match self {
+            Self::Null => Ok((Stdio::null(), None)),
+            Self::Append(path) => OpenOptions::new()
+                .append(true)
+                .create(true)
+                .custom_flags(libc::O_CLOEXEC)
+                .open(path)
+                .map(|file| (Stdio::from(file), None))
+                .map_err(|_| -1),
+            Self::Pipe => {
+                let [read, write] = cloexec_pipe()?;
+                let (read, write) = unsafe { (File::from_raw_fd(read), File::from_raw_fd(write)) };
+                Ok(if input {
+                    (Stdio::from(read), Some(write))
+                } else {
+                    (Stdio::from(write), Some(read))
+                })
+            }
+            Self::Fd(fd) => match unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, 0) } {
+                -1 => Err(-1),
+                fd => Ok((Stdio::from(unsafe { File::from_raw_fd(fd) }), None)),
+            },
+        }
+    }
+}
+
+/// Spawn a command fully detached from the calling process
+///
+/// The command is started from an intermediate child in a new session with its
+/// standard streams connected as described by `stdio`. The intermediate child
+/// exits right away so the command is reparented to init and never becomes a
+/// zombie of the caller. Use [`ExecEnv::apply`] to give it a clean environment.
+///
+/// Example:
+///
+///```
+///use fork::{spawn_detached, DetachedStdio, ExecEnv, StdioTarget};
+///use std::io::Read;
+///use std::process::Command;
+///
+///let mut cmd = Command::new("echo");
+///cmd.arg("hello");
+///let stdio = DetachedStdio {
+///    stdout: StdioTarget::Pipe,
+///    ..DetachedStdio::default()
+///};
+///let child = spawn_detached(ExecEnv::new().apply(&mut cmd), &stdio).expect("failed to spawn");
+///
+///let mut output = String::new();
+///child.stdout.expect("no stdout").read_to_string(&mut output).expect("failed to read");
+///assert_eq!(output, "hello\n");
+///```
+///
+/// # Errors
+/// returns `-1` if a stream can't be set up, or if the pipe, `fork` or spawning
+/// the command fails
+pub fn spawn_detached(cmd: &mut Command, stdio: &DetachedStdio) -> Result<DetachedChild, i32> {
+    let (stdin, stdin_end) = stdio.stdin.open(true)?;
+    let (stdout, stdout_end) = stdio.stdout.open(false)?;
+    let (stderr, stderr_end) = stdio.stderr.open(false)?;
+    let [report, report_end] = cloexec_pipe()?;
+    let (report, report_end) =
+        unsafe { (File::from_raw_fd(report), File::from_raw_fd(report_end)) };
+    let report_fd = report_end.as_raw_fd();
+    let active = Arc::new(AtomicBool::new(true));
+    let hook_active = Arc::clone(&active);
+
+    cmd.stdin(stdin).stdout(stdout).stderr(stderr);
+    unsafe {
+        cmd.pre_exec(move || {
+            if !hook_active.load(Ordering::Acquire) {
+                return Ok(());
+            }
+            // the spawned child only reports the pid of its own child, which
+            // goes on to exec in a new session
+            match libc::fork() {
+                -1 => Err(io::Error::last_os_error()),
+                0 => match libc::setsid() {
+                    -1 => Err(io::Error::last_os_error()),
+                    _ => Ok(()),
+                },
+                pid => {
+                    let pid = pid.to_ne_bytes();
+                    libc::write(report_fd, pid.as_ptr().cast(), pid.len());
+                    libc::_exit(0)
+                }
+            }
+        })
+    };
+    let spawned = cmd.spawn();
+    active.store(false, Ordering::Release);
+    // release the child's ends of the streams held by `cmd`
+    cmd.stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit());
+    drop(report_end);
+    let mut intermediate = spawned.map_err(|_| -1)?;
+    intermediate.wait().map_err(|_| -1)?;
+    Ok(DetachedChild {
+        pid: read_pid(report.as_raw_fd())?,
+        exit: None,
+        stdin: stdin_end,
+        stdout: stdout_end,
+        stderr: stderr_end,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        acquire_controlling_tty, atomic_write, audit_fds, bind_reuseport, boot_id, children_of,
+        count_zombie_children, create_temporary, current_session_id, daemon_with,
+        daemonize_and_run, descendants_of, double_fork, drain_workers, fork, getpid,
+        ignore_sigpipe, is_daemonized, is_pid1, is_session_leader, launched_by_launchd, notify,
+        pid1_mode, preopen_dev_null, process_state, process_table, raise_nofile_limit, read_pid,
+        redirect_stderr_fd, redirect_stdin_null, redirect_stdio, redirect_stdio_saved,
+        redirect_stdio_with, redirect_stdout_file, resource_usage, rfc3339, run_once, run_reaper,
+        run_with_timeout, setsid, spawn_detached, spawn_reaper_thread, spawn_with_timeout,
+        spawn_worker, start_time, temporary_path, wait_all, wait_for_exit, wait_for_signals,
+        waitpid, waitpid_status, waitpid_with, worker_id, write_ignoring_epipe, Account,
+        AlreadyDaemon, ChildLimits, Credentials, Daemon, DaemonOptions, Daemonized, DetachedExit,
+        DetachedStdio, DoubleFork, ExecEnv, FdPlan, FdPolicy, FileLock, Fork, Interrupt,
+        LockMethod, LockMode, NotifySocket, NullPolicy, ParentWatch, ResolvedUser, Resolver,
+        Resource, RunOnce, ScopedAlarm, ScopedChdir, Signal, SignalSet, Stage, StatusFile,
+        StdioTarget, Tee, WaitStatus, Who, WorkerId, DAEMON_ENV, NOTIFY_SOCKET_ENV,
+        STARTUP_TIMED_OUT,
+    };
+    use std::io::{Read, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+    use std::{env, process, thread};
+
+    /// Run `f` in a forked child and assert that it returned `true`
+    pub(crate) fn in_child(f: impl FnOnce() -> bool) {
+        in_child_of(fork(), f);
+    }
+
+    /// Run `f` in the child of `forked` and assert that it returned `true`
+    ///
+    /// The child leaves with `_exit`, a panic in `f` counts as `false`.
+    pub(crate) fn in_child_of(forked: Result<Fork, i32>, f: impl FnOnce() -> bool) {
+        match forked {
+            Ok(Fork::Child) => {
+                let ok = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or(false);
+                unsafe { libc::_exit(i32::from(!ok)) }
+            }
+            Ok(Fork::Parent(child)) => {
+                assert_eq!(waitpid_status(child), Ok(WaitStatus::Exited(0)));
+            }
+            Err(_) => panic!("fork failed"),
+        }
+    }
+
+    #[test]
     fn test_fork() {
         if let Ok(Fork::Parent(child)) = fork() {
             assert!(child > 0);
         }
     }
+
+    #[test]
+    fn test_run_once() {
+        let lock = env::temp_dir().join(format!("fork-run-once-{}.lock", process::id()));
+
+        match run_once(&lock, || {
+            thread::sleep(Duration::from_millis(500));
+            0
+        }) {
+            Ok(RunOnce::Parent(child)) => {
+                assert!(matches!(run_once(&lock, || 0), Ok(RunOnce::AlreadyRunning)));
+                assert!(waitpid(child).is_ok());
+            }
+            _ => panic!("failed to run job"),
+        }
+
+        // the lock is released once the job exits
+        match run_once(&lock, || 0) {
+            Ok(RunOnce::Parent(child)) => assert!(waitpid(child).is_ok()),
+            _ => panic!("lock was not released"),
+        }
+
+        // a panicking job doesn't return into the caller
+        match run_once(&lock, || panic!("job failed")) {
+            Ok(RunOnce::Parent(child)) => {
+                assert_eq!(waitpid_status(child), Ok(WaitStatus::Exited(101)));
+            }
+            _ => panic!("failed to run job"),
+        }
+    }
+
+    #[test]
+    fn test_file_lock() {
+        let path = env::temp_dir().join(format!("fork-file-lock-{}.lock", process::id()));
+        let shared = FileLock::options(&path).mode(LockMode::Shared);
+
+        // flock locks belong to the open file, so they conflict within a process
+        let first = shared.try_lock().expect("failed to lock");
+        assert!(first.is_some());
+        assert!(shared.try_lock().expect("failed to lock").is_some());
+        assert!(FileLock::options(&path)
+            .try_lock()
+            .expect("failed to lock")
+            .is_none());
+        drop(first);
+
+        // fcntl locks belong to the process, take one in a child
+        let fcntl = FileLock::options(&path).method(LockMethod::Fcntl);
+        match fork() {
+            Ok(Fork::Child) => {
+                let held = fcntl.try_lock().ok().flatten();
+                thread::sleep(Duration::from_millis(300));
+                process::exit(i32::from(held.is_none()));
+            }
+            Ok(Fork::Parent(child)) => {
+                thread::sleep(Duration::from_millis(100));
+                let shared = fcntl.clone().mode(LockMode::Shared);
+                assert!(shared.try_lock().expect("failed to lock").is_none());
+                let lock = shared
+                    .lock_timeout(Duration::from_secs(5))
+                    .expect("failed to lock");
+                assert_eq!(lock.map(|lock| lock.mode()), Some(LockMode::Shared));
+                assert_eq!(waitpid_status(child), Ok(WaitStatus::Exited(0)));
+            }
+            Err(_) => panic!("fork failed"),
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_spawn_with_timeout() {
+        assert_eq!(
+            spawn_with_timeout(&mut Command::new("true"), Duration::from_secs(5)),
+            Ok(WaitStatus::Exited(0))
+        );
+        assert_eq!(
+            spawn_with_timeout(Command::new("sleep").arg("5"), Duration::from_millis(50)),
+            Ok(WaitStatus::TimedOut)
+        );
+    }
+
+    #[test]
+    fn test_write_ignoring_epipe() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        assert_eq!(ignore_sigpipe(), Ok(()));
+
+        assert_eq!(write_ignoring_epipe(fds[1], b"ping"), Ok(true));
+        unsafe { libc::close(fds[0]) };
+        assert_eq!(write_ignoring_epipe(fds[1], b"ping"), Ok(false));
+        unsafe { libc::close(fds[1]) };
+    }
+
+    #[test]
+    fn test_wait_for_signals() {
+        in_child(|| {
+            // block it first so raising it leaves it pending
+            unsafe {
+                let mut set = std::mem::zeroed::<libc::sigset_t>();
+                libc::sigemptyset(&mut set);
+                libc::sigaddset(&mut set, libc::SIGUSR1);
+                libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+                libc::raise(libc::SIGUSR1);
+            }
+            matches!(
+                wait_for_signals(&[Signal::SIGUSR2, Signal::SIGUSR1]),
+                Ok(Signal::SIGUSR1)
+            )
+        });
+    }
+
+    #[test]
+    fn test_run_reaper() {
+        // reap in a child so that children of other tests are left alone
+        in_child(|| {
+            for code in 3..6 {
+                if matches!(fork(), Ok(Fork::Child)) {
+                    process::exit(code);
+                }
+            }
+            let mut codes = Vec::new();
+            let reaped = run_reaper(|_, status| {
+                if let WaitStatus::Exited(code) = status {
+                    codes.push(code);
+                }
+                true
+            });
+            codes.sort_unstable();
+            reaped.is_ok() && codes == [3, 4, 5]
+        });
+    }
+
+    #[test]
+    fn test_daemon_is_not_session_leader() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        match fork() {
+            Ok(Fork::Child) => {
+                if let Ok(Fork::Child) = daemon_with(DaemonOptions::empty()) {
+                    let leader = [u8::from(is_session_leader())];
+                    unsafe { libc::write(fds[1], leader.as_ptr().cast(), 1) };
+                }
+                process::exit(0);
+            }
+            Ok(Fork::Parent(child)) => {
+                assert!(waitpid(child).is_ok());
+                unsafe { libc::close(fds[1]) };
+                let mut leader = [1u8];
+                assert_eq!(
+                    unsafe { libc::read(fds[0], leader.as_mut_ptr().cast(), 1) },
+                    1
+                );
+                assert_eq!(leader, [0]);
+                unsafe { libc::close(fds[0]) };
+            }
+            Err(_) => panic!("fork failed"),
+        }
+    }
+
+    #[test]
+    fn test_daemonize_and_run_runs_body_once() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        in_child(|| {
+            unsafe { libc::close(fds[0]) };
+            daemonize_and_run(DaemonOptions::empty(), || {
+                unsafe { libc::write(fds[1], b"ran".as_ptr().cast(), 3) };
+                0
+            })
+        });
+        unsafe { libc::close(fds[1]) };
+        let mut output = String::new();
+        let mut read = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+        read.read_to_string(&mut output).expect("failed to read");
+        assert_eq!(output, "ran");
+    }
+
+    #[test]
+    fn test_already_daemon_policy() {
+        in_child(|| {
+            let detached = setsid().is_ok() && !is_daemonized();
+            env::set_var(DAEMON_ENV, "1");
+            let daemon = Daemon::new().nochdir(true).noclose(true);
+            let failed = daemon
+                .clone()
+                .already_daemon(AlreadyDaemon::Fail)
+                .start()
+                .is_err();
+            let pid = getpid();
+            let continued = matches!(
+                daemon.already_daemon(AlreadyDaemon::Continue).start(),
+                Ok(Daemonized::Daemon(info)) if info.pid == pid
+            );
+            detached && is_daemonized() && failed && continued
+        });
+    }
+
+    #[test]
+    fn test_daemonized_after_start() {
+        in_child(|| {
+            let dir = env::temp_dir().join(format!("fork-daemonized-{}", process::id()));
+            let created = std::fs::create_dir(&dir).is_ok();
+            let daemon = Daemon::new().foreground(true).noclose(true);
+            let prepared = daemon.clone().working_dir(&dir).prepare();
+            let removed = std::fs::remove_dir(&dir).is_ok();
+            let failed = prepared.is_ok_and(|prepared| prepared.start().is_err());
+            let unmarked = env::var_os(DAEMON_ENV).is_none() && !is_daemonized();
+            let started = setsid().is_ok() && daemon.nochdir(true).start().is_ok();
+            created && removed && failed && unmarked && started && is_daemonized()
+        });
+    }
+
+    #[test]
+    fn test_double_fork_stages() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        match double_fork() {
+            Ok(DoubleFork::Parent {
+                child,
+                stage: Stage::First,
+            }) => {
+                unsafe { libc::close(fds[1]) };
+                assert_eq!(waitpid_status(child), Ok(WaitStatus::Exited(0)));
+                let mut output = String::new();
+                let mut read = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+                read.read_to_string(&mut output).expect("failed to read");
+                let mut stages: Vec<_> = output.chars().collect();
+                stages.sort_unstable();
+                assert_eq!(stages, ['C', 'I']);
+            }
+            Ok(DoubleFork::Parent {
+                stage: Stage::Intermediate,
+                ..
+            }) => {
+                let stage = if is_session_leader() { b"I" } else { b"?" };
+                unsafe { libc::write(fds[1], stage.as_ptr().cast(), 1) };
+                process::exit(0);
+            }
+            Ok(DoubleFork::Child) => {
+                let stage = if is_session_leader() { b"?" } else { b"C" };
+                unsafe { libc::write(fds[1], stage.as_ptr().cast(), 1) };
+                process::exit(0);
+            }
+            Err(_) => panic!("fork failed"),
+        }
+    }
+
+    #[test]
+    fn test_spawn_reaper_thread() {
+        // the signal mask and disposition are per process, keep them away from other tests
+        in_child(|| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            let Ok(reaper) = spawn_reaper_thread(move |pid, status| {
+                let _ = sender.send((pid, status));
+                false
+            }) else {
+                return false;
+            };
+            let Ok(child) = Command::new("sh").arg("-c").arg("exit 3").spawn() else {
+                return false;
+            };
+            let reaped = receiver.recv_timeout(Duration::from_secs(5));
+            reaped.is_ok_and(|(pid, status)| {
+                u32::try_from(pid) == Ok(child.id()) && status == WaitStatus::Exited(3)
+            }) && matches!(reaper.join(), Ok(Ok(())))
+        });
+    }
+
+    #[test]
+    fn test_waitpid_with_interrupt() {
+        in_child(|| {
+            let sleeper = match fork() {
+                Ok(Fork::Child) => {
+                    thread::sleep(Duration::from_secs(5));
+                    process::exit(0);
+                }
+                Ok(Fork::Parent(sleeper)) => sleeper,
+                Err(_) => return false,
+            };
+            // no SA_RESTART, so SIGALRM interrupts waitpid
+            let mut action = unsafe { std::mem::zeroed::<libc::sigaction>() };
+            action.sa_sigaction =
+                super::noop_handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            let timer = libc::itimerval {
+                it_interval: libc::timeval {
+                    tv_sec: 0,
+                    tv_usec: 0,
+                },
+                it_value: libc::timeval {
+                    tv_sec: 0,
+                    tv_usec: 100_000,
+                },
+            };
+            unsafe {
+                libc::sigaction(libc::SIGALRM, &action, std::ptr::null_mut());
+                libc::setitimer(libc::ITIMER_REAL, &timer, std::ptr::null_mut());
+            }
+            let interrupted = waitpid_with(sleeper, Interrupt::Return);
+            unsafe { libc::kill(sleeper, libc::SIGKILL) };
+            let killed = waitpid_with(sleeper, Interrupt::Retry);
+            interrupted == Ok(WaitStatus::Interrupted)
+                && killed == Ok(WaitStatus::Signaled(Signal::SIGKILL))
+        });
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "netbsd"))]
+    #[test]
+    fn test_queue_signal_value() {
+        in_child(|| {
+            // blocked before the sender exists, so the signal stays pending
+            if SignalSet::new().add(Signal::SIGUSR1).block().is_err() {
+                return false;
+            }
+            let receiver = getpid();
+            let sender = match fork() {
+                Ok(Fork::Child) => {
+                    let sent = super::queue_signal(receiver, Signal::SIGUSR1, 42);
+                    process::exit(i32::from(sent.is_err()));
+                }
+                Ok(Fork::Parent(sender)) => sender,
+                Err(_) => return false,
+            };
+            let received = super::wait_for_queued_signal(&[Signal::SIGUSR1]);
+            received == Ok((Signal::SIGUSR1, 42))
+                && waitpid_status(sender) == Ok(WaitStatus::Exited(0))
+        });
+    }
+
+    #[test]
+    fn test_signal_names_round_trip() {
+        for signum in 1..=64 {
+            let signal = Signal::from_raw(signum);
+            assert_eq!(Signal::from_name(&signal.to_string()), Some(signal));
+        }
+        assert_eq!(Signal::SIGCHLD.to_string(), "SIGCHLD");
+        assert_eq!(Signal::from_name("sigwinch"), Some(Signal::SIGWINCH));
+        assert_eq!(Signal::from_name("SIG"), None);
+        assert_eq!(Signal::from_name("0"), None);
+    }
+
+    #[test]
+    fn test_signal_set_mask() {
+        // the signal mask is per thread, keep it away from other tests
+        in_child(|| {
+            let set: SignalSet = [Signal::SIGUSR2, Signal::SIGWINCH].into_iter().collect();
+            let blocked = set.block().is_ok()
+                && SignalSet::blocked().is_ok_and(|mask| {
+                    mask.contains(Signal::SIGUSR2) && mask.contains(Signal::SIGWINCH)
+                });
+            let unblocked = set.remove(Signal::SIGWINCH).unblock().is_ok()
+                && SignalSet::blocked().is_ok_and(|mask| {
+                    !mask.contains(Signal::SIGUSR2) && mask.contains(Signal::SIGWINCH)
+                });
+            let invalid = SignalSet::try_from(&[Signal::from_raw(1000)][..]).is_err();
+            blocked && unblocked && invalid
+        });
+    }
+
+    #[test]
+    fn test_scoped_alarm_is_cleared() {
+        in_child(|| {
+            let guard = ScopedAlarm::new(Duration::from_millis(100));
+            drop(guard);
+            thread::sleep(Duration::from_millis(300));
+            true
+        });
+    }
+
+    #[test]
+    fn test_parent_watch() {
+        let [read, write] = crate::cloexec_pipe().expect("failed to create pipe");
+
+        in_child(|| {
+            let Ok(watch) = ParentWatch::new() else {
+                return false;
+            };
+            match fork() {
+                Ok(Fork::Child) => {
+                    let notified = watch.child(move || {
+                        unsafe { libc::write(write, b"gone".as_ptr().cast(), 4) };
+                        process::exit(0);
+                    });
+                    if notified.is_ok() {
+                        thread::sleep(Duration::from_secs(5));
+                    }
+                    process::exit(2);
+                }
+                Ok(Fork::Parent(_)) => {
+                    let alive = watch.parent();
+                    thread::sleep(Duration::from_millis(100));
+                    drop(alive);
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+        unsafe { libc::close(write) };
+        let mut output = String::new();
+        let mut read = unsafe { std::fs::File::from_raw_fd(read) };
+        read.read_to_string(&mut output).expect("failed to read");
+        assert_eq!(output, "gone");
+    }
+
+    #[test]
+    fn test_spawn_group_catches_panic() {
+        let group = super::spawn_group(|| panic!("leader failed")).expect("failed to spawn group");
+        assert_eq!(
+            group.wait_all(),
+            Ok(vec![(group.pgid(), WaitStatus::Exited(101))])
+        );
+    }
+
+    #[test]
+    fn test_spawn_worker_sets_id() {
+        let id = WorkerId {
+            index: 3,
+            generation: 7,
+        };
+        let worker = spawn_worker(id, || i32::from(worker_id() != Some(id)));
+        let worker = worker.expect("failed to spawn worker");
+        assert_eq!(waitpid_status(worker), Ok(WaitStatus::Exited(0)));
+        assert_eq!(worker_id(), None);
+
+        let worker = spawn_worker(id, || panic!("worker failed"));
+        let worker = worker.expect("failed to spawn worker");
+        assert_eq!(waitpid_status(worker), Ok(WaitStatus::Exited(101)));
+    }
+
+    #[test]
+    fn test_drain_workers_escalates() {
+        let stubborn = spawn_worker(
+            WorkerId {
+                index: 0,
+                generation: 0,
+            },
+            || {
+                unsafe { libc::signal(libc::SIGTERM, libc::SIG_IGN) };
+                thread::sleep(Duration::from_secs(10));
+                0
+            },
+        );
+        let stubborn = stubborn.expect("failed to spawn worker");
+        thread::sleep(Duration::from_millis(100));
+        let statuses = drain_workers(&[stubborn], Signal::SIGTERM, Duration::from_millis(200));
+        assert_eq!(statuses.get(&stubborn), Some(&WaitStatus::TimedOut));
+    }
+
+    #[test]
+    fn test_bind_reuseport_twice() {
+        let first = bind_reuseport("127.0.0.1:0".parse().unwrap(), 16).expect("failed to bind");
+        let addr = first.local_addr().expect("no local address");
+        let second = bind_reuseport(addr, 16).expect("failed to bind again");
+        assert_eq!(second.local_addr().ok(), Some(addr));
+        assert!(std::net::TcpListener::bind(addr).is_err());
+    }
+
+    #[test]
+    fn test_notify_socket() {
+        let path = env::temp_dir().join(format!("fork-notify-{}.sock", process::id()));
+        let socket = NotifySocket::bind(&path).expect("failed to bind");
+
+        in_child(|| {
+            env::set_var(NOTIFY_SOCKET_ENV, &path);
+            notify("READY=1\nSTATUS=serving") == Ok(true)
+        });
+        let notification = socket
+            .recv(Duration::from_secs(5))
+            .expect("failed to receive")
+            .expect("no notification");
+        assert!(notification.is_ready());
+        assert_eq!(notification.get("STATUS"), Some("serving"));
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_wait_for_exit() {
+        let mut child = Command::new("sleep")
+            .arg("5")
+            .spawn()
+            .expect("failed to spawn");
+        let pid = libc::pid_t::try_from(child.id()).expect("pid out of range");
+        assert_eq!(
+            wait_for_exit(pid, Some(Duration::from_millis(100))),
+            Ok(false)
+        );
+        assert!(!super::exited(pid));
+        child.kill().expect("failed to kill");
+        assert_eq!(wait_for_exit(pid, None), Ok(true));
+        // still a zombie until reaped
+        assert!(super::exited(pid));
+        child.wait().expect("failed to reap");
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    fn test_detached_child_wait_as_reaper() {
+        in_child(|| {
+            let exit = super::become_reaper()
+                .and_then(|()| {
+                    spawn_detached(
+                        Command::new("sh").args(["-c", "exit 3"]),
+                        &DetachedStdio::default(),
+                    )
+                })
+                .and_then(|mut child| child.wait());
+            exit == Ok(DetachedExit::Status(WaitStatus::Exited(3)))
+        });
+    }
+
+    #[test]
+    fn test_run_with_timeout() {
+        let output = run_with_timeout(
+            Command::new("sh").args(["-c", "echo out; echo err >&2; exit 2"]),
+            Duration::from_secs(5),
+        )
+        .expect("failed to run");
+        assert_eq!(output.status.code(), Some(2));
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+
+        // the background sleep is part of the group and killed with the shell
+        let output = run_with_timeout(
+            Command::new("sh").args(["-c", "sleep 10 & echo $!; wait"]),
+            Duration::from_millis(200),
+        )
+        .expect("failed to run");
+        let sleep: libc::pid_t = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .expect("no pid");
+        assert_eq!(wait_for_exit(sleep, Some(Duration::from_secs(5))), Ok(true));
+    }
+
+    #[test]
+    fn test_daemon_run_in_context() {
+        let dir = std::fs::canonicalize(env::temp_dir()).expect("no temp dir");
+        let daemon = Daemon::new()
+            .working_dir(&dir)
+            .umask(0o077)
+            .exec_env(ExecEnv::new().var("FORK_CONTEXT", "1"));
+        let script = format!(
+            "test \"$(pwd)\" = {} && test \"$(umask)\" = 0077 && test \"$FORK_CONTEXT\" = 1 && test -z \"$HOME\"",
+            dir.display()
+        );
+        let status = daemon
+            .run_in_context(Command::new("sh").args(["-c", &script]))
+            .expect("failed to run");
+        assert!(status.success());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_fd_policy_apply() {
+        let null = std::fs::File::open("/dev/null").expect("failed to open /dev/null");
+        let leaked = unsafe { libc::dup(null.as_raw_fd()) };
+        let kept = unsafe { libc::dup(null.as_raw_fd()) };
+        let script = format!("test -e /dev/fd/{kept} && ! test -e /dev/fd/{leaked}");
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", &script]);
+        assert!(!cmd.status().expect("failed to run sh").success());
+        let status = FdPolicy::new()
+            .keep(kept)
+            .apply(&mut cmd)
+            .status()
+            .expect("failed to run sh");
+        assert!(status.success());
+        unsafe { libc::close(kept) };
+        assert!(FdPolicy::new()
+            .keep(kept)
+            .apply(&mut Command::new("true"))
+            .status()
+            .is_err());
+        unsafe { libc::close(leaked) };
+    }
+
+    #[test]
+    fn test_fd_probe_limit() {
+        in_child(|| {
+            // as high as allowed, the kernel's `nr_open` or unlimited
+            let mut limit = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+            limit.rlim_cur = limit.rlim_max;
+            unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) };
+            let probed = super::fd_probe_limit();
+            (1..=super::FD_PROBE_MAX).contains(&probed)
+        });
+    }
+
+    #[test]
+    fn test_fd_plan_cycle() {
+        let first = super::cloexec_pipe().expect("failed to create pipe");
+        let second = super::cloexec_pipe().expect("failed to create pipe");
+        in_child(|| {
+            let plan = FdPlan::new()
+                .map(first[1], second[1])
+                .map(second[1], first[1])
+                .close(first[0])
+                .close(second[0]);
+            plan.perform().is_ok()
+                && unsafe { libc::write(first[1], b"a".as_ptr().cast(), 1) } == 1
+                && unsafe { libc::write(second[1], b"b".as_ptr().cast(), 1) } == 1
+                && unsafe { libc::fcntl(first[0], libc::F_GETFD) } == -1
+        });
+        unsafe {
+            libc::close(first[1]);
+            libc::close(second[1]);
+        }
+        let read = |fd| {
+            let mut buf = String::new();
+            unsafe { std::fs::File::from_raw_fd(fd) }
+                .read_to_string(&mut buf)
+                .expect("failed to read");
+            buf
+        };
+        // the write ends were swapped
+        assert_eq!(read(first[0]), "b");
+        assert_eq!(read(second[0]), "a");
+    }
+
+    #[test]
+    fn test_tee_rotate() {
+        let log = env::temp_dir().join(format!("fork-rotate-{}.log", process::id()));
+        let rotated = log.with_extension("log.1");
+        std::fs::write(&log, "previous\n").expect("failed to write log");
+        let _ = std::fs::remove_file(&rotated);
+
+        let (mut output, forwarder) = Tee::new(&log)
+            .rotate(10, 1)
+            .pipe()
+            .expect("failed to create pipe");
+        output.write_all(b"hello\n").expect("failed to write");
+        drop(output);
+        assert_eq!(forwarder.join().expect("forwarder panicked"), Ok(()));
+
+        assert_eq!(std::fs::read_to_string(&log).ok(), Some("hello\n".into()));
+        assert_eq!(
+            std::fs::read_to_string(&rotated).ok(),
+            Some("previous\n".into())
+        );
+        let _ = std::fs::remove_file(&log);
+        let _ = std::fs::remove_file(&rotated);
+    }
+
+    #[test]
+    fn test_tee_format_lines() {
+        let log = env::temp_dir().join(format!("fork-format-{}.log", process::id()));
+        let _ = std::fs::remove_file(&log);
+        let (mut output, forwarder) = Tee::new(&log)
+            .format_lines(|out, line| {
+                out.extend_from_slice(b"> ");
+                out.extend_from_slice(line);
+            })
+            .pipe()
+            .expect("failed to create pipe");
+        output.write_all(b"one\ntw").expect("failed to write");
+        output.write_all(b"o\nthree").expect("failed to write");
+        drop(output);
+        assert_eq!(forwarder.join().expect("forwarder panicked"), Ok(()));
+        assert_eq!(
+            std::fs::read_to_string(&log).ok(),
+            Some("> one\n> two\n> three\n".into())
+        );
+        let _ = std::fs::remove_file(&log);
+
+        assert_eq!(rfc3339(std::time::UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_set_process_title() {
+        in_child(|| {
+            let title = "fork-test: worker 3";
+            unsafe { super::set_process_title(title) }.is_ok()
+                && std::fs::read("/proc/self/cmdline")
+                    .is_ok_and(|cmdline| cmdline.starts_with(b"fork-test: worker 3\0"))
+                && std::fs::read_to_string("/proc/self/comm").ok()
+                    == Some("fork-test: work\n".into())
+                && unsafe { super::set_process_title("nul\0byte") }.is_err()
+        });
+        // restarts keep the arguments the title overwrote
+        in_child(|| {
+            let args: Vec<std::ffi::OsString> = env::args_os().collect();
+            unsafe { super::set_process_title("fork-test: titled") }.is_ok()
+                && env::args_os().ne(args.iter().cloned())
+                && super::self_command().is_ok_and(|cmd| cmd.get_args().eq(&args[1..]))
+        });
+    }
+
+    #[test]
+    fn test_process_state() {
+        let parent = process_state();
+        in_child(|| {
+            unsafe { libc::umask(0o027) };
+            setsid().is_ok() && {
+                let state = process_state();
+                state.ppid == parent.pid
+                    && state.pgid == state.pid
+                    && state.sid == state.pid
+                    && !state.has_tty
+                    && state.umask == 0o027
+                    && state.cwd == env::current_dir().ok()
+            }
+        });
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_daemon_check_working_dir() {
+        let shm = std::path::Path::new("/dev/shm");
+        let mounts = std::fs::read_to_string("/proc/mounts").unwrap_or_default();
+        if mounts.contains(" /dev/shm tmpfs ") {
+            let daemon = Daemon::new().working_dir(shm).check_working_dir(true);
+            assert!(daemon.prepare().is_err());
+            assert!(daemon.check_working_dir(false).prepare().is_ok());
+        }
+
+        // the temporary directory itself must pass the checks
+        let tmp =
+            env::temp_dir() == std::path::Path::new("/tmp") && !mounts.contains(" /tmp tmpfs ");
+        if tmp && unsafe { libc::getuid() } == 0 {
+            let dir = env::temp_dir().join(format!("fork-private-{}", process::id()));
+            std::fs::create_dir_all(&dir).expect("failed to create dir");
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))
+                .expect("failed to set permissions");
+            let daemon = Daemon::new()
+                .working_dir(&dir)
+                .user("nobody")
+                .check_working_dir(true);
+            assert!(daemon.prepare().is_err());
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o711))
+                .expect("failed to set permissions");
+            assert!(daemon.prepare().is_ok());
+            let _ = std::fs::remove_dir(&dir);
+        }
+    }
+
+    #[test]
+    fn test_atomic_write() {
+        let path = env::temp_dir().join(format!("fork-atomic-{}.status", process::id()));
+        assert!(atomic_write(&path, b"first").is_ok());
+        assert!(atomic_write(&path, b"second").is_ok());
+        assert_eq!(std::fs::read(&path).ok(), Some(b"second".to_vec()));
+        assert!(atomic_write(path.join("not-a-dir"), b"").is_err());
+        assert_ne!(temporary_path(&path), temporary_path(&path));
+
+        // a symlink planted at the name temporary files used to have
+        let victim = env::temp_dir().join(format!("fork-atomic-victim-{}", process::id()));
+        std::fs::write(&victim, "precious").expect("failed to write");
+        let mut planted = path.as_os_str().to_owned();
+        planted.push(format!(".{}.tmp", process::id()));
+        std::os::unix::fs::symlink(&victim, &planted).expect("failed to symlink");
+        assert!(atomic_write(&path, b"third").is_ok());
+        let pidfile = path.with_extension("pid");
+        let mut planted_pid = pidfile.as_os_str().to_owned();
+        planted_pid.push(format!(".{}.tmp", process::id()));
+        std::os::unix::fs::symlink(&victim, &planted_pid).expect("failed to symlink");
+        let prepared = Daemon::new().pidfile(&pidfile).prepare();
+        assert!(
+            prepared.is_ok_and(|prepared| prepared.pidfile.is_some_and(|p| p.commit(7).is_ok()))
+        );
+        assert_eq!(std::fs::read(&pidfile).ok(), Some(b"7\n".to_vec()));
+        assert_eq!(std::fs::read(&victim).ok(), Some(b"precious".to_vec()));
+        // an existing name is never reused
+        let (tmp, _file) = create_temporary(&path).expect("failed to create");
+        assert_eq!(
+            std::fs::symlink_metadata(&tmp).map(|m| m.is_file()).ok(),
+            Some(true)
+        );
+        for file in [&path, &victim, &pidfile, &tmp] {
+            let _ = std::fs::remove_file(file);
+        }
+        let _ = std::fs::remove_file(&planted);
+        let _ = std::fs::remove_file(&planted_pid);
+        // only the planted links are left next to the files
+        let leftovers = std::fs::read_dir(env::temp_dir())
+            .expect("failed to read dir")
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                let name = entry.file_name();
+                let prefix = format!("fork-atomic-{}.", process::id());
+                name.to_string_lossy().starts_with(&prefix)
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn test_daemon_pidfile_mode_and_owner() {
+        let path = env::temp_dir().join(format!("fork-pidfile-mode-{}.pid", process::id()));
+        let commit = |daemon: Daemon| {
+            let prepared = daemon.pidfile(&path).prepare().expect("failed to prepare");
+            let pidfile = prepared.pidfile.as_ref().expect("no pid file");
+            assert!(pidfile.commit(42).is_ok());
+            std::fs::metadata(&path).expect("no pid file")
+        };
+        let meta = commit(Daemon::new().pidfile_mode(0o640));
+        assert_eq!(meta.permissions().mode() & 0o777, 0o640);
+
+        if unsafe { libc::getuid() } == 0 {
+            let meta = commit(Daemon::new().user("nobody").pidfile_owner(true));
+            assert_eq!(std::os::unix::fs::MetadataExt::uid(&meta), 65534);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_scoped_chdir_restores_in_owner_only() {
+        // the working directory is per process, keep it away from other tests
+        in_child(|| {
+            let previous = env::current_dir().expect("failed current_dir");
+            let guard = ScopedChdir::new("/").expect("failed to chdir");
+            let forked = match fork() {
+                Ok(Fork::Child) => {
+                    drop(guard);
+                    let code = i32::from(env::current_dir().ok() != Some("/".into()));
+                    process::exit(code);
+                }
+                Ok(Fork::Parent(child)) => waitpid_status(child),
+                Err(_) => return false,
+            };
+            drop(guard);
+            let restored = env::current_dir().ok() == Some(previous);
+            restored && forked == Ok(WaitStatus::Exited(0))
+        });
+    }
+
+    #[test]
+    fn test_raise_nofile_limit() {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) },
+            0
+        );
+
+        // never lowered
+        assert_eq!(raise_nofile_limit(1), Ok(limit.rlim_cur));
+        // capped at the hard limit
+        let raised = raise_nofile_limit(libc::rlim_t::MAX).expect("failed to raise");
+        assert!(raised >= limit.rlim_cur && raised <= limit.rlim_max);
+    }
+
+    #[test]
+    fn test_daemon_info() {
+        let pidfile = env::temp_dir().join(format!("fork-daemon-info-{}.pid", process::id()));
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        match fork() {
+            Ok(Fork::Child) => {
+                let daemon = Daemon::new().nochdir(true).noclose(true).pidfile(&pidfile);
+                if let Ok(Daemonized::Daemon(info)) = daemon.start() {
+                    let written = std::fs::read_to_string(&pidfile).unwrap_or_default();
+                    let ok = info.pid == getpid()
+                        && info.sid != info.pid
+                        && info.pgid == info.sid
+                        && info.cwd == env::current_dir().ok()
+                        && info.pidfile.as_ref() == Some(&pidfile)
+                        && written == format!("{}\n", info.pid);
+                    let ok = [u8::from(ok)];
+                    unsafe { libc::write(fds[1], ok.as_ptr().cast(), 1) };
+                }
+                process::exit(0);
+            }
+            Ok(Fork::Parent(child)) => {
+                assert!(waitpid(child).is_ok());
+                unsafe { libc::close(fds[1]) };
+                let mut ok = [0u8];
+                assert_eq!(unsafe { libc::read(fds[0], ok.as_mut_ptr().cast(), 1) }, 1);
+                assert_eq!(ok, [1]);
+                unsafe { libc::close(fds[0]) };
+                let _ = std::fs::remove_file(&pidfile);
+            }
+            Err(_) => panic!("fork failed"),
+        }
+    }
+
+    #[test]
+    fn test_daemon_single_fork() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        match fork() {
+            Ok(Fork::Child) => {
+                let daemon = Daemon::new().nochdir(true).noclose(true).double_fork(false);
+                match daemon.start() {
+                    Ok(Daemonized::Daemon(info)) => {
+                        let leader = [u8::from(info.sid == info.pid)];
+                        unsafe { libc::write(fds[1], leader.as_ptr().cast(), 1) };
+                    }
+                    _ => unsafe {
+                        libc::write(fds[1], [0u8].as_ptr().cast(), 1);
+                    },
+                }
+                process::exit(0);
+            }
+            Ok(Fork::Parent(child)) => {
+                assert!(waitpid(child).is_ok());
+                unsafe { libc::close(fds[1]) };
+                let mut leader = [0u8];
+                assert_eq!(
+                    unsafe { libc::read(fds[0], leader.as_mut_ptr().cast(), 1) },
+                    1
+                );
+                assert_eq!(leader, [1]);
+                unsafe { libc::close(fds[0]) };
+            }
+            Err(_) => panic!("fork failed"),
+        }
+    }
+
+    #[test]
+    fn test_acquire_controlling_tty() {
+        in_child(|| {
+            setsid().is_ok()
+                && unsafe {
+                    let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+                    libc::grantpt(master);
+                    libc::unlockpt(master);
+                    let name = libc::ptsname(master);
+                    let slave = if name.is_null() {
+                        -1
+                    } else {
+                        libc::open(name, libc::O_RDWR | libc::O_NOCTTY)
+                    };
+                    acquire_controlling_tty(slave).is_ok()
+                }
+        });
+    }
+
+    #[test]
+    fn test_wait_all_partial() {
+        let mut children = Vec::new();
+        for code in 1..4 {
+            match fork() {
+                Ok(Fork::Parent(child)) => children.push(child),
+                Ok(Fork::Child) => process::exit(code),
+                Err(_) => panic!("fork failed"),
+            }
+        }
+        let sleeper = match fork() {
+            Ok(Fork::Parent(child)) => child,
+            Ok(Fork::Child) => {
+                thread::sleep(Duration::from_secs(5));
+                process::exit(0);
+            }
+            Err(_) => panic!("fork failed"),
+        };
+        children.push(sleeper);
+
+        let statuses = wait_all(&children, Duration::from_millis(500));
+        assert_eq!(statuses.len(), 3);
+        for (code, child) in (1..4).zip(&children) {
+            assert_eq!(statuses[child], WaitStatus::Exited(code));
+        }
+
+        unsafe { libc::kill(sleeper, libc::SIGKILL) };
+        assert_eq!(
+            waitpid_status(sleeper),
+            Ok(WaitStatus::Signaled(Signal::SIGKILL))
+        );
+    }
+
+    #[test]
+    fn test_exec_env_vars() {
+        let vars = ExecEnv::new()
+            .inherit("FORK_EXEC_ENV_UNSET")
+            .var("A", "1")
+            .var("A", "2")
+            .vars();
+        assert_eq!(
+            vars,
+            vec![
+                ("A".into(), "2".into()),
+                ("PATH".into(), "/usr/local/bin:/usr/bin:/bin".into())
+            ]
+        );
+        let vars = ExecEnv::new().path_fallback(None::<&str>).vars();
+        assert!(vars.is_empty());
+    }
+
+    #[test]
+    fn test_spawn_detached() {
+        let child = spawn_detached(Command::new("sleep").arg("5"), &DetachedStdio::default())
+            .expect("failed to spawn");
+        let pid = child.id();
+        assert!(pid > 0);
+        // its own session, not a child of the test process
+        assert_eq!(unsafe { libc::getsid(pid) }, pid);
+        assert_eq!(waitpid(pid), Err(-1));
+        let mut child = child;
+        assert_eq!(child.try_wait(), Ok(None));
+        assert_eq!(child.kill(), Ok(()));
+        assert_eq!(child.wait(), Ok(DetachedExit::Unknown));
+        assert_eq!(child.kill(), Ok(()));
+
+        assert!(
+            spawn_detached(&mut Command::new("/nonexistent"), &DetachedStdio::default()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_spawn_detached_stdio() {
+        let log = env::temp_dir().join(format!("fork-detached-{}.log", process::id()));
+        let _ = std::fs::remove_file(&log);
+        let stdio = DetachedStdio {
+            stdin: StdioTarget::Pipe,
+            stdout: StdioTarget::Pipe,
+            stderr: StdioTarget::Append(log.clone()),
+        };
+        let mut child = spawn_detached(
+            Command::new("sh").args(["-c", "echo out; echo err >&2; cat"]),
+            &stdio,
+        )
+        .expect("failed to spawn");
+
+        child
+            .stdin
+            .take()
+            .expect("no stdin")
+            .write_all(b"in\n")
+            .expect("failed to write");
+        let mut output = String::new();
+        child
+            .stdout
+            .take()
+            .expect("no stdout")
+            .read_to_string(&mut output)
+            .expect("failed to read");
+        assert_eq!(output, "out\nin\n");
+        assert!(child.stderr.is_none());
+        assert_eq!(std::fs::read_to_string(&log).ok(), Some("err\n".into()));
+        let _ = std::fs::remove_file(&log);
+    }
+
+    #[test]
+    fn test_daemon_numeric_credentials() {
+        assert!(Daemon::new().user_id(65534).credentials().is_err());
+        let credentials = Daemon::new()
+            .user_id(65534)
+            .group_id(65533)
+            .credentials()
+            .expect("failed to resolve");
+        assert!(credentials.is_some_and(|c| c.gid == 65533 && c.user == Some((65534, vec![65533]))));
+
+        struct Fixed;
+        impl Resolver for Fixed {
+            fn user(&self, name: &str) -> Result<(libc::uid_t, libc::gid_t), i32> {
+                if name == "app" {
+                    Ok((1000, 1001))
+                } else {
+                    Err(-1)
+                }
+            }
+
+            fn group(&self, _: &str) -> Result<libc::gid_t, i32> {
+                Err(-1)
+            }
+        }
+        let credentials = Daemon::new()
+            .user("app")
+            .resolver(Fixed)
+            .credentials()
+            .expect("failed to resolve");
+        assert!(credentials.is_some_and(|c| c.gid == 1001 && c.user == Some((1000, vec![1001]))));
+        assert!(Daemon::new()
+            .user("app")
+            .group("app")
+            .resolver(Fixed)
+            .credentials()
+            .is_err());
+    }
+
+    #[test]
+    fn test_daemon_supplementary_groups() {
+        let groups = |daemon: Daemon| {
+            let credentials = daemon.credentials().expect("failed to resolve");
+            credentials.and_then(|c| c.user).map(|(_, groups)| groups)
+        };
+        let daemon = Daemon::new().user_id(1000).group_id(1000);
+        assert_eq!(groups(daemon.clone()), Some(vec![1000]));
+        assert_eq!(
+            groups(daemon.clone().supplementary_group_ids([])),
+            Some(vec![])
+        );
+        let listed = daemon
+            .clone()
+            .supplementary_group_ids([44, 0])
+            .supplementary_group_ids([44]);
+        assert_eq!(groups(listed), Some(vec![44, 0]));
+        assert!(daemon
+            .supplementary_groups(["fork-no-such-group"])
+            .credentials()
+            .is_err());
+    }
+
+    #[test]
+    fn test_daemon_resolve_all() {
+        assert!(Daemon::new()
+            .user("fork-no-such-user")
+            .resolve_all()
+            .is_err());
+
+        let daemon = Daemon::new().user("root").pidfile("fork.pid");
+        let resolved = daemon.clone().resolve_all().expect("failed to resolve");
+        assert!(matches!(resolved.user, Some(Account::Id(0))));
+        assert!(matches!(resolved.group, Some(Account::Id(_))));
+        assert_eq!(resolved.credentials(), daemon.credentials());
+        let cwd = env::current_dir().expect("failed current_dir");
+        assert_eq!(resolved.pidfile, Some(cwd.join("fork.pid")));
+    }
+
+    #[test]
+    fn test_resolved_user() {
+        let user = ResolvedUser::new(65534, 65533, vec![65533, 65532]);
+        assert_eq!(
+            (user.uid(), user.gid(), user.groups()),
+            (65534, 65533, &[65533, 65532][..])
+        );
+        let credentials = Daemon::new()
+            .user("fork-no-such-user")
+            .resolved_user(user.clone())
+            .credentials();
+        assert_eq!(credentials, Ok(Some(Credentials::clone(&user.0))));
+
+        if unsafe { libc::getuid() } != 0 {
+            assert!(user.switch().is_err());
+            return;
+        }
+        in_child(|| {
+            user.switch().is_ok() && unsafe { (libc::geteuid(), libc::getegid()) } == (65534, 65533)
+        });
+    }
+
+    #[test]
+    fn test_credentials_verify() {
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+        let mut groups = vec![0; usize::try_from(count).expect("failed getgroups")];
+        unsafe { libc::getgroups(count, groups.as_mut_ptr()) };
+
+        let current = Credentials {
+            user: Some((uid, groups.clone())),
+            gid,
+        };
+        assert!(current.verify().is_ok());
+        let other_gid = Credentials {
+            user: None,
+            gid: gid.wrapping_add(1),
+        };
+        assert!(other_gid.verify().is_err());
+        groups.push(groups.iter().chain([&gid]).max().map_or(1, |max| max + 1));
+        let other_groups = Credentials {
+            user: Some((uid, groups)),
+            gid,
+        };
+        assert!(other_groups.verify().is_err());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_daemon_dumpable() {
+        in_child(|| {
+            let dumpable = |flag| {
+                let prepared = Daemon::new().dumpable(flag).prepare();
+                prepared.is_ok_and(|prepared| prepared.finish().is_ok())
+                    && unsafe { libc::prctl(libc::PR_GET_DUMPABLE) } == i32::from(flag)
+            };
+            dumpable(false) && dumpable(true)
+        });
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_daemon_private_tmp() {
+        if unsafe { libc::getuid() } != 0 {
+            return;
+        }
+        let marker = format!("/tmp/fork-private-tmp-{}", process::id());
+        in_child(|| {
+            let prepared = Daemon::new().private_tmp(true).prepare();
+            if !prepared.is_ok_and(|prepared| prepared.finish().is_ok()) {
+                return false;
+            }
+            let empty = |dir| std::fs::read_dir(dir).is_ok_and(|mut d| d.next().is_none());
+            let writable = std::fs::write(&marker, "private").is_ok();
+            empty("/var/tmp") && writable
+        });
+        assert!(std::fs::metadata(&marker).is_err());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_daemon_protect_system() {
+        if unsafe { libc::getuid() } != 0 {
+            return;
+        }
+        in_child(|| {
+            let prepared = Daemon::new().protect_system(true).prepare();
+            if !prepared.is_ok_and(|prepared| prepared.finish().is_ok()) {
+                return false;
+            }
+            let read_only = |dir: &str| {
+                let path = format!("{dir}/fork-protect-system-{}", process::id());
+                std::fs::write(&path, "").is_err_and(|e| e.raw_os_error() == Some(libc::EROFS))
+            };
+            read_only("/usr") && read_only("/etc")
+        });
+    }
+
+    #[test]
+    fn test_daemon_exec() {
+        let dir = env::temp_dir().join(format!("fork-daemon-exec-{}", process::id()));
+        std::fs::create_dir_all(&dir).expect("failed to create dir");
+        // writable by nobody once privileges are dropped
+        std::fs::set_permissions(&dir, PermissionsExt::from_mode(0o777)).expect("failed to chmod");
+        let pidfile = dir.join("pid");
+        let output = dir.join("output");
+        let root = unsafe { libc::getuid() } == 0;
+
+        in_child(|| {
+            let mut daemon = Daemon::new()
+                .working_dir(&dir)
+                .umask(0o027)
+                .pidfile(&pidfile)
+                .rlimit(Resource::Core, 0, 0);
+            if root {
+                daemon = daemon.user("nobody");
+            }
+            let started = daemon.exec(
+                Command::new("sh")
+                    .arg("-c")
+                    .arg("echo $$ $(id -u) $(pwd) $(umask) $(ulimit -c) > tmp && mv tmp output"),
+            );
+            started.is_ok()
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !output.exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let output = std::fs::read_to_string(&output).expect("no output");
+        let pid = std::fs::read_to_string(&pidfile).expect("no pid file");
+        let uid = if root {
+            65534
+        } else {
+            unsafe { libc::getuid() }
+        };
+        assert_eq!(
+            output,
+            format!("{} {uid} {} 0027 0\n", pid.trim(), dir.display())
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_fork_combinators() {
+        let parent = Fork::Parent(42);
+        assert!(parent.is_parent() && !parent.is_child());
+        assert_eq!(parent.parent_pid(), Some(42));
+        assert_eq!(parent.child_then(|| 1), None);
+        assert_eq!(parent.parent_then(|pid| pid + 1), Some(43));
+
+        let child = Fork::Child;
+        assert!(child.is_child() && !child.is_parent());
+        assert_eq!(child.parent_pid(), None);
+        assert_eq!(child.child_then(|| 1), Some(1));
+        assert_eq!(child.parent_then(|pid| pid + 1), None);
+    }
+
+    #[test]
+    fn test_daemon_options() {
+        let options = DaemonOptions::CHDIR_ROOT | DaemonOptions::REDIRECT_STDIO;
+        assert!(options.contains(DaemonOptions::CHDIR_ROOT));
+        assert!(options.contains(DaemonOptions::REDIRECT_STDIO));
+        assert!(!options.contains(DaemonOptions::CLOSE_STDIO));
+        assert!(DaemonOptions::empty().contains(DaemonOptions::empty()));
+        assert_eq!(DaemonOptions::default(), DaemonOptions::empty());
+    }
+
+    #[test]
+    fn test_redirect_stdio_saved() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        // stdio is per process, keep it away from the test harness
+        in_child(|| {
+            unsafe { libc::dup2(fds[1], 1) };
+            let Ok(original) = redirect_stdio_saved() else {
+                return false;
+            };
+            let mut stdout = std::io::stdout();
+            let _ = stdout.write_all(b"lost ").and_then(|()| stdout.flush());
+            let _ = original
+                .stdout()
+                .map(|mut stdout| stdout.write_all(b"kept "));
+            let restored = original.restore().is_ok();
+            let _ = stdout.write_all(b"back").and_then(|()| stdout.flush());
+            restored
+        });
+        unsafe { libc::close(fds[1]) };
+        let mut output = String::new();
+        let mut reader = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+        reader.read_to_string(&mut output).expect("failed to read");
+        assert_eq!(output, "kept back");
+    }
+
+    #[test]
+    fn test_redirect_per_stream() {
+        let log = env::temp_dir().join(format!("fork-redirect-{}.log", process::id()));
+        let _ = std::fs::remove_file(&log);
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        in_child(|| {
+            let redirected = redirect_stderr_fd(fds[1]).is_ok()
+                && redirect_stdout_file(&log).is_ok()
+                && redirect_stdin_null().is_ok();
+            let _ = std::io::stdout()
+                .write_all(b"out")
+                .and_then(|()| std::io::stdout().flush());
+            let _ = std::io::stderr().write_all(b"err");
+            redirected
+        });
+        unsafe { libc::close(fds[1]) };
+        let mut err = String::new();
+        let mut reader = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+        reader.read_to_string(&mut err).expect("failed to read");
+        assert_eq!(err, "err");
+        assert_eq!(std::fs::read_to_string(&log).ok(), Some("out".into()));
+        let _ = std::fs::remove_file(&log);
+    }
+
+    #[test]
+    fn test_daemon_launcher_output() {
+        let log = env::temp_dir().join(format!("fork-launcher-{}.log", process::id()));
+        let _ = std::fs::remove_file(&log);
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        in_child(|| {
+            unsafe { libc::close(fds[0]) };
+            if redirect_stderr_fd(fds[1]).is_err() {
+                return false;
+            }
+            unsafe { libc::close(fds[1]) };
+            let daemon = Daemon::new()
+                .nochdir(true)
+                .launcher_output(true)
+                .stdout(&log);
+            let Ok(Daemonized::Daemon(info)) = daemon.start() else {
+                return false;
+            };
+            let _ = std::io::stderr().write_all(b"starting");
+            let ready = info.launcher.map(super::Launcher::ready);
+            let _ = std::io::stdout()
+                .write_all(b"running")
+                .and_then(|()| std::io::stdout().flush());
+            ready == Some(Ok(()))
+        });
+        unsafe { libc::close(fds[1]) };
+        let mut relayed = String::new();
+        let mut reader = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+        reader.read_to_string(&mut relayed).expect("failed to read");
+        assert_eq!(relayed, "starting");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while std::fs::read_to_string(&log).unwrap_or_default().is_empty()
+            && Instant::now() < deadline
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(std::fs::read_to_string(&log).ok(), Some("running".into()));
+        let _ = std::fs::remove_file(&log);
+    }
+
+    #[test]
+    fn test_daemon_startup_timeout() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        match fork() {
+            Ok(Fork::Child) => {
+                unsafe { libc::close(fds[0]) };
+                let daemon = Daemon::new()
+                    .nochdir(true)
+                    .launcher_output(true)
+                    .startup_timeout(Duration::from_millis(200));
+                if let Ok(Daemonized::Daemon(_)) = daemon.start() {
+                    let pid = getpid().to_ne_bytes();
+                    unsafe { libc::write(fds[1], pid.as_ptr().cast(), pid.len()) };
+                    thread::sleep(Duration::from_secs(10));
+                }
+                process::exit(2);
+            }
+            Ok(Fork::Parent(child)) => {
+                unsafe { libc::close(fds[1]) };
+                assert_eq!(
+                    waitpid_status(child),
+                    Ok(WaitStatus::Exited(STARTUP_TIMED_OUT))
+                );
+                let daemon = read_pid(fds[0]).expect("no daemon pid");
+                unsafe { libc::close(fds[0]) };
+                let deadline = Instant::now() + Duration::from_secs(5);
+                let alive = || {
+                    process_table()
+                        .is_ok_and(|table| table.iter().any(|p| p.pid == daemon && !p.zombie))
+                };
+                while alive() && Instant::now() < deadline {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                assert!(!alive());
+            }
+            Err(_) => panic!("fork failed"),
+        }
+    }
+
+    #[test]
+    fn test_audit_fds() {
+        in_child(|| {
+            let Ok(file) = std::fs::File::open("/dev/null") else {
+                return false;
+            };
+            let leaked = file.as_raw_fd();
+            let mut fds = [0; 2];
+            if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+                return false;
+            }
+            let policy = FdPolicy::new().keep(fds[0]).keep(fds[1]);
+            let ok = audit_fds(&policy).is_ok_and(|report| {
+                report.stdio.contains(&0)
+                    && report.kept == fds
+                    && report.unexpected.contains(&leaked)
+                    && !report.is_clean()
+            }) && audit_fds(&policy.deny_unexpected(true)).is_err();
+            drop(file);
+            ok
+        });
+    }
+
+    #[test]
+    fn test_audit_fds_after_redirect() {
+        in_child(|| {
+            // start from a clean process, as a daemon would
+            for fd in 3..super::fd_probe_limit() {
+                unsafe { libc::close(fd) };
+            }
+            let policy = FdPolicy::new().deny_unexpected(true);
+            audit_fds(&policy).is_ok()
+                && redirect_stdio().is_ok()
+                && audit_fds(&policy).is_ok_and(|report| report.is_clean())
+        });
+    }
+
+    #[test]
+    fn test_redirect_stdio_discard() {
+        in_child(|| {
+            if redirect_stdio_with(NullPolicy::Discard).is_err() {
+                return false;
+            }
+            // more than a pipe buffer, blocks unless discarded
+            let written = std::io::stdout()
+                .write_all(&[b'x'; 1 << 20])
+                .and_then(|()| std::io::stdout().flush())
+                .is_ok();
+            let mut input = Vec::new();
+            let eof = std::io::stdin()
+                .read_to_end(&mut input)
+                .is_ok_and(|n| n == 0);
+            written && eof
+        });
+    }
+
+    #[test]
+    fn test_daemon_null_policy_discard() {
+        let dir = env::temp_dir().join(format!("fork-discard-{}", process::id()));
+        std::fs::create_dir_all(&dir).expect("failed to create dir");
+        let done = dir.join("done");
+        // the launcher returned long before the daemon writes
+        let daemon = Daemon::new()
+            .redirect_stdio(true)
+            .null_policy(NullPolicy::Discard);
+        daemon
+            .exec(Command::new("sh").arg("-c").arg(format!(
+                "sleep 0.2; echo out && echo err >&2 && ! read line && echo ok > {0}.tmp && mv {0}.tmp {0}",
+                done.display()
+            )))
+            .expect("failed to start daemon");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done.exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(std::fs::read_to_string(&done).ok(), Some("ok\n".into()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_preopened_dev_null() {
+        in_child(|| {
+            let mut limit = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            if preopen_dev_null().is_err()
+                || unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == -1
+            {
+                return false;
+            }
+            limit.rlim_cur = limit.rlim_cur.min(256);
+            if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } == -1 {
+                return false;
+            }
+            // use up every free descriptor slot
+            while unsafe { libc::dup(0) } != -1 {}
+            let exhausted = std::fs::File::open("/dev/null").is_err();
+            let redirected = redirect_stdio().is_ok() && redirect_stdin_null().is_ok();
+            exhausted && redirected
+        });
+    }
+
+    #[test]
+    fn test_daemon_bind_before_drop() {
+        let daemon = Daemon::new().bind_before_drop(|| {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+            Ok(vec![listener.into()])
+        });
+        let prepared = daemon.prepare().expect("failed to prepare");
+        assert_eq!(prepared.fds.len(), 1);
+        let info = prepared.into_info(None);
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(info.fds[0]) };
+        assert!(listener.local_addr().is_ok());
+
+        let failing = Daemon::new().bind_before_drop(|| Err(std::io::ErrorKind::AddrInUse.into()));
+        assert!(failing.prepare().is_err());
+    }
+
+    #[test]
+    fn test_daemon_prepare() {
+        assert!(Daemon::new().user("fork-no-such-user").prepare().is_err());
+
+        let pidfile = env::temp_dir().join(format!("fork-prepare-{}.pid", process::id()));
+        let _ = std::fs::remove_file(&pidfile);
+        let prepared = Daemon::new().pidfile(&pidfile).prepare();
+        assert!(prepared.is_ok());
+        // written under a temporary name, renamed once it holds the pid
+        assert!(!pidfile.exists());
+        if let Ok(prepared) = &prepared {
+            assert!(prepared
+                .pidfile
+                .as_ref()
+                .is_some_and(|p| p.commit(42).is_ok()));
+        }
+        assert_eq!(
+            std::fs::read_to_string(&pidfile).ok(),
+            Some("42\n".to_string())
+        );
+        drop(prepared);
+        let _ = std::fs::remove_file(&pidfile);
+    }
+
+    #[test]
+    fn test_resource_usage() {
+        let usage = resource_usage(Who::Self_).expect("failed to get usage");
+        assert!(usage.max_rss > 0);
+
+        in_child(|| {
+            let before = resource_usage(Who::Children).unwrap_or_default();
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg("i=0; while [ $i -lt 100000 ]; do i=$((i+1)); done")
+                .status();
+            let after = resource_usage(Who::Children).unwrap_or_default();
+            let used = after.user_time + after.system_time > before.user_time + before.system_time;
+            status.is_ok() && used && after.max_rss > 0
+        });
+    }
+
+    #[test]
+    fn test_child_limits() {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("ulimit -t; ulimit -v");
+        let output = ChildLimits::new()
+            .cpu(5)
+            .address_space(1 << 30)
+            .apply(&mut cmd)
+            .output()
+            .expect("failed to run sh");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "5\n1048576\n");
+    }
+
+    #[test]
+    fn test_start_time() {
+        let started = start_time(getpid()).expect("failed to get start time");
+        assert_eq!(start_time(getpid()), Ok(started));
+        assert!(!boot_id().expect("failed to get boot id").is_empty());
+
+        let mut child = Command::new("sleep")
+            .arg("1")
+            .spawn()
+            .expect("failed to spawn");
+        let pid = libc::pid_t::try_from(child.id()).expect("pid out of range");
+        assert!(start_time(pid).is_ok_and(|child_started| child_started >= started));
+        child.kill().expect("failed to kill");
+        child.wait().expect("failed to wait");
+        assert_eq!(start_time(pid), Err(-1));
+    }
+
+    #[test]
+    fn test_descendants_of() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        match fork() {
+            Ok(Fork::Child) => {
+                unsafe { libc::close(fds[0]) };
+                // a shell with a background job in its own process group
+                let shell = Command::new("sh")
+                    .arg("-c")
+                    .arg("set -m; sleep 5 & echo $!; wait")
+                    .stdout(unsafe { std::process::Stdio::from_raw_fd(fds[1]) })
+                    .spawn()
+                    .and_then(|mut shell| shell.wait());
+                process::exit(i32::from(shell.is_err()));
+            }
+            Ok(Fork::Parent(child)) => {
+                unsafe { libc::close(fds[1]) };
+                let mut reader = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+                let mut line = [0u8; 16];
+                let n = reader.read(&mut line).expect("failed to read");
+                let job: libc::pid_t = String::from_utf8_lossy(&line[..n])
+                    .trim()
+                    .parse()
+                    .expect("no pid");
+
+                let children = children_of(child).expect("failed to list children");
+                let descendants = descendants_of(child).expect("failed to list descendants");
+                assert_eq!(children.len(), 1);
+                assert!(!children.contains(&job));
+                assert!(descendants.contains(&children[0]) && descendants.contains(&job));
+                assert!(descendants_of(getpid()).is_ok_and(|all| all.contains(&job)));
+
+                unsafe { libc::kill(job, libc::SIGKILL) };
+                assert_eq!(waitpid_status(child), Ok(WaitStatus::Exited(0)));
+            }
+            Err(_) => panic!("fork failed"),
+        }
+    }
+
+    #[test]
+    fn test_count_zombie_children() {
+        in_child(|| {
+            let Ok(Fork::Parent(grandchild)) = fork() else {
+                process::exit(0);
+            };
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while count_zombie_children() != Ok(1) && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            let zombie = count_zombie_children() == Ok(1);
+            let reaped = waitpid(grandchild).is_ok() && count_zombie_children() == Ok(0);
+            zombie && reaped
+        });
+    }
+
+    #[test]
+    fn test_daemon_foreground() {
+        assert!(!launched_by_launchd());
+        let dir = env::temp_dir().join(format!("fork-foreground-{}", process::id()));
+        std::fs::create_dir_all(&dir).expect("failed to create dir");
+        let dir = std::fs::canonicalize(&dir).expect("failed to canonicalize");
+
+        match fork() {
+            Ok(Fork::Child) => {
+                let pid = getpid();
+                let sid = current_session_id();
+                let daemon = Daemon::new()
+                    .foreground(true)
+                    .working_dir(&dir)
+                    .pidfile(dir.join("pid"))
+                    .status_file(
+                        StatusFile::new(dir.join("status.json"))
+                            .version("1.0 \"beta\"")
+                            .socket("/run/a.sock")
+                            .socket("/run/b.sock"),
+                    )
+                    .noclose(true);
+                let ok = matches!(daemon.start(), Ok(Daemonized::Daemon(info))
+                    if info.pid == pid
+                        && info.sid == sid
+                        && info.cwd.as_ref() == Some(&dir)
+                        && env::current_dir().ok().as_ref() == Some(&dir));
+                process::exit(i32::from(!ok));
+            }
+            Ok(Fork::Parent(child)) => {
+                assert_eq!(waitpid_status(child), Ok(WaitStatus::Exited(0)));
+                assert_eq!(
+                    std::fs::read_to_string(dir.join("pid")).ok(),
+                    Some(format!("{child}\n"))
+                );
+                let status = std::fs::read_to_string(dir.join("status.json"))
+                    .expect("failed to read status file");
+                assert!(status.starts_with(&format!("{{\"pid\":{child},\"started\":")));
+                assert!(status.ends_with(
+                    ",\"version\":\"1.0 \\\"beta\\\"\",\"sockets\":[\"/run/a.sock\",\"/run/b.sock\"]}\n"
+                ));
+                // no temporary files are left next to them
+                let files = std::fs::read_dir(&dir).map(Iterator::count).ok();
+                assert_eq!(files, Some(2));
+                let _ = std::fs::remove_dir_all(&dir);
+            }
+            Err(_) => panic!("fork failed"),
+        }
+    }
+
+    #[test]
+    fn test_pid1_mode_forwards_signals() {
+        assert!(!is_pid1());
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        match fork() {
+            Ok(Fork::Child) => {
+                unsafe { libc::close(fds[0]) };
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c")
+                    .arg("trap 'exit 7' USR1; echo ready; while :; do sleep 0.1; done")
+                    .stdout(unsafe { std::process::Stdio::from_raw_fd(fds[1]) });
+                let code = match pid1_mode(&mut cmd) {
+                    Ok(WaitStatus::Exited(code)) => code,
+                    _ => 1,
+                };
+                process::exit(code);
+            }
+            Ok(Fork::Parent(child)) => {
+                unsafe { libc::close(fds[1]) };
+                let mut reader = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+                let mut ready = [0u8; 6];
+                reader.read_exact(&mut ready).expect("failed to read");
+                assert_eq!(&ready, b"ready\n");
+                unsafe { libc::kill(child, libc::SIGUSR1) };
+                assert_eq!(waitpid_status(child), Ok(WaitStatus::Exited(7)));
+            }
+            Err(_) => panic!("fork failed"),
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    #[test]
+    fn test_become_reaper() {
+        in_child(|| {
+            let shell = super::become_reaper().and_then(|()| {
+                Command::new("sh")
+                    .arg("-c")
+                    .arg("sleep 0.1 & echo $!")
+                    .output()
+                    .map_err(|_| -1)
+            });
+            let orphan = shell
+                .ok()
+                .and_then(|output| String::from_utf8_lossy(&output.stdout).trim().parse().ok());
+            orphan.is_some_and(|orphan: libc::pid_t| {
+                children_of(getpid()) == Ok(vec![orphan])
+                    && waitpid_status(orphan) == Ok(WaitStatus::Exited(0))
+            })
+        });
+    }
 }
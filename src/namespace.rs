@@ -0,0 +1,848 @@
+//! Start children in new Linux namespaces, to isolate sandboxed workers from
+//! the host [see namespaces(7)](https://man7.org/linux/man-pages/man7/namespaces.7.html).
+//!
+//! [`Namespaces`] collects the namespaces to create and how to set them up.
+//! [`Namespaces::fork`] forks a child that enters them, [`Namespaces::apply`]
+//! does the same for a [`Command`] before it runs. Everything the child needs
+//! is prepared in the parent, the child only makes system calls.
+//!
+//! Creating namespaces other than user namespaces requires `CAP_SYS_ADMIN`.
+//! Unprivileged processes create a user namespace along with the others and
+//! map their own ids into it, see [`Namespaces::map_current_user`].
+//!
+//! A new PID namespace only applies to the children of the process creating
+//! it, so with [`Namespaces::pid`] the process entering the namespaces forks
+//! once more. It stays outside as a proxy: it waits for the child, which is
+//! PID 1 inside, and exits with its status, or with 128 plus the signal number
+//! if it was killed. The child is killed if the proxy dies. The proxy closes
+//! all its descriptors, so pipes to the child see end of file once the child
+//! closes them.
+//!
+//! Example:
+//!
+//!```no_run
+//!use fork::namespace::Namespaces;
+//!use fork::{waitpid, Fork};
+//!
+//!let sandbox = Namespaces::new().hostname("worker-3").expect("invalid hostname");
+//!match sandbox.fork() {
+//!    Ok(Fork::Child) => {
+//!        // the hostname is only changed in the child's UTS namespace
+//!        std::process::exit(0);
+//!    }
+//!    Ok(Fork::Parent(child)) => waitpid(child).expect("failed to wait on child"),
+//!    Err(_) => eprintln!("failed to create namespaces"),
+//!}
+//!```
+
+use crate::{fork, Fork};
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+/// Namespaces a child is started in and their setup
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Namespaces {
+    flags: libc::c_int,
+    hostname: Option<CString>,
+    domainname: Option<CString>,
+    mount_proc: bool,
+    /// mount points and options of tmpfs file systems
+    tmpfs: Vec<(CString, CString)>,
+    uid_map: Option<Vec<u8>>,
+    gid_map: Option<Vec<u8>>,
+    deny_setgroups: bool,
+    loopback: bool,
+    /// host paths, where they are mounted and whether they are read-only
+    binds: Vec<(CString, CString, bool)>,
+}
+
+/// A range of ids mapped into a user namespace, a line of `uid_map` or
+/// `gid_map` [see user_namespaces(7)](https://man7.org/linux/man-pages/man7/user_namespaces.7.html)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdMap {
+    /// First id inside the namespace
+    pub inside: u32,
+    /// First id outside, in the user namespace of the process writing the map
+    pub outside: u32,
+    /// Number of ids in the range
+    pub count: u32,
+}
+
+impl IdMap {
+    /// Map the single id `outside` to `inside`
+    #[must_use]
+    pub const fn single(inside: u32, outside: u32) -> Self {
+        Self {
+            inside,
+            outside,
+            count: 1,
+        }
+    }
+}
+
+/// Write the uid and gid maps of the user namespace of `pid`, from its parent
+/// user namespace, e.g. after the child signalled that it called `unshare`
+///
+/// Takes care of the order the kernel requires: `setgroups` is set to `deny`
+/// first if `deny_setgroups`, which an unprivileged writer needs before it may
+/// write `gid_map`, then each map is written with a single `write`. An empty
+/// map is left unwritten. Each map can only be written once.
+///
+/// # Errors
+/// returns `-1` if a map has more than 340 lines or a file can't be written,
+/// e.g. without `CAP_SETUID` or `CAP_SETGID` for other ids than the writer's
+pub fn write_id_maps(
+    pid: libc::pid_t,
+    uid_map: &[IdMap],
+    gid_map: &[IdMap],
+    deny_setgroups: bool,
+) -> Result<(), i32> {
+    let proc = Path::new("/proc").join(pid.to_string());
+    let write = |file: &str, contents: &[u8]| {
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(proc.join(file))
+            .map_err(|_| -1)?;
+        write_once(file.as_raw_fd(), contents)
+    };
+    if deny_setgroups {
+        write("setgroups", b"deny")?;
+    }
+    if !uid_map.is_empty() {
+        write("uid_map", &id_map_text(uid_map)?)?;
+    }
+    if !gid_map.is_empty() {
+        write("gid_map", &id_map_text(gid_map)?)?;
+    }
+    Ok(())
+}
+
+/// Namespace flags [`unshare_current`] accepts
+const NAMESPACE_FLAGS: libc::c_int = libc::CLONE_NEWNS
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET
+    | libc::CLONE_NEWCGROUP;
+
+/// Move the calling process into new namespaces, e.g. a daemon that already
+/// forked and wants a mount namespace of its own [see unshare(2)](https://man7.org/linux/man-pages/man2/unshare.2.html)
+///
+/// `flags` combines `libc::CLONE_NEW*` flags. With `CLONE_NEWNS` all mounts
+/// are made private, so that later mounts stay in the new namespace. A new PID
+/// namespace only applies to children forked afterwards, and a new user
+/// namespace requires a single threaded process.
+///
+/// Example:
+///
+///```no_run
+///use fork::namespace::unshare_current;
+///
+///match unshare_current(libc::CLONE_NEWNS) {
+///    Ok(()) => {}
+///    Err(libc::EINVAL) => eprintln!("mount namespaces are not supported"),
+///    Err(libc::EPERM) => eprintln!("CAP_SYS_ADMIN is required"),
+///    Err(errno) => eprintln!("unshare failed: {errno}"),
+///}
+///```
+///
+/// # Errors
+/// returns the `errno` of the failure: `EINVAL` for flags other than
+/// namespace flags or namespaces the kernel wasn't built with, `ENOSYS` if
+/// `unshare` isn't available, `EPERM` without the privileges and `ENOSPC` or
+/// `EUSERS` past the namespace limits
+pub fn unshare_current(flags: libc::c_int) -> Result<(), i32> {
+    let errno = || io::Error::last_os_error().raw_os_error().unwrap_or(-1);
+    if flags & !NAMESPACE_FLAGS != 0 {
+        return Err(libc::EINVAL);
+    }
+    if unsafe { libc::unshare(flags) } == -1 {
+        return Err(errno());
+    }
+    if flags & libc::CLONE_NEWNS != 0 {
+        let private = libc::MS_REC | libc::MS_PRIVATE;
+        let root = c"/".as_ptr();
+        let none = std::ptr::null();
+        if unsafe { libc::mount(none, root, none, private, none.cast()) } == -1 {
+            return Err(errno());
+        }
+    }
+    Ok(())
+}
+
+/// Most lines the kernel accepts in a map since Linux 4.15
+const ID_MAP_MAX_LINES: usize = 340;
+
+/// The contents of a map file for `map`
+fn id_map_text(map: &[IdMap]) -> Result<Vec<u8>, i32> {
+    if map.len() > ID_MAP_MAX_LINES {
+        return Err(-1);
+    }
+    let text: String = map
+        .iter()
+        .map(|range| format!("{} {} {}\n", range.inside, range.outside, range.count))
+        .collect();
+    Ok(text.into_bytes())
+}
+
+/// Write `contents` to a file of `/proc/self` without allocating
+fn write_self(path: &std::ffi::CStr, contents: &[u8]) -> Result<(), i32> {
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+    if fd == -1 {
+        return Err(-1);
+    }
+    let written = write_once(fd, contents);
+    unsafe { libc::close(fd) };
+    written
+}
+
+/// Write `contents` to `fd` with a single `write`, as the map files require
+fn write_once(fd: libc::c_int, contents: &[u8]) -> Result<(), i32> {
+    let written = unsafe { libc::write(fd, contents.as_ptr().cast(), contents.len()) };
+    if written.unsigned_abs() != contents.len() {
+        return Err(-1);
+    }
+    Ok(())
+}
+
+impl Namespaces {
+    /// No new namespaces, the child shares all of them with the parent
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// New UTS namespace, the child's hostname and domain name are its own
+    #[must_use]
+    pub const fn uts(mut self) -> Self {
+        self.flags |= libc::CLONE_NEWUTS;
+        self
+    }
+
+    /// New PID namespace, the child is PID 1 and sees only its descendants
+    #[must_use]
+    pub const fn pid(mut self) -> Self {
+        self.flags |= libc::CLONE_NEWPID;
+        self
+    }
+
+    /// New mount namespace, mounts made by the child don't propagate to the
+    /// parent
+    ///
+    /// The child marks all mounts private before mounting anything.
+    #[must_use]
+    pub const fn mount(mut self) -> Self {
+        self.flags |= libc::CLONE_NEWNS;
+        self
+    }
+
+    /// New network namespace, the child starts with only a loopback interface,
+    /// which is down
+    #[must_use]
+    pub const fn net(mut self) -> Self {
+        self.flags |= libc::CLONE_NEWNET;
+        self
+    }
+
+    /// Bring up the loopback interface of a new network namespace, so the
+    /// child can still use `127.0.0.1` and `::1`, implies [`Namespaces::net`]
+    #[must_use]
+    pub const fn loopback(mut self) -> Self {
+        self.loopback = true;
+        self.net()
+    }
+
+    /// Mount a fresh `/proc` for the new PID namespace, so `ps` and anything
+    /// else reading `/proc` sees the processes inside instead of the host's
+    ///
+    /// Implies [`Namespaces::pid`] and [`Namespaces::mount`].
+    #[must_use]
+    pub const fn mount_proc(mut self) -> Self {
+        self.mount_proc = true;
+        self.pid().mount()
+    }
+
+    /// Mount an empty `tmpfs` on the existing directory `path`, with mount
+    /// `options` like `size=64m,mode=1777` [see tmpfs(5)](https://man7.org/linux/man-pages/man5/tmpfs.5.html)
+    ///
+    /// Scratch space that is private to the child and gone once it exits. Can
+    /// be repeated, implies [`Namespaces::mount`].
+    ///
+    /// # Errors
+    /// returns `-1` if `path` or `options` contain a nul byte
+    pub fn tmpfs<P: AsRef<Path>>(mut self, path: P, options: &str) -> Result<Self, i32> {
+        let path = CString::new(path.as_ref().as_os_str().as_bytes()).map_err(|_| -1)?;
+        let options = CString::new(options).map_err(|_| -1)?;
+        self.tmpfs.push((path, options));
+        Ok(self.mount())
+    }
+
+    /// Bind mount the host path `source` and the mounts below it on the
+    /// existing `target`, read-only unless `writable`
+    ///
+    /// Binds are made in order after the `tmpfs` mounts, so a sandbox can
+    /// start from an empty `tmpfs` and bind in only the paths the child needs.
+    /// Can be repeated, implies [`Namespaces::mount`]. Mounts below `source`
+    /// stay writable if they were.
+    ///
+    /// # Errors
+    /// returns `-1` if `source` or `target` contain a nul byte
+    pub fn bind<S: AsRef<Path>, T: AsRef<Path>>(
+        mut self,
+        source: S,
+        target: T,
+        writable: bool,
+    ) -> Result<Self, i32> {
+        let path = |path: &Path| CString::new(path.as_os_str().as_bytes()).map_err(|_| -1);
+        let (source, target) = (path(source.as_ref())?, path(target.as_ref())?);
+        self.binds.push((source, target, !writable));
+        Ok(self.mount())
+    }
+
+    /// New user namespace, the child has all capabilities inside it but none
+    /// outside
+    ///
+    /// Ids without a mapping show up as the overflow id `65534` inside.
+    #[must_use]
+    pub const fn user(mut self) -> Self {
+        self.flags |= libc::CLONE_NEWUSER;
+        self
+    }
+
+    /// Map user ids into the new user namespace, implies [`Namespaces::user`]
+    ///
+    /// The child writes the map itself from inside the namespace, so it can
+    /// only map its own effective uid with a single [`IdMap`]. Mapping other
+    /// ranges takes a privileged parent calling [`write_id_maps`].
+    ///
+    /// # Errors
+    /// returns `-1` if the map has more than 340 lines
+    pub fn uid_map(mut self, map: &[IdMap]) -> Result<Self, i32> {
+        self.uid_map = Some(id_map_text(map)?);
+        Ok(self.user())
+    }
+
+    /// Map group ids into the new user namespace, implies [`Namespaces::user`]
+    ///
+    /// An unprivileged process needs [`Namespaces::deny_setgroups`] to write
+    /// the map.
+    ///
+    /// # Errors
+    /// returns `-1` if the map has more than 340 lines
+    pub fn gid_map(mut self, map: &[IdMap]) -> Result<Self, i32> {
+        self.gid_map = Some(id_map_text(map)?);
+        Ok(self.user())
+    }
+
+    /// Forbid `setgroups` in the new user namespace, so that dropping a
+    /// group can't be undone to regain access it denies
+    #[must_use]
+    pub const fn deny_setgroups(mut self, deny: bool) -> Self {
+        self.deny_setgroups = deny;
+        self
+    }
+
+    /// Map the caller's effective uid and gid to `root` in a new user
+    /// namespace and deny `setgroups`, like `unshare --map-root-user`
+    ///
+    /// Lets an unprivileged process create the other namespaces, which
+    /// require `CAP_SYS_ADMIN` in the user namespace that owns them.
+    #[must_use]
+    pub fn map_current_user(self) -> Self {
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        Self {
+            uid_map: Some(format!("0 {uid} 1\n").into_bytes()),
+            gid_map: Some(format!("0 {gid} 1\n").into_bytes()),
+            ..self.deny_setgroups(true).user()
+        }
+    }
+
+    /// Set the hostname in a new UTS namespace [see sethostname(2)](https://man7.org/linux/man-pages/man2/sethostname.2.html)
+    ///
+    /// # Errors
+    /// returns `-1` if `name` contains a nul byte or is longer than 64 bytes
+    pub fn hostname(self, name: &str) -> Result<Self, i32> {
+        let name = uts_name(name)?;
+        Ok(Self {
+            hostname: Some(name),
+            ..self.uts()
+        })
+    }
+
+    /// Set the NIS domain name in a new UTS namespace [see setdomainname(2)](https://man7.org/linux/man-pages/man2/setdomainname.2.html)
+    ///
+    /// # Errors
+    /// returns `-1` if `name` contains a nul byte or is longer than 64 bytes
+    pub fn domainname(self, name: &str) -> Result<Self, i32> {
+        let name = uts_name(name)?;
+        Ok(Self {
+            domainname: Some(name),
+            ..self.uts()
+        })
+    }
+
+    /// Fork a child that enters the namespaces and sets them up
+    ///
+    /// A child that fails to do so exits with status `127`, the parent sees it
+    /// when it waits for the child.
+    ///
+    /// # Errors
+    /// returns `-1` if `fork` fails
+    pub fn fork(&self) -> Result<Fork, i32> {
+        match fork()? {
+            Fork::Parent(child) => Ok(Fork::Parent(child)),
+            Fork::Child => {
+                if self.enter().is_err() {
+                    unsafe { libc::_exit(127) };
+                }
+                Ok(Fork::Child)
+            }
+        }
+    }
+
+    /// Enter the namespaces in `cmd` before it runs, with a `pre_exec` hook
+    ///
+    /// Spawning `cmd` fails if a namespace can't be created or set up.
+    pub fn apply<'a>(&self, cmd: &'a mut Command) -> &'a mut Command {
+        let namespaces = self.clone();
+        unsafe { cmd.pre_exec(move || namespaces.enter().map_err(|_| io::Error::last_os_error())) }
+    }
+
+    /// `unshare` and set up the namespaces in the calling process, or in a
+    /// child of it with a new PID namespace
+    fn enter(&self) -> Result<(), i32> {
+        if self.flags != 0 {
+            unshare_current(self.flags).map_err(|_| -1)?;
+        }
+        if self.deny_setgroups {
+            write_self(c"/proc/self/setgroups", b"deny")?;
+        }
+        if let Some(map) = &self.uid_map {
+            write_self(c"/proc/self/uid_map", map)?;
+        }
+        if let Some(map) = &self.gid_map {
+            write_self(c"/proc/self/gid_map", map)?;
+        }
+        if self.flags & libc::CLONE_NEWPID != 0 {
+            proxy()?;
+        }
+        if let Some(name) = &self.hostname {
+            let len = name.as_bytes().len();
+            if unsafe { libc::sethostname(name.as_ptr(), len) } == -1 {
+                return Err(-1);
+            }
+        }
+        if let Some(name) = &self.domainname {
+            let len = name.as_bytes().len();
+            if unsafe { libc::setdomainname(name.as_ptr(), len) } == -1 {
+                return Err(-1);
+            }
+        }
+        if self.loopback {
+            loopback_up()?;
+        }
+        if self.mount_proc {
+            let flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+            let proc = c"proc".as_ptr();
+            let mounted =
+                unsafe { libc::mount(proc, c"/proc".as_ptr(), proc, flags, std::ptr::null()) };
+            if mounted == -1 {
+                return Err(-1);
+            }
+        }
+        for (path, options) in &self.tmpfs {
+            let tmpfs = c"tmpfs".as_ptr();
+            let flags = libc::MS_NOSUID | libc::MS_NODEV;
+            if unsafe { libc::mount(tmpfs, path.as_ptr(), tmpfs, flags, options.as_ptr().cast()) }
+                == -1
+            {
+                return Err(-1);
+            }
+        }
+        for (source, target, read_only) in &self.binds {
+            bind(source, target, *read_only)?;
+        }
+        Ok(())
+    }
+}
+
+/// Mount an empty `tmpfs` on `/tmp` and `/var/tmp`, for
+/// [`Daemon::private_tmp`](crate::Daemon::private_tmp) in a new mount namespace
+pub(crate) fn mount_private_tmp() -> Result<(), i32> {
+    for dir in [c"/tmp", c"/var/tmp"] {
+        let tmpfs = c"tmpfs".as_ptr();
+        let flags = libc::MS_NOSUID | libc::MS_NODEV;
+        let options = c"mode=1777".as_ptr().cast();
+        if unsafe { libc::mount(tmpfs, dir.as_ptr(), tmpfs, flags, options) } == -1
+            && (dir != c"/var/tmp" || io::Error::last_os_error().kind() != io::ErrorKind::NotFound)
+        {
+            return Err(-1);
+        }
+    }
+    Ok(())
+}
+
+/// Bind mount `source` and the mounts below it on `target`, read-only with
+/// a remount of the new mount, the flag is ignored by the bind itself
+pub(crate) fn bind(
+    source: &std::ffi::CStr,
+    target: &std::ffi::CStr,
+    read_only: bool,
+) -> Result<(), i32> {
+    let none = std::ptr::null();
+    let (source, target) = (source.as_ptr(), target.as_ptr());
+    let flags = libc::MS_BIND | libc::MS_REC;
+    if unsafe { libc::mount(source, target, none, flags, none.cast()) } == -1 {
+        return Err(-1);
+    }
+    if !read_only {
+        return Ok(());
+    }
+    // flags locked by a more privileged user namespace must be kept
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(target, &mut stat) } == -1 {
+        return Err(-1);
+    }
+    let mut flags = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY;
+    for (st, ms) in [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+    ] {
+        if stat.f_flag & st != 0 {
+            flags |= ms;
+        }
+    }
+    if unsafe { libc::mount(none, target, none, flags, none.cast()) } == -1 {
+        return Err(-1);
+    }
+    Ok(())
+}
+
+/// Set the `IFF_UP` flag of the `lo` interface [see netdevice(7)](https://man7.org/linux/man-pages/man7/netdevice.7.html)
+fn loopback_up() -> Result<(), i32> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(-1);
+    }
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in request.ifr_name.iter_mut().zip(b"lo") {
+        *dst = *src as libc::c_char;
+    }
+    let mut result = unsafe { libc::ioctl(fd, libc::SIOCGIFFLAGS as _, &mut request) };
+    if result != -1 {
+        unsafe { request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short };
+        result = unsafe { libc::ioctl(fd, libc::SIOCSIFFLAGS as _, &request) };
+    }
+    unsafe { libc::close(fd) };
+    if result == -1 {
+        return Err(-1);
+    }
+    Ok(())
+}
+
+/// Fork after creating a PID namespace and continue in the child, PID 1 of the
+/// namespace, while the parent waits and exits with the child's status
+fn proxy() -> Result<(), i32> {
+    match unsafe { libc::fork() } {
+        -1 => Err(-1),
+        // die with the proxy, its pid isn't visible in here to check whether
+        // it is gone already, but it only exits after the child
+        0 => match unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) } {
+            -1 => Err(-1),
+            _ => Ok(()),
+        },
+        child => {
+            close_all();
+            let mut status = 0;
+            while unsafe { libc::waitpid(child, &mut status, 0) } == -1 {
+                if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                    unsafe { libc::_exit(127) };
+                }
+            }
+            let code = if libc::WIFSIGNALED(status) {
+                128 + libc::WTERMSIG(status)
+            } else {
+                libc::WEXITSTATUS(status)
+            };
+            unsafe { libc::_exit(code) }
+        }
+    }
+}
+
+/// Close every descriptor of the proxy, it must not keep pipes open that its
+/// parent waits on, like the one `Command::spawn` reads `exec` errors from
+fn close_all() {
+    if unsafe { libc::syscall(libc::SYS_close_range, 0, libc::c_uint::MAX, 0) } == 0 {
+        return;
+    }
+    for fd in 0..crate::fd_probe_limit() {
+        unsafe { libc::close(fd) };
+    }
+}
+
+/// Longest hostname or domain name, `HOST_NAME_MAX` on Linux
+const UTS_NAME_MAX: usize = 64;
+
+/// `name` as a C string if the kernel accepts it as a hostname or domain name
+fn uts_name(name: &str) -> Result<CString, i32> {
+    if name.len() > UTS_NAME_MAX {
+        return Err(-1);
+    }
+    CString::new(name).map_err(|_| -1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unshare_current, write_id_maps, IdMap, Namespaces};
+    use crate::{waitpid_status, Fork, WaitStatus};
+    use std::process::Command;
+
+    #[test]
+    fn test_uts_names() {
+        assert!(Namespaces::new().hostname(&"x".repeat(65)).is_err());
+        assert!(Namespaces::new().domainname("a\0b").is_err());
+        let namespaces = Namespaces::new().hostname("worker").expect("valid name");
+        assert_eq!(
+            namespaces,
+            Namespaces::new()
+                .uts()
+                .hostname("worker")
+                .expect("valid name")
+        );
+    }
+
+    #[test]
+    fn test_mount_proc() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let scratch = std::env::temp_dir().join(format!("fork-ns-scratch-{}", std::process::id()));
+        std::fs::create_dir_all(&scratch).expect("failed to create dir");
+        let namespaces = Namespaces::new()
+            .mount_proc()
+            .tmpfs(&scratch, "size=1m,mode=700")
+            .expect("valid path");
+        let output = namespaces
+            .apply(&mut Command::new("sh"))
+            .arg("-c")
+            .arg(format!(
+                "echo $$ $(ls /proc | grep -c '^[0-9]') > {0}/out && cat {0}/out",
+                scratch.display()
+            ))
+            .output()
+            .expect("failed to run sh");
+        // sh is PID 1 and /proc only lists it and the few processes it started
+        let output = String::from_utf8_lossy(&output.stdout);
+        let fields: Vec<u32> = output
+            .split_whitespace()
+            .filter_map(|f| f.parse().ok())
+            .collect();
+        assert!(
+            matches!(fields[..], [1, processes] if processes < 10),
+            "{output}"
+        );
+        // the tmpfs was private to the child
+        assert!(!scratch.join("out").exists());
+        let _ = std::fs::remove_dir(&scratch);
+
+        match Namespaces::new().pid().fork() {
+            Ok(Fork::Child) => std::process::exit(i32::from(unsafe { libc::getpid() } != 1) + 3),
+            Ok(Fork::Parent(child)) => {
+                assert_eq!(waitpid_status(child), Ok(WaitStatus::Exited(3)));
+            }
+            Err(_) => panic!("fork failed"),
+        }
+    }
+
+    #[test]
+    fn test_user_namespace() {
+        let id_map = |file| std::fs::read_to_string(file).unwrap_or_default();
+        let same = |contents: String, expected: &str| {
+            contents.split_whitespace().eq(expected.split_whitespace())
+        };
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        crate::tests::in_child_of(Namespaces::new().map_current_user().fork(), || {
+            let root = unsafe { libc::getuid() == 0 && libc::getgid() == 0 };
+            let mapped = same(id_map("/proc/self/uid_map"), &format!("0 {uid} 1"))
+                && same(id_map("/proc/self/gid_map"), &format!("0 {gid} 1"));
+            let denied = id_map("/proc/self/setgroups") == "deny\n";
+            root && mapped && denied
+        });
+
+        // the parent maps the child's namespace once it unshared
+        let pipe = || {
+            let mut fds = [0; 2];
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            fds
+        };
+        let ([unshared_read, unshared_write], [mapped_read, mapped_write]) = (pipe(), pipe());
+        let wait = |fd| {
+            let mut byte = 0u8;
+            // EOF once the other side closed its end
+            unsafe { libc::read(fd, std::ptr::addr_of_mut!(byte).cast(), 1) };
+            unsafe { libc::close(fd) };
+        };
+        match crate::fork() {
+            Ok(Fork::Child) => {
+                unsafe { libc::close(unshared_read) };
+                unsafe { libc::close(mapped_write) };
+                if unsafe { libc::unshare(libc::CLONE_NEWUSER) } == -1 {
+                    std::process::exit(2);
+                }
+                unsafe { libc::close(unshared_write) };
+                wait(mapped_read);
+                let root = unsafe { libc::getuid() == 0 && libc::getgid() == 0 };
+                std::process::exit(i32::from(!root));
+            }
+            Ok(Fork::Parent(child)) => {
+                unsafe { libc::close(unshared_write) };
+                unsafe { libc::close(mapped_read) };
+                wait(unshared_read);
+                let map = [IdMap::single(0, uid)];
+                let result = write_id_maps(child, &map, &[IdMap::single(0, gid)], true);
+                // maps can only be written once
+                let again = write_id_maps(child, &map, &[], false);
+                unsafe { libc::close(mapped_write) };
+                assert_eq!((result, again), (Ok(()), Err(-1)));
+                assert_eq!(waitpid_status(child), Ok(WaitStatus::Exited(0)));
+            }
+            Err(_) => panic!("fork failed"),
+        }
+        assert!(write_id_maps(1, &[IdMap::single(0, 0); 341], &[], false).is_err());
+    }
+
+    #[test]
+    fn test_loopback() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let connects = || {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").ok()?;
+            std::net::TcpStream::connect(listener.local_addr().ok()?).ok()
+        };
+        for (namespaces, expected) in [
+            (Namespaces::new().net(), false),
+            (Namespaces::new().loopback(), true),
+        ] {
+            crate::tests::in_child_of(namespaces.fork(), || connects().is_some() == expected);
+        }
+    }
+
+    #[test]
+    fn test_unshare_current() {
+        assert_eq!(unshare_current(libc::CLONE_FILES), Err(libc::EINVAL));
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let host = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+        crate::tests::in_child(|| {
+            if unshare_current(libc::CLONE_NEWUTS | libc::CLONE_NEWNS).is_err() {
+                return false;
+            }
+            let name = c"unshared";
+            let renamed = unsafe { libc::sethostname(name.as_ptr(), 8) } == 0;
+            // mounts are private after unshare
+            let root = std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+            let private = root
+                .lines()
+                .find(|line| line.split(' ').nth(4) == Some("/"))
+                .is_some_and(|line| !line.contains("shared:"));
+            renamed && private
+        });
+        let after = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+        assert_eq!(after, host);
+    }
+
+    #[test]
+    fn test_bind() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("fork-ns-bind-{}", std::process::id()));
+        for sub in ["ro", "rw", "view-ro", "view-rw"] {
+            std::fs::create_dir_all(dir.join(sub)).expect("failed to create dir");
+        }
+        std::fs::write(dir.join("ro/file"), "host").expect("failed to write");
+        let sandboxes = [Namespaces::new(), Namespaces::new().map_current_user()].map(|ns| {
+            ns.bind(dir.join("ro"), dir.join("view-ro"), false)
+                .and_then(|ns| ns.bind(dir.join("rw"), dir.join("view-rw"), true))
+                .expect("valid paths")
+        });
+        for sandbox in sandboxes {
+            crate::tests::in_child_of(sandbox.fork(), || {
+                let read = std::fs::read_to_string(dir.join("view-ro/file"));
+                let denied = std::fs::write(dir.join("view-ro/new"), "")
+                    .is_err_and(|e| e.raw_os_error() == Some(libc::EROFS));
+                let written = std::fs::write(dir.join("view-rw/new"), "child").is_ok();
+                read.is_ok_and(|s| s == "host") && denied && written
+            });
+            // writes went through to the host, the views are empty outside
+            let new = dir.join("rw/new");
+            assert_eq!(std::fs::read_to_string(&new).ok().as_deref(), Some("child"));
+            std::fs::remove_file(new).expect("failed to remove");
+            assert!(std::fs::read_dir(dir.join("view-ro"))
+                .is_ok_and(|mut entries| entries.next().is_none()));
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_pid_namespace_spawn() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let started = std::time::Instant::now();
+        let mut child = Namespaces::new()
+            .map_current_user()
+            .pid()
+            .apply(Command::new("sleep").arg("3"))
+            .spawn()
+            .expect("failed to spawn");
+        // spawn returns once sleep runs, not when it exits
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+        assert!(child.id() > 0);
+        child.kill().expect("failed to kill");
+        child.wait().expect("failed to wait");
+
+        // output is drained while the command runs
+        let output = Namespaces::new()
+            .pid()
+            .apply(Command::new("head").args(["-c", "1000000", "/dev/zero"]))
+            .output()
+            .expect("failed to run head");
+        assert_eq!(output.stdout.len(), 1_000_000);
+    }
+
+    #[test]
+    fn test_hostname() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let host = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+        let namespaces = Namespaces::new()
+            .hostname("fork-sandbox")
+            .expect("valid name");
+        crate::tests::in_child_of(namespaces.fork(), || {
+            let name = std::fs::read_to_string("/proc/sys/kernel/hostname");
+            name.ok().as_deref() == Some("fork-sandbox\n")
+        });
+
+        let output = namespaces
+            .apply(&mut Command::new("cat"))
+            .arg("/proc/sys/kernel/hostname")
+            .output()
+            .expect("failed to run cat");
+        assert_eq!(output.stdout, b"fork-sandbox\n");
+        // the parent's hostname is untouched
+        let after = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+        assert_eq!(after, host);
+    }
+}
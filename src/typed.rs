@@ -0,0 +1,202 @@
+//! Type-state alternative to [`fork`](crate::fork()).
+//!
+//! [`fork`] returns a [`Parent`] or a [`Child`] context and operations that are
+//! only valid on one side are methods of that side, so calling child-only setup
+//! such as [`Child::exec`] or [`Child::reset_signals`] in the parent is a
+//! compile error rather than a runtime surprise.
+//!
+//! Example:
+//!
+//!```
+//!use fork::typed::{fork, Forked};
+//!use fork::WaitStatus;
+//!use std::process::Command;
+//!
+//!match fork().expect("fork failed") {
+//!    Forked::Parent(parent) => {
+//!        assert_eq!(parent.wait(), Ok(WaitStatus::Exited(0)));
+//!    }
+//!    Forked::Child(child) => {
+//!        child.reset_signals().expect("failed to reset signals");
+//!        let _ = child.exec(&mut Command::new("true"));
+//!        child.exit(127);
+//!    }
+//!}
+//!```
+
+use crate::{waitpid_status, Signal, SignalSet, WaitStatus};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// Highest signal number reset by [`Child::reset_signals`]
+const MAX_SIGNAL: libc::c_int = 64;
+
+/// Result of [`fork`]
+#[derive(Debug)]
+pub enum Forked {
+    /// Continuing in the parent, with the child's pid
+    Parent(Parent),
+    /// Running in the new child
+    Child(Child),
+}
+
+/// The parent side of a [`fork`], holding the pid of the child
+#[derive(Debug, PartialEq, Eq)]
+#[must_use = "the child should be waited on"]
+pub struct Parent {
+    child: libc::pid_t,
+}
+
+impl Parent {
+    /// Process ID of the child
+    #[must_use]
+    pub const fn child_pid(&self) -> libc::pid_t {
+        self.child
+    }
+
+    /// Send `signal` to the child
+    ///
+    /// # Errors
+    /// returns `-1` if error
+    pub fn kill(&self, signal: Signal) -> Result<(), i32> {
+        match unsafe { libc::kill(self.child, signal.as_raw()) } {
+            -1 => Err(-1),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait for the child to exit
+    ///
+    /// # Errors
+    /// returns `-1` if `waitpid` fails
+    pub fn wait(self) -> Result<WaitStatus, i32> {
+        waitpid_status(self.child)
+    }
+}
+
+/// The child side of a [`fork`]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Child {
+    _private: (),
+}
+
+impl Child {
+    /// Restore the default disposition of every signal and unblock them all,
+    /// so that an exec'd program doesn't inherit the parent's signal setup
+    ///
+    /// # Errors
+    /// returns `-1` if the signal mask can't be cleared
+    pub fn reset_signals(&self) -> Result<(), i32> {
+        for signum in 1..=MAX_SIGNAL {
+            if signum != libc::SIGKILL && signum != libc::SIGSTOP {
+                // fails harmlessly for numbers that aren't signals here
+                unsafe { libc::signal(signum, libc::SIG_DFL) };
+            }
+        }
+        SignalSet::new().set_mask().map(|_| ())
+    }
+
+    /// Create a new session, see [`setsid`](crate::setsid)
+    ///
+    /// # Errors
+    /// returns `-1` if error
+    pub fn setsid(&self) -> Result<libc::pid_t, i32> {
+        crate::setsid()
+    }
+
+    /// Replace the child with `cmd`, only returns if `exec` fails
+    ///
+    /// The standard library allocates and takes its environment lock here, so
+    /// in a multithreaded parent prefer [`Command::spawn`] with a `pre_exec`
+    /// hook, which keeps the forked side async-signal-safe.
+    pub fn exec(&self, cmd: &mut Command) -> std::io::Error {
+        cmd.exec()
+    }
+
+    /// Exit the child without returning to the caller's code path
+    ///
+    /// Uses `_exit`, so `atexit` handlers don't run and stdio buffers copied
+    /// from the parent aren't flushed a second time.
+    pub fn exit(self, code: i32) -> ! {
+        unsafe { libc::_exit(code) }
+    }
+}
+
+/// Create a new child process, see [`fork`](crate::fork())
+///
+/// # Errors
+/// returns `-1` if error
+pub fn fork() -> Result<Forked, i32> {
+    match crate::fork()? {
+        crate::Fork::Parent(child) => Ok(Forked::Parent(Parent { child })),
+        crate::Fork::Child => Ok(Forked::Child(Child { _private: () })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fork, Forked};
+    use crate::{Signal, WaitStatus};
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+    use std::process::Command;
+
+    #[test]
+    fn test_typed_fork_exec() {
+        match fork() {
+            Ok(Forked::Parent(parent)) => {
+                assert!(parent.child_pid() > 0);
+                assert_eq!(parent.wait(), Ok(WaitStatus::Signaled(Signal::SIGTERM)));
+            }
+            Ok(Forked::Child(child)) => {
+                unsafe { libc::signal(libc::SIGTERM, libc::SIG_IGN) };
+                if child.reset_signals().is_err() {
+                    child.exit(1);
+                }
+                // SIGTERM is no longer ignored by the exec'd shell
+                let _ = child.exec(Command::new("sh").args(["-c", "kill -TERM $$; exit 7"]));
+                child.exit(127);
+            }
+            Err(_) => panic!("fork failed"),
+        }
+    }
+
+    #[test]
+    fn test_typed_exit_skips_stdio_flush() {
+        let [read, write] = crate::cloexec_pipe().expect("failed to create pipe");
+        match fork() {
+            Ok(Forked::Parent(parent)) => {
+                unsafe { libc::close(write) };
+                assert_eq!(parent.wait(), Ok(WaitStatus::Exited(3)));
+                let mut read = unsafe { std::fs::File::from_raw_fd(read) };
+                let mut output = Vec::new();
+                read.read_to_end(&mut output).expect("failed to read");
+                // `exit` would have flushed the C stdio buffer
+                assert!(output.is_empty());
+            }
+            Ok(Forked::Child(child)) => {
+                let stream = unsafe { libc::fdopen(write, c"w".as_ptr()) };
+                if !stream.is_null() {
+                    unsafe { libc::fputs(c"buffered".as_ptr(), stream) };
+                }
+                child.exit(3);
+            }
+            Err(_) => panic!("fork failed"),
+        }
+    }
+
+    #[test]
+    fn test_typed_fork_kill() {
+        match fork() {
+            Ok(Forked::Parent(parent)) => {
+                assert!(parent.kill(Signal::SIGKILL).is_ok());
+                assert_eq!(parent.wait(), Ok(WaitStatus::Signaled(Signal::SIGKILL)));
+            }
+            Ok(Forked::Child(child)) => {
+                std::thread::sleep(std::time::Duration::from_secs(5));
+                child.exit(0);
+            }
+            Err(_) => panic!("fork failed"),
+        }
+    }
+}
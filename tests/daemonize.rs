@@ -0,0 +1,73 @@
+#![cfg(feature = "daemonize")]
+
+use std::process::Command;
+use std::time::{Duration, Instant};
+use std::{env, fs, process, thread};
+
+#[test]
+fn daemonize_runs_command_detached() {
+    let dir = env::temp_dir().join(format!("fork-daemonize-{}", process::id()));
+    fs::create_dir_all(&dir).expect("failed to create dir");
+    let pidfile = dir.join("pid");
+    let stdout = dir.join("stdout");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_daemonize"))
+        .arg("--pidfile")
+        .arg(&pidfile)
+        .arg("--stdout")
+        .arg(&stdout)
+        .args(["--chdir", "/", "--umask", "027", "--"])
+        .args(["sh", "-c", "pwd; umask; echo $$"])
+        .status()
+        .expect("failed to run daemonize");
+    assert!(status.success());
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let output = loop {
+        let output = fs::read_to_string(&stdout).unwrap_or_default();
+        if output.lines().count() == 3 || Instant::now() > deadline {
+            break output;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    let pid = fs::read_to_string(&pidfile).expect("no pid file");
+    assert_eq!(output, format!("/\n0027\n{pid}"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn daemonize_connects_stdio_to_dev_null() {
+    let output = env::temp_dir().join(format!("fork-daemonize-stdio-{}", process::id()));
+    let status = Command::new(env!("CARGO_BIN_EXE_daemonize"))
+        .args([
+            "sh",
+            "-c",
+            "links=$(readlink /proc/$$/fd/0 /proc/$$/fd/1 /proc/$$/fd/2); echo \"$links\" > \"$0\"",
+        ])
+        .arg(&output)
+        .status()
+        .expect("failed to run daemonize");
+    assert!(status.success());
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let links = loop {
+        let links = fs::read_to_string(&output).unwrap_or_default();
+        if links.lines().count() == 3 || Instant::now() > deadline {
+            break links;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(links, "/dev/null\n/dev/null\n/dev/null\n");
+    let _ = fs::remove_file(&output);
+}
+
+#[test]
+fn daemonize_requires_a_command() {
+    let output = Command::new(env!("CARGO_BIN_EXE_daemonize"))
+        .args(["--chdir", "/"])
+        .output()
+        .expect("failed to run daemonize");
+    assert_eq!(output.status.code(), Some(64));
+}